@@ -0,0 +1,25 @@
+/*
+    Embeds the git commit the binary was built from as GIT_COMMIT, for --version
+    Nix builds have no .git, so the flake passes the revision in the GIT_COMMIT environment variable instead
+*/
+
+use std::{path::Path, process::Command};
+
+fn main() {
+    println!("cargo:rerun-if-env-changed=GIT_COMMIT");
+    // a missing file counts as changed, which would rebuild every time
+    for file in [".git/HEAD", ".git/index"] {
+        if Path::new(file).exists() {println!("cargo:rerun-if-changed={}", file);}
+    }
+    let commit = std::env::var("GIT_COMMIT").ok().filter(|commit| !commit.is_empty()).or_else(git_commit).unwrap_or("unknown".to_string());
+    println!("cargo:rustc-env=GIT_COMMIT={}", commit);
+}
+
+/// returns the short hash of HEAD, suffixed with -dirty if the tree has uncommitted changes
+fn git_commit() -> Option<String> {
+    let output = Command::new("git").args(["rev-parse", "--short", "HEAD"]).output().ok().filter(|output| output.status.success())?;
+    let hash = String::from_utf8_lossy(&output.stdout).trim().to_string();
+    let dirty = Command::new("git").args(["status", "--porcelain", "--untracked-files=no"]).output()
+        .is_ok_and(|output| !output.stdout.is_empty());
+    Some(if dirty {format!("{}-dirty", hash)} else {hash})
+}
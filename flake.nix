@@ -48,6 +48,8 @@
           version = "0.1.0";
           src = ./.;
           cargoLock.lockFile = ./Cargo.lock;
+          # the source has no .git, so build.rs takes the commit from here
+          GIT_COMMIT = self.shortRev or self.dirtyShortRev or "unknown";
           nativeBuildInputs = with pkgs; [
             pkg-config
             makeWrapper
@@ -1,18 +1,41 @@
 /*
     allows interaction with the vm launcher servers with easy to call commands
 */
-use std::{error::Error, fmt::Display, sync::Arc, time::Duration};
+use std::{collections::HashMap, error::Error, fmt::Display, sync::Arc, time::Duration};
 use dbus::{nonblock::{Proxy, SyncConnection}, Path};
 use dbus_tokio::connection::IOResourceError;
-use tokio::task::JoinHandle;
-use crate::launcher::VmType;
+use tokio::{io::AsyncWriteExt, task::JoinHandle};
+use nix::unistd::Uid;
+use crate::{config::{Config, ConfigError, InputMode, DEFAULT_CONFIG_PATH}, iommu::{device_class, iommu_groups, is_bridge, IOMMU_GROUPS_PATH}, launcher::{pci_sysfs_address, LauncherError, VmState, VmType, MOUSE_PLACEHOLDER}, logs::newest_log};
 
 /// all operations supported on the command line
+#[derive(Debug, PartialEq)]
 pub enum Command{
-    Start(VmType, String),
+    /// vm type, mouse path, an optional libvirt domain name, and an optional config profile. spice lite and direct have no mouse path
+    Start(VmType, String, Option<String>, Option<String>),
+    ListProfiles,
     Open,
-    Shutdown,
-    Query,
+    /// whether to destroy the vm instead of shutting it down gracefully
+    Shutdown(bool),
+    Cancel,
+    /// whether to print json instead of text
+    Query(bool),
+    Status(bool),
+    /// whether to print json instead of text
+    Metrics(bool),
+    Pause,
+    Resume,
+    Reload,
+    Recover,
+    Attach,
+    Detach,
+    Check,
+    Iommu,
+    /// log kind, vm or viewer, and whether to keep following it
+    Logs(String, bool),
+    /// libvirt domain name, the running vm's if None
+    Console(Option<String>),
+    Version,
     Help
 }
 
@@ -22,10 +45,28 @@ pub enum CliError{
     FailedToConnectToSystemBus(dbus::Error),
     FailedToStartUserService(dbus::Error),
     FailedToQueryState(dbus::Error),
+    FailedToQueryMetrics(dbus::Error),
     FailedToCallShutdown(dbus::Error),
+    FailedToCancelLaunch(dbus::Error),
+    FailedToCallPause(dbus::Error),
+    FailedToReload(dbus::Error),
     FailedToLaunchLG(dbus::Error),
     FailedToLaunchSpice(dbus::Error),
-    FailedToConnectToSessionBus(dbus::Error)
+    FailedToLaunchSpiceLite(dbus::Error),
+    FailedToLaunchDirect(dbus::Error),
+    FailedToConnectToSessionBus(dbus::Error),
+    NotRunAsRoot(String),
+    FailedToLoadConfig(ConfigError),
+    FailedToRecover(Vec<LauncherError>),
+    FailedToDetach(LauncherError),
+    FailedToAttach(Vec<LauncherError>),
+    ChecksFailed(usize),
+    FailedToReadIommuGroups(std::io::Error),
+    IommuNotEnabled,
+    NoLogsFound(String),
+    FailedToReadLog(std::io::Error),
+    FailedToRunConsole(std::io::Error),
+    ConsoleFailed(std::process::ExitStatus)
 }
 impl Display for CliError{
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
@@ -34,9 +75,27 @@ impl Display for CliError{
             Self::FailedToConnectToSessionBus(err) => format!("Could not connect to the session dbus: {}", *err),
             Self::FailedToStartUserService(err) => format!("DBus session call to start the user windows-launcher.service failed: {}", *err),
             Self::FailedToQueryState(err) => format!("Failed to query the system server for the vm state: {}", *err),
+            Self::FailedToQueryMetrics(err) => format!("Failed to query the system server for its metrics: {}", *err),
             Self::FailedToCallShutdown(err) => format!("Failed to call shutdown on the system server: {}", *err),
+            Self::FailedToCancelLaunch(err) => format!("Failed to call CancelLaunch on the system server: {}", *err),
+            Self::FailedToCallPause(err) => format!("Failed to call pause or resume on the system server: {}", *err),
+            Self::FailedToReload(err) => format!("Failed to reload the config of the system server: {}", *err),
             Self::FailedToLaunchLG(err) => format!("Failed to call LaunchLG on the system server: {}", *err),
-            Self::FailedToLaunchSpice(err) => format!("Failed to call LaunchSpice on the system server: {}", *err)
+            Self::FailedToLaunchSpice(err) => format!("Failed to call LaunchSpice on the system server: {}", *err),
+            Self::FailedToLaunchSpiceLite(err) => format!("Failed to call LaunchSpiceLite on the system server: {}", *err),
+            Self::FailedToLaunchDirect(err) => format!("Failed to call LaunchDirect on the system server: {}", *err),
+            Self::NotRunAsRoot(flag) => format!("{} must be run as root", *flag),
+            Self::FailedToLoadConfig(err) => format!("Failed to load the config: {}", *err),
+            Self::FailedToRecover(errs) => format!("Recovery finished with errors: {}", errs.iter().map(|err| err.to_string()).collect::<Vec<String>>().join("; ")),
+            Self::FailedToDetach(err) => format!("Failed to detach the gpu: {}", *err),
+            Self::FailedToAttach(errs) => format!("Reattaching the gpu finished with errors: {}", errs.iter().map(|err| err.to_string()).collect::<Vec<String>>().join("; ")),
+            Self::ChecksFailed(count) => format!("{} environment checks failed", *count),
+            Self::FailedToReadIommuGroups(err) => format!("Could not read {}: {}", IOMMU_GROUPS_PATH, *err),
+            Self::IommuNotEnabled => format!("{} is empty, the iommu is not enabled", IOMMU_GROUPS_PATH),
+            Self::NoLogsFound(dir) => format!("No log files found in {}", *dir),
+            Self::FailedToReadLog(err) => format!("Failed to read the log file: {}", *err),
+            Self::FailedToRunConsole(err) => format!("Could not run virsh console: {}", *err),
+            Self::ConsoleFailed(status) => format!("virsh console exited with {}", *status)
         });
         Ok(())
     }
@@ -44,60 +103,441 @@ impl Display for CliError{
 impl Error for CliError{}
 
 
+/// maps the command line arguments, without the program name, to a command. anything malformed maps to help
+pub fn parse_command(arguments: &[String]) -> Command {
+    // --profile <name> may follow any launch command
+    if let Some(index) = arguments.iter().position(|arg| arg == "--profile") {
+        let Some(profile) = arguments.get(index + 1) else {return Command::Help;};
+        let rest = arguments.iter().enumerate().filter(|(i, _)| *i != index && *i != index + 1).map(|(_, arg)| arg.clone()).collect::<Vec<String>>();
+        return match parse_command(&rest) {
+            Command::Start(vm_type, path, name, None) => Command::Start(vm_type, path, name, Some(profile.clone())),
+            _ => Command::Help
+        };
+    }
+    let Some(flag) = arguments.first() else {return Command::Help;};
+    match (flag.as_str(), arguments.len()) {
+        ("--spice", 2..=3) => Command::Start(VmType::Spice, arguments[1].clone(), arguments.get(2).cloned(), None),
+        ("--spice-lite", 1..=2) => Command::Start(VmType::SpiceLite, String::new(), arguments.get(1).cloned(), None),
+        ("--direct", 1..=2) => Command::Start(VmType::Direct, String::new(), arguments.get(1).cloned(), None),
+        ("--lg", 2..=3) => Command::Start(VmType::LookingGlass, arguments[1].clone(), arguments.get(2).cloned(), None),
+        ("--list-profiles", 1) => Command::ListProfiles,
+        ("--open", 1) => Command::Open,
+        ("--query", 1) => Command::Query(false),
+        ("--query", 2) if arguments[1] == "--json" => Command::Query(true),
+        ("--status", 1) => Command::Status(false),
+        ("--status", 2) if arguments[1] == "--json" => Command::Status(true),
+        ("--metrics", 1) => Command::Metrics(false),
+        ("--metrics", 2) if arguments[1] == "--json" => Command::Metrics(true),
+        ("--pause", 1) => Command::Pause,
+        ("--resume", 1) => Command::Resume,
+        ("--reload", 1) => Command::Reload,
+        ("--shutdown", 1) => Command::Shutdown(false),
+        ("--shutdown", 2) if arguments[1] == "--force" => Command::Shutdown(true),
+        ("--cancel", 1) => Command::Cancel,
+        ("--recover", 1) => Command::Recover,
+        ("--attach", 1) => Command::Attach,
+        ("--detach", 1) => Command::Detach,
+        ("--check", 1) => Command::Check,
+        ("--iommu", 1) => Command::Iommu,
+        ("--version", 1) => Command::Version,
+        ("--console", 1..=2) => Command::Console(arguments.get(1).cloned()),
+        ("--logs", 1..=3) => {
+            let follow = arguments[1..].iter().any(|arg| arg == "--follow");
+            let kinds = arguments[1..].iter().filter(|arg| *arg != "--follow").collect::<Vec<&String>>();
+            match kinds.as_slice() {
+                [] => Command::Logs("vm".to_string(), follow),
+                [kind] if *kind == "vm" || *kind == "viewer" => Command::Logs(kind.to_string(), follow),
+                _ => Command::Help
+            }
+        },
+        _ => Command::Help
+    }
+}
+
 pub async fn cli(command: Command) -> Result<(), CliError> {
     match command{
-        Command::Start(VmType::LookingGlass, path) => start_lg(path).await,
-        Command::Start(VmType::Spice, path) => start_spice(path).await,
+        Command::Start(VmType::LookingGlass, path, name, profile) => start_lg(path, name, profile).await,
+        Command::Start(VmType::Spice, path, name, profile) => start_spice(path, name, profile).await,
+        Command::Start(VmType::SpiceLite, _, name, profile) => start_spice_lite(name, profile).await,
+        Command::Start(VmType::Direct, _, name, profile) => start_direct(name, profile).await,
+        Command::ListProfiles => list_profiles().await,
         Command::Open => open().await,
-        Command::Query => query().await,
-        Command::Shutdown => shutdown().await,
+        Command::Query(json) => query(json).await,
+        Command::Status(json) => status(json).await,
+        Command::Metrics(json) => metrics(json).await,
+        Command::Pause => pause(true).await,
+        Command::Resume => pause(false).await,
+        Command::Reload => reload().await,
+        Command::Shutdown(force) => shutdown(force).await,
+        Command::Cancel => cancel().await,
+        Command::Recover => recover().await,
+        Command::Attach => attach().await,
+        Command::Detach => detach().await,
+        Command::Check => check().await,
+        Command::Iommu => iommu().await,
+        Command::Logs(kind, follow) => logs(kind, follow).await,
+        Command::Console(name) => console(name).await,
+        Command::Version => version().await,
         Command::Help => help().await
     }
 }
 // start the looking glass windows vm
-pub async fn start_lg(path: String) -> Result<(), CliError> {
+pub async fn start_lg(path: String, name: Option<String>, profile: Option<String>) -> Result<(), CliError> {
     let (conn, h) = get_system_conn()?;
-    let proxy = Proxy::new("org.cws.WindowsLauncher", "/org/cws/WindowsLauncher", Duration::from_secs(2), conn.clone());
-    let _: () = proxy.method_call("org.cws.WindowsLauncher.Manager", "LaunchLG", (path,)).await.map_err(|err| CliError::FailedToLaunchLG(err))?;
+    launch(conn, VmType::LookingGlass, path, name, profile).await.map_err(CliError::FailedToLaunchLG)?;
     h.abort();
     Ok(())
 }
 // start the spice windows vm
-pub async fn start_spice(path: String) -> Result<(), CliError> {
+pub async fn start_spice(path: String, name: Option<String>, profile: Option<String>) -> Result<(), CliError> {
+    let (conn, h) = get_system_conn()?;
+    launch(conn, VmType::Spice, path, name, profile).await.map_err(CliError::FailedToLaunchSpice)?;
+    h.abort();
+    open().await?;
+    Ok(())
+}
+// start the spice windows vm without touching the host
+pub async fn start_spice_lite(name: Option<String>, profile: Option<String>) -> Result<(), CliError> {
     let (conn, h) = get_system_conn()?;
-    let proxy = Proxy::new("org.cws.WindowsLauncher", "/org/cws/WindowsLauncher", Duration::from_secs(2), conn.clone());
-    let _: () = proxy.method_call("org.cws.WindowsLauncher.Manager", "LaunchSpice", (path,)).await.map_err(|err| CliError::FailedToLaunchSpice(err))?;
+    launch(conn, VmType::SpiceLite, String::new(), name, profile).await.map_err(CliError::FailedToLaunchSpiceLite)?;
     h.abort();
     open().await?;
     Ok(())
 }
+// start the windows vm on the passthrough gpu's own monitor
+pub async fn start_direct(name: Option<String>, profile: Option<String>) -> Result<(), CliError> {
+    let (conn, h) = get_system_conn()?;
+    launch(conn, VmType::Direct, String::new(), name, profile).await.map_err(CliError::FailedToLaunchDirect)?;
+    h.abort();
+    Ok(())
+}
+// ask the server to launch, through LaunchProfile if a profile was given
+pub async fn launch(conn: Arc<SyncConnection>, vm_type: VmType, path: String, name: Option<String>, profile: Option<String>) -> Result<(), dbus::Error> {
+    let proxy = Proxy::new("org.cws.WindowsLauncher", "/org/cws/WindowsLauncher", dbus_timeout(), conn);
+    let name = name.unwrap_or_default();
+    match (profile, vm_type) {
+        (Some(profile), vm_type) => proxy.method_call("org.cws.WindowsLauncher.Manager", "LaunchProfile", (vm_type.as_code(), path, name, profile)).await,
+        (None, VmType::LookingGlass) => proxy.method_call("org.cws.WindowsLauncher.Manager", "LaunchLG", (path, name)).await,
+        (None, VmType::Spice) => proxy.method_call("org.cws.WindowsLauncher.Manager", "LaunchSpice", (path, name)).await,
+        (None, VmType::SpiceLite) => proxy.method_call("org.cws.WindowsLauncher.Manager", "LaunchSpiceLite", (name,)).await,
+        (None, VmType::Direct) => proxy.method_call("org.cws.WindowsLauncher.Manager", "LaunchDirect", (name,)).await
+    }
+}
+// print the profiles in the config
+pub async fn list_profiles() -> Result<(), CliError> {
+    let config = Config::load().map_err(CliError::FailedToLoadConfig)?;
+    for name in config.profile_names() {
+        println!("{}", name);
+    }
+    Ok(())
+}
 // start the user session
 pub async fn open() -> Result<(), CliError> {
     let (conn, h) = get_session_conn()?;
-    let proxy = Proxy::new("org.freedesktop.systemd1", "/org/freedesktop/systemd1", Duration::from_secs(2), conn.clone());
+    let proxy = Proxy::new("org.freedesktop.systemd1", "/org/freedesktop/systemd1", dbus_timeout(), conn.clone());
     let _: (Path,) = proxy.method_call("org.freedesktop.systemd1.Manager", "StartUnit", ("windows-launcher.service", "replace")).await
-        .map_err(|err| CliError::FailedToStartUserService(err))?;
+        .map_err(CliError::FailedToStartUserService)?;
     h.abort();
     Ok(())
 }
 // query the state of the vm
-pub async fn query() -> Result<(), CliError> {
+pub async fn query(json: bool) -> Result<(), CliError> {
     let (conn, h) = get_system_conn()?;
-    let proxy = Proxy::new("org.cws.WindowsLauncher", "/org/cws/WindowsLauncher", Duration::from_secs(2), conn.clone());
+    let proxy = Proxy::new("org.cws.WindowsLauncher", "/org/cws/WindowsLauncher", dbus_timeout(), conn.clone());
     let (state, t): (String, String) = proxy.method_call("org.cws.WindowsLauncher.Manager", "Query", ()).await
-        .map_err(|err| CliError::FailedToQueryState(err))?;
-    println!("VM State: {}", state);
-    println!("VM Type: {}", t);
+        .map_err(CliError::FailedToQueryState)?;
+    if json {
+        println!("{}", serde_json::json!({"state": state, "type": t}));
+    }else {
+        println!("VM State: {}", state);
+        println!("VM Type: {}", t);
+    }
+    h.abort();
+    Ok(())
+}
+// print the vm state along with gpu and service state
+pub async fn status(json: bool) -> Result<(), CliError> {
+    let (conn, h) = get_system_conn()?;
+    let proxy = Proxy::new("org.cws.WindowsLauncher", "/org/cws/WindowsLauncher", dbus_timeout(), conn.clone());
+    let (status,): (HashMap<String, String>,) = proxy.method_call("org.cws.WindowsLauncher.Manager", "QueryDetailed", ()).await
+        .map_err(CliError::FailedToQueryState)?;
+    if json {
+        println!("{}", serde_json::json!(status));
+        h.abort();
+        return Ok(());
+    }
+    let mut rows = status.into_iter().collect::<Vec<(String, String)>>();
+    rows.sort();
+    let width = rows.iter().map(|(key, _)| key.len()).max().unwrap_or(0);
+    for (key, value) in rows {
+        println!("{:width$} | {}", key, value, width = width);
+    }
+    h.abort();
+    Ok(())
+}
+// print how long each launch phase last took and how often it failed
+pub async fn metrics(json: bool) -> Result<(), CliError> {
+    let (conn, h) = get_system_conn()?;
+    let proxy = Proxy::new("org.cws.WindowsLauncher", "/org/cws/WindowsLauncher", dbus_timeout(), conn.clone());
+    let (phases,): (HashMap<String, (f64, u64, u64)>,) = proxy.method_call("org.cws.WindowsLauncher.Manager", "Metrics", ()).await
+        .map_err(CliError::FailedToQueryMetrics)?;
+    h.abort();
+    if json {
+        let phases = phases.into_iter().map(|(phase, (secs, successes, failures))| (phase, serde_json::json!({"last_secs": secs, "successes": successes, "failures": failures})))
+            .collect::<serde_json::Map<String, serde_json::Value>>();
+        println!("{}", serde_json::Value::Object(phases));
+        return Ok(());
+    }
+    if phases.is_empty() {
+        println!("No launches since the server started");
+        return Ok(());
+    }
+    let mut rows = phases.into_iter().collect::<Vec<(String, (f64, u64, u64))>>();
+    rows.sort_by(|a, b| a.0.cmp(&b.0));
+    let width = rows.iter().map(|(phase, _)| phase.len()).max().unwrap_or(0);
+    for (phase, (secs, successes, failures)) in rows {
+        println!("{:width$} | last {:.1}s | {} ok | {} failed", phase, secs, successes, failures, width = width);
+    }
+    Ok(())
+}
+// pause or resume the vm
+pub async fn pause(pause: bool) -> Result<(), CliError> {
+    let (conn, h) = get_system_conn()?;
+    let proxy = Proxy::new("org.cws.WindowsLauncher", "/org/cws/WindowsLauncher", dbus_timeout(), conn.clone());
+    let _: () = proxy.method_call("org.cws.WindowsLauncher.Manager", if pause {"Pause"} else {"Resume"}, ()).await
+        .map_err(CliError::FailedToCallPause)?;
+    h.abort();
+    Ok(())
+}
+// have the server re-read its config file
+pub async fn reload() -> Result<(), CliError> {
+    let (conn, h) = get_system_conn()?;
+    let proxy = Proxy::new("org.cws.WindowsLauncher", "/org/cws/WindowsLauncher", dbus_timeout(), conn.clone());
+    let _: () = proxy.method_call("org.cws.WindowsLauncher.Manager", "Reload", ()).await
+        .map_err(CliError::FailedToReload)?;
     h.abort();
     Ok(())
 }
 // shutdown the vm
-pub async fn shutdown() -> Result<(), CliError> {
+pub async fn shutdown(force: bool) -> Result<(), CliError> {
+    let (conn, h) = get_system_conn()?;
+    let proxy = Proxy::new("org.cws.WindowsLauncher", "/org/cws/WindowsLauncher", shutdown_timeout(), conn.clone());
+    let _: () = proxy.method_call("org.cws.WindowsLauncher.Manager", if force {"ForceShutdown"} else {"Shutdown"}, ()).await
+        .map_err(CliError::FailedToCallShutdown)?;
+    h.abort();
+    Ok(())
+}
+// cancel a launch that hasn't started the vm yet
+pub async fn cancel() -> Result<(), CliError> {
+    let (conn, h) = get_system_conn()?;
+    let proxy = Proxy::new("org.cws.WindowsLauncher", "/org/cws/WindowsLauncher", shutdown_timeout(), conn.clone());
+    let _: () = proxy.method_call("org.cws.WindowsLauncher.Manager", "CancelLaunch", ()).await
+        .map_err(CliError::FailedToCancelLaunch)?;
+    h.abort();
+    Ok(())
+}
+// put the host back to normal, without going through the server
+pub async fn recover() -> Result<(), CliError> {
+    if !Uid::effective().is_root() {return Err(CliError::NotRunAsRoot("--recover".to_string()));}
+    let config = Config::load().map_err(CliError::FailedToLoadConfig)?;
     let (conn, h) = get_system_conn()?;
-    let proxy = Proxy::new("org.cws.WindowsLauncher", "/org/cws/WindowsLauncher", Duration::from_secs(30), conn.clone());
-    let _: () = proxy.method_call("org.cws.WindowsLauncher.Manager", "Shutdown", ()).await
-        .map_err(|err| CliError::FailedToCallShutdown(err))?;
+    let errors = crate::launcher::recover(conn, &config).await;
     h.abort();
+    if !errors.is_empty() {return Err(CliError::FailedToRecover(errors));}
+    Ok(())
+}
+// hand the gpu to vfio-pci without launching a vm
+pub async fn detach() -> Result<(), CliError> {
+    if !Uid::effective().is_root() {return Err(CliError::NotRunAsRoot("--detach".to_string()));}
+    let config = Config::load().map_err(CliError::FailedToLoadConfig)?;
+    let (conn, h) = get_system_conn()?;
+    println!("Detaching {}", config.gpu_pci_ids.iter().chain(config.extra_pci_ids.iter()).cloned().collect::<Vec<String>>().join(", "));
+    let result = crate::launcher::detach_gpu(conn, &config).await;
+    h.abort();
+    result.map_err(CliError::FailedToDetach)?;
+    println!("Gpu is bound to vfio-pci, run --attach to give it back");
+    Ok(())
+}
+// give the gpu back to the host after --detach
+pub async fn attach() -> Result<(), CliError> {
+    if !Uid::effective().is_root() {return Err(CliError::NotRunAsRoot("--attach".to_string()));}
+    let config = Config::load().map_err(CliError::FailedToLoadConfig)?;
+    let (conn, h) = get_system_conn()?;
+    println!("Reattaching the gpu");
+    let errors = crate::launcher::attach_gpu(conn, &config).await;
+    h.abort();
+    if !errors.is_empty() {return Err(CliError::FailedToAttach(errors));}
+    println!("Gpu is bound to its host drivers");
+    Ok(())
+}
+// check the environment for common misconfiguration, printing a line per check
+pub async fn check() -> Result<(), CliError> {
+    let mut failures = 0;
+    let mut report = |name: &str, result: Result<String, String>| {
+        match result {
+            Ok(detail) => println!("PASS | {}: {}", name, detail),
+            Err(detail) => {println!("FAIL | {}: {}", name, detail); failures += 1;}
+        }
+    };
+    let config = match Config::load() {
+        Ok(config) => {report("config", Ok("loaded".to_string())); config},
+        Err(err) => {report("config", Err(err.to_string())); Config::default()}
+    };
+    // xml templates. only looking glass and spice need the mouse placeholder, and only with the evdev virtual mouse on
+    for vm_type in [VmType::LookingGlass, VmType::Spice, VmType::SpiceLite, VmType::Direct] {
+        let name = format!("{} xml", vm_type);
+        let Some(path) = config.xml_path(&vm_type) else {
+            println!("SKIP | {}: not configured", name);
+            continue;
+        };
+        let needs_mouse = config.virtual_mouse(&vm_type);
+        report(&name, match std::fs::read_to_string(&path) {
+            Err(err) => Err(format!("could not read {}: {}", path, err)),
+            Ok(xml) if needs_mouse && !xml.contains(MOUSE_PLACEHOLDER) => Err(format!("{} does not contain {}", path, MOUSE_PLACEHOLDER)),
+            Ok(_) => Ok(path)
+        });
+    }
+    // binaries
+    for binary in ["virsh", "modprobe", "looking-glass-client", "virt-viewer"] {
+        report(binary, find_on_path(binary).map(|path| path.display().to_string()).ok_or("not found on PATH".to_string()));
+    }
+    // pci node devices
+    match tokio::process::Command::new("virsh").args(["-c", &config.libvirt_uri, "nodedev-list"]).output().await {
+        Ok(out) => {
+            let devices = String::from_utf8_lossy(&out.stdout);
+            for pci in config.gpu_pci_ids.iter().chain(config.extra_pci_ids.iter()) {
+                report(pci, if devices.lines().any(|device| device.trim() == pci) {Ok("exists".to_string())} else {Err("not listed by virsh nodedev-list".to_string())});
+            }
+        },
+        Err(err) => {report("pci devices", Err(format!("could not run virsh nodedev-list: {}", err)));}
+    }
+    // iommu
+    let devices = config.gpu_pci_ids.iter().chain(config.extra_pci_ids.iter()).cloned().collect::<Vec<String>>();
+    report("cpu masks", crate::launcher::check_cpu_masks(&config).map(|_| format!("host {}, default {}", config.host_cpu_mask, config.default_cpu_mask)).map_err(|err| err.to_string()));
+    report("iommu", crate::launcher::iommu_preflight(&devices).map(|_| "enabled, devices are isolated".to_string()).map_err(|err| err.to_string()));
+    // virtual mouse service
+    if !config.use_virtual_mouse {
+        println!("SKIP | org.cws.VirtualMouse: use_virtual_mouse is off");
+    } else if config.input_mode == InputMode::VirtioTablet {
+        println!("SKIP | org.cws.VirtualMouse: input_mode is virtio_tablet");
+    } else {
+        match get_system_conn() {
+            Ok((conn, h)) => {
+                let proxy = Proxy::new("org.freedesktop.DBus", "/org/freedesktop/DBus", config.dbus_timeout(), conn.clone());
+                let result: Result<(bool,), dbus::Error> = proxy.method_call("org.freedesktop.DBus", "NameHasOwner", ("org.cws.VirtualMouse",)).await;
+                report("org.cws.VirtualMouse", match result {
+                    Ok((true,)) => Ok("owned".to_string()),
+                    Ok((false,)) => Err("no service owns the name".to_string()),
+                    Err(err) => Err(err.to_string())
+                });
+                h.abort();
+            },
+            Err(err) => {report("system bus", Err(err.to_string()));}
+        }
+    }
+    if failures > 0 {return Err(CliError::ChecksFailed(failures));}
+    Ok(())
+}
+// returns the config, or the default with the environment applied if it can't be read, so the cli still works
+fn config_or_default() -> Config {
+    Config::load().unwrap_or_else(|_| {
+        let mut config = Config::default();
+        config.apply_env();
+        config
+    })
+}
+// returns the configured dbus timeout
+pub fn dbus_timeout() -> Duration {
+    config_or_default().dbus_timeout()
+}
+// returns how long a shutdown can take: the guest's grace period, plus a minute for the host cleanup
+pub fn shutdown_timeout() -> Duration {
+    Duration::from_secs(config_or_default().shutdown_grace_secs + 60)
+}
+// print every iommu group, marking the configured passthrough devices and anything else sharing their groups
+pub async fn iommu() -> Result<(), CliError> {
+    let config = Config::load().unwrap_or_else(|err| {
+        println!("Could not load the config, marking the default devices: {}", err);
+        Config::default()
+    });
+    let groups = iommu_groups().map_err(CliError::FailedToReadIommuGroups)?;
+    if groups.is_empty() {return Err(CliError::IommuNotEnabled);}
+    let passthrough = config.gpu_pci_ids.iter().chain(config.extra_pci_ids.iter())
+        .filter_map(|pci| pci_sysfs_address(pci)).collect::<Vec<String>>();
+    for (group, devices) in groups {
+        let shared = devices.iter().any(|device| passthrough.contains(device));
+        println!("Group {}:", group);
+        for device in devices {
+            let class = device_class(&device).unwrap_or("unknown".to_string());
+            let mark = if passthrough.contains(&device) {"*"}
+                else if shared && !is_bridge(&device) {"!"}
+                else {" "};
+            println!("  {} {} [{}]", mark, device, class);
+        }
+    }
+    println!("* passthrough device, ! shares a group with one and would have to be passed through too");
+    Ok(())
+}
+// returns the first file called binary in the PATH directories
+pub fn find_on_path(binary: &str) -> Option<std::path::PathBuf> {
+    std::env::var_os("PATH").and_then(|paths| std::env::split_paths(&paths).map(|dir| dir.join(binary)).find(|path| path.is_file()))
+}
+// print the newest vm or viewer log, and keep printing what is appended to it if follow is set
+pub async fn logs(kind: String, follow: bool) -> Result<(), CliError> {
+    let config = Config::load().map_err(CliError::FailedToLoadConfig)?;
+    let dir = std::path::Path::new(&config.log_dir).join(&kind);
+    let path = newest_log(&dir).ok_or(CliError::NoLogsFound(dir.display().to_string()))?;
+    let mut file = tokio::fs::File::open(&path).await.map_err(CliError::FailedToReadLog)?;
+    let mut stdout = tokio::io::stdout();
+    loop {
+        tokio::io::copy(&mut file, &mut stdout).await.map_err(CliError::FailedToReadLog)?;
+        stdout.flush().await.map_err(CliError::FailedToReadLog)?;
+        if !follow {return Ok(());}
+        tokio::time::sleep(Duration::from_millis(500)).await;
+    }
+}
+// attach the terminal to the domain's serial console until the user detaches
+pub async fn console(name: Option<String>) -> Result<(), CliError> {
+    let config = config_or_default();
+    let name = match name {
+        Some(name) => name,
+        None => running_vm_name().await.unwrap_or(config.vm_name.clone())
+    };
+    println!("Attaching to the serial console of {}, detach with ctrl+]", name);
+    // virsh puts the terminal in raw mode and restores it on detach, so the child gets the terminal as it is
+    let status = tokio::process::Command::new("virsh").args(["-c", &config.libvirt_uri, "console", &name]).status().await
+        .map_err(CliError::FailedToRunConsole)?;
+    if !status.success() {return Err(CliError::ConsoleFailed(status));}
+    Ok(())
+}
+// returns the domain of the vm the server is running, if it is reachable and running one
+pub async fn running_vm_name() -> Option<String> {
+    let (conn, h) = get_system_conn().ok()?;
+    let proxy = Proxy::new("org.cws.WindowsLauncher", "/org/cws/WindowsLauncher", dbus_timeout(), conn.clone());
+    let result: Result<(HashMap<String, String>,), dbus::Error> = proxy.method_call("org.cws.WindowsLauncher.Manager", "QueryDetailed", ()).await;
+    h.abort();
+    let (status,) = result.ok()?;
+    if status.get("vm_state").is_some_and(|state| *state == VmState::Inactive.to_string()) {return None;}
+    status.get("vm_name").filter(|name| !name.is_empty()).cloned()
+}
+// print the version, commit, and config modes, for bug reports
+pub async fn version() -> Result<(), CliError> {
+    println!("windows-launcher {} ({})", env!("CARGO_PKG_VERSION"), env!("GIT_COMMIT"));
+    let path = std::env::var("WINDOWS_VM_CONFIG").unwrap_or(DEFAULT_CONFIG_PATH.to_string());
+    let config = match Config::load() {
+        Ok(config) => {println!("config: {}", path); config},
+        Err(err) => {println!("config: {} could not be loaded, showing the defaults: {}", path, err); config_or_default()}
+    };
+    let on_off = |on: bool| if on {"on"} else {"off"};
+    println!("virtual mouse: {}", on_off(config.use_virtual_mouse));
+    println!("input mode: {}", config.input_mode);
+    println!("gpu management: {}", if config.gpu_pci_ids.is_empty() {"off"} else if config.igpu_host {"igpu host"} else {"on"});
+    println!("vfio management: {}", on_off(config.manage_vfio));
+    println!("cpu isolation: {}", on_off(config.isolate_cpus));
+    println!("hugepages: {}", config.hugepages);
+    println!("status socket: {}", on_off(config.status_socket));
+    println!("control socket: {}", config.control_socket.as_deref().unwrap_or("off"));
+    println!("libvirt uri: {}", config.libvirt_uri);
     Ok(())
 }
 // print a help message
@@ -106,24 +546,114 @@ pub async fn help() -> Result<(), CliError> {
     println!("Usage:");
     println!("--server: starts the system server, used as a start command for a systemd service");
     println!("--session: start the session server, used as a start command foir a systemd user service");
-    println!("--spice: starts the spice vm, and then the user service. requires mouse evdev path as second arg");
-    println!("--lg: start the looking glass vm. requires mouse evdev path as second arg");
+    println!("--spice: starts the spice vm, and then the user service. requires mouse evdev path as second arg, and optionally the libvirt domain name as third");
+    println!("--spice-lite: starts the spice vm without cpu pinning, governor changes, or a virtual mouse, and then the user service. optionally takes the libvirt domain name");
+    println!("--direct: starts the vm on the passthrough gpu's own monitor, with no viewer or virtual mouse. optionally takes the libvirt domain name");
+    println!("--lg: start the looking glass vm. requires mouse evdev path as second arg, and optionally the libvirt domain name as third");
     println!("--open: starts the user session service to open the correct vm viewer");
-    println!("--query: returns the state of the vm");
-    println!("--shutdown: stops the vm");
+    println!("--query: returns the state of the vm. add --json for a single line json object");
+    println!("--status: returns the state of the vm, gpu, display manager, and connected users. add --json for a single line json object");
+    println!("--metrics: prints how long gpu detach, setup, vm boot, and cleanup last took, and how often each has succeeded and failed. add --json for a single line json object");
+    println!("--pause: pauses the vm");
+    println!("--resume: resumes the vm, unless the lid is closed");
+    println!("--reload: makes the server re-read its config file. while a vm is running, only changes that don't affect it are accepted");
+    println!("--shutdown: stops the vm. add --force to destroy a hung vm instead of waiting for it to shut down");
+    println!("--cancel: cancels a launch that has not started the vm yet, and puts the host back");
+    println!("--check: checks the config, xml files, binaries, pci devices, and virtual mouse service, printing a line per check");
+    println!("--iommu: prints every iommu group, marking the configured passthrough devices and anything sharing their groups");
+    println!("--console: attaches to the running vm's serial console, or the given domain's, to watch it boot. detach with ctrl+]");
+    println!("--logs: prints the newest log, of the vm by default or the viewer with \"--logs viewer\". add --follow to keep printing new output");
+    println!("--recover: reattaches the gpu and restores cpus, governor, and display manager, regardless of server state. must be run as root");
+    println!("--detach: stops the display manager and hands the gpu to vfio-pci without launching a vm, for testing. must be run as root");
+    println!("--attach: gives the gpu back to the host after --detach. must be run as root");
+    println!("--profile: follows any launch command to launch with a named profile from the config, e.g. --lg /dev/input/event7 --profile gaming");
+    println!("--list-profiles: prints the profile names in the config");
+    println!("--version: prints the version, the git commit it was built from, and which optional parts of the config are on, for bug reports");
     println!("--help: shows this help message");
     Ok(())
 }
 
 pub fn get_system_conn() -> Result<(Arc<SyncConnection>, JoinHandle<IOResourceError>), CliError>{
-    let (r, conn) = dbus_tokio::connection::new_system_sync().map_err(|err| CliError::FailedToConnectToSystemBus(err))?;
+    let (r, conn) = dbus_tokio::connection::new_system_sync().map_err(CliError::FailedToConnectToSystemBus)?;
     let handle = tokio::spawn(r);
-    return Ok((conn, handle));
+    Ok((conn, handle))
 }
 
 pub fn get_session_conn() -> Result<(Arc<SyncConnection>, JoinHandle<IOResourceError>), CliError>{
-    let (r, conn) = dbus_tokio::connection::new_session_sync().map_err(|err| CliError::FailedToConnectToSessionBus(err))?;
+    let (r, conn) = dbus_tokio::connection::new_session_sync().map_err(CliError::FailedToConnectToSessionBus)?;
     let handle = tokio::spawn(r);
-    return Ok((conn, handle));
+    Ok((conn, handle))
 }
 
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(args: &[&str]) -> Vec<String> {
+        args.iter().map(|arg| arg.to_string()).collect()
+    }
+
+    #[test]
+    fn start_commands_take_mouse_path_and_optional_name() {
+        assert_eq!(parse_command(&args(&["--lg", "/dev/input/event7"])), Command::Start(VmType::LookingGlass, "/dev/input/event7".to_string(), None, None));
+        assert_eq!(parse_command(&args(&["--spice", "/dev/input/event7", "windows11"])), 
+            Command::Start(VmType::Spice, "/dev/input/event7".to_string(), Some("windows11".to_string()), None));
+        assert_eq!(parse_command(&args(&["--spice-lite"])), Command::Start(VmType::SpiceLite, String::new(), None, None));
+        assert_eq!(parse_command(&args(&["--direct", "windows11"])), Command::Start(VmType::Direct, String::new(), Some("windows11".to_string()), None));
+    }
+
+    #[test]
+    fn profile_follows_launch_commands() {
+        assert_eq!(parse_command(&args(&["--lg", "/dev/input/event7", "--profile", "gaming"])), 
+            Command::Start(VmType::LookingGlass, "/dev/input/event7".to_string(), None, Some("gaming".to_string())));
+        assert_eq!(parse_command(&args(&["--profile", "work", "--direct"])), Command::Start(VmType::Direct, String::new(), None, Some("work".to_string())));
+        assert_eq!(parse_command(&args(&["--lg", "/dev/input/event7", "--profile"])), Command::Help);
+        assert_eq!(parse_command(&args(&["--query", "--profile", "work"])), Command::Help);
+        assert_eq!(parse_command(&args(&["--list-profiles"])), Command::ListProfiles);
+    }
+
+    #[test]
+    fn missing_mouse_path_is_help() {
+        assert_eq!(parse_command(&args(&["--spice"])), Command::Help);
+        assert_eq!(parse_command(&args(&["--lg"])), Command::Help);
+    }
+
+    #[test]
+    fn extra_args_are_help() {
+        assert_eq!(parse_command(&args(&["--lg", "/dev/input/event7", "windows11", "extra"])), Command::Help);
+        assert_eq!(parse_command(&args(&["--query", "extra"])), Command::Help);
+        assert_eq!(parse_command(&args(&["--attach", "extra"])), Command::Help);
+    }
+
+    #[test]
+    fn unknown_flags_are_help() {
+        assert_eq!(parse_command(&args(&[])), Command::Help);
+        assert_eq!(parse_command(&args(&["--bogus"])), Command::Help);
+        assert_eq!(parse_command(&args(&["--help"])), Command::Help);
+        assert_eq!(parse_command(&args(&["--status", "--yaml"])), Command::Help);
+        assert_eq!(parse_command(&args(&["--logs", "bogus"])), Command::Help);
+    }
+
+    #[test]
+    fn control_commands_parse() {
+        assert_eq!(parse_command(&args(&["--shutdown"])), Command::Shutdown(false));
+        assert_eq!(parse_command(&args(&["--shutdown", "--force"])), Command::Shutdown(true));
+        assert_eq!(parse_command(&args(&["--cancel"])), Command::Cancel);
+        assert_eq!(parse_command(&args(&["--reload"])), Command::Reload);
+        assert_eq!(parse_command(&args(&["--recover"])), Command::Recover);
+        assert_eq!(parse_command(&args(&["--detach"])), Command::Detach);
+        assert_eq!(parse_command(&args(&["--iommu"])), Command::Iommu);
+        assert_eq!(parse_command(&args(&["--version"])), Command::Version);
+    }
+
+    #[test]
+    fn report_commands_take_their_flags() {
+        assert_eq!(parse_command(&args(&["--status"])), Command::Status(false));
+        assert_eq!(parse_command(&args(&["--query", "--json"])), Command::Query(true));
+        assert_eq!(parse_command(&args(&["--metrics", "--json"])), Command::Metrics(true));
+        assert_eq!(parse_command(&args(&["--logs", "viewer", "--follow"])), Command::Logs("viewer".to_string(), true));
+        assert_eq!(parse_command(&args(&["--console"])), Command::Console(None));
+        assert_eq!(parse_command(&args(&["--console", "windows-gaming"])), Command::Console(Some("windows-gaming".to_string())));
+    }
+}
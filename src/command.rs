@@ -0,0 +1,25 @@
+/*
+    Runs external programs like modprobe and virsh
+    The launcher goes through CommandRunner, so its setup steps can be tested without root or real devices
+*/
+
+use std::{future::Future, pin::Pin, process::{Output, Stdio}};
+
+/// future returned by CommandRunner::run
+pub type CommandFuture<'a> = Pin<Box<dyn Future<Output = std::io::Result<Output>> + Send + 'a>>;
+
+/// Runs a program to completion, capturing its output
+pub trait CommandRunner: Send + Sync {
+    fn run<'a>(&'a self, program: &'a str, args: &'a [&'a str]) -> CommandFuture<'a>;
+}
+
+/// Runs programs on the host
+#[derive(Debug, Default, Clone, Copy)]
+pub struct SystemRunner;
+impl CommandRunner for SystemRunner {
+    fn run<'a>(&'a self, program: &'a str, args: &'a [&'a str]) -> CommandFuture<'a> {
+        let mut command = tokio::process::Command::new(program);
+        command.args(args).stdin(Stdio::null());
+        Box::pin(async move {command.output().await})
+    }
+}
@@ -0,0 +1,416 @@
+/*
+    Configuration for the root server, read from a toml file at startup
+    Every field has a default matching the original hardcoded setup, so the file is optional
+*/
+
+use std::{collections::HashMap, error::Error, fmt::Display, path::Path, time::Duration};
+use serde::Deserialize;
+use crate::{launcher::{VmType, DEFAULT_ALLOWED_CPUS, HOST_ALLOWED_CPUS}, logs::{DEFAULT_LOG_DIR, DEFAULT_LOG_KEEP}};
+
+/// default location of the config file, overridable with WINDOWS_VM_CONFIG
+pub const DEFAULT_CONFIG_PATH: &str = "/etc/windows-vm-launcher/config.toml";
+
+/// Represents all ways loading the config can fail
+#[derive(Debug)]
+pub enum ConfigError{
+    FailedToReadConfig(String, std::io::Error),
+    FailedToParseConfig(String, toml::de::Error)
+}
+impl Display for ConfigError{
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let _ = f.write_str(&match self {
+            Self::FailedToReadConfig(path, err) => format!("Could not read the config file {}: {}", *path, *err),
+            Self::FailedToParseConfig(path, err) => format!("Could not parse the config file {}: {}", *path, *err)
+        });
+        Ok(())
+    }
+}
+impl Error for ConfigError{}
+
+/// How lg and spice guests get pointer input
+#[derive(Deserialize, Debug, Clone, Copy, Default, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum InputMode{
+    /// a virtual mouse created from the host pointer device, passed through as an evdev device
+    #[default]
+    Evdev,
+    /// a virtio tablet in the domain xml, which needs no host device
+    VirtioTablet
+}
+impl InputMode{
+    /// parses the name used in the config file, e.g. virtio_tablet
+    pub fn from_name(name: &str) -> Option<Self>{
+        match name {
+            "evdev" => Some(Self::Evdev),
+            "virtio_tablet" => Some(Self::VirtioTablet),
+            _ => None
+        }
+    }
+}
+impl Display for InputMode{
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Self::Evdev => "evdev",
+            Self::VirtioTablet => "virtio_tablet"
+        })
+    }
+}
+
+/// All tunables of the launcher
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(default)]
+pub struct Config{
+    /// path of the looking glass vm xml, falls back to WINDOWS_LG_XML
+    pub lg_xml_path: Option<String>,
+    /// path of the spice vm xml, falls back to WINDOWS_SPICE_XML
+    pub spice_xml_path: Option<String>,
+    /// path of the spice lite vm xml, without the virtual mouse. falls back to WINDOWS_SPICE_LITE_XML
+    pub spice_lite_xml_path: Option<String>,
+    /// path of the direct vm xml, without the virtual mouse. falls back to WINDOWS_DIRECT_XML
+    pub direct_xml_path: Option<String>,
+    /// libvirt node devices of the gpu, detached in order
+    pub gpu_pci_ids: Vec<String>,
+    /// nvidia kernel modules, unloaded in order and reloaded in reverse
+    pub nvidia_modules: Vec<String>,
+    /// other libvirt node devices to pass through, e.g. usb controllers. detached after the gpu, reattached before it
+    pub extra_pci_ids: Vec<String>,
+    /// kernel modules the extra devices need unloaded first, e.g. xhci_hcd
+    pub extra_modules: Vec<String>,
+    /// cpus the host is limited to while the vm runs, overridden by HOST_ALLOWED_CPUS
+    pub host_cpu_mask: String,
+    /// cpus the host gets back after the vm closes, overridden by DEFAULT_ALLOWED_CPUS
+    pub default_cpu_mask: String,
+    /// libvirt domain name of the vm
+    pub vm_name: String,
+    /// cpufreq governor set while the vm runs, overridden by VM_GOVERNOR. the original is restored afterwards
+    pub vm_governor: String,
+    /// seconds between checks of whether the vm has closed
+    pub vm_poll_interval_secs: u64,
+    /// seconds to wait for the vm to close before giving up, forever if unset
+    pub vm_max_wait_secs: Option<u64>,
+    /// seconds a launch may take to get the vm running before it is aborted and cleaned up, 0 to wait forever. overridden by LAUNCH_TIMEOUT_SECS
+    pub launch_timeout_secs: u64,
+    /// seconds a shutdown waits for the guest to power off before destroying it, overridden by SHUTDOWN_GRACE_SECS
+    pub shutdown_grace_secs: u64,
+    /// whether the acpi shutdown is sent again halfway through the grace period
+    pub shutdown_retry: bool,
+    /// directory the vm and viewer logs are written under, in vm/ and viewer/
+    pub log_dir: String,
+    /// how many old logs of each kind are kept when a new one is created
+    pub log_keep: usize,
+    /// whether the session raises desktop notifications when the vm is ready or shutting down
+    pub notifications: bool,
+    /// seconds a session waits on UserConnected before the server tells it to retry, overridden by USER_CONNECT_TIMEOUT
+    pub user_connect_timeout_secs: u64,
+    /// kill processes still holding /dev/nvidia* after the display manager stops, overridden by FORCE_KILL_GPU_PROCS
+    pub force_kill_gpu_procs: bool,
+    /// root process names waited on to exit after the display manager stops, matched against the whole command name
+    pub gpu_process_names: Vec<String>,
+    /// user units stopped for every logged in user before the gpu is detached, in order, and started again in reverse. overridden by USER_UNITS_TO_STOP
+    pub user_units: Vec<String>,
+    /// milliseconds to wait after user_units' stop jobs finish before the gpu drivers are unloaded, so their clients can let go of it. overridden by USER_UNITS_SETTLE_MS
+    pub user_units_settle_ms: u64,
+    /// values for {{NAME}} tokens in the vm xml, keyed by NAME
+    pub xml_substitutions: HashMap<String, String>,
+    /// looking glass viewer command line, overridden by LG_VIEWER_CMD
+    pub lg_viewer_cmd: String,
+    /// spice viewer command line, the vm name is appended, and --connect libvirt_uri unless it sets one. overridden by SPICE_VIEWER_CMD
+    pub spice_viewer_cmd: String,
+    /// which sessions open a viewer: "all", "active" for only active login sessions, or a seat name like "seat0"
+    pub viewer_policy: String,
+    /// uids or user names whose sessions connect and open a viewer, empty for everyone. overridden by VIEWER_USERS, comma separated
+    pub viewer_users: Vec<String>,
+    /// unit stopped to free the gpu, and started again afterwards. overridden by DISPLAY_SERVICE
+    pub display_service: String,
+    /// whether display_service is a user unit, run in every logged in user's manager
+    pub display_service_user: bool,
+    /// the host desktop runs on an igpu, so display_service is left running and only what holds the passthrough gpu is stopped. overridden by IGPU_HOST
+    pub igpu_host: bool,
+    /// seconds to wait for the gpu to bind its host driver after reattaching, before the display service starts
+    pub gpu_settle_secs: u64,
+    /// seconds a dbus call waits for its reply, overridden by DBUS_TIMEOUT_SECS
+    pub dbus_timeout_secs: u64,
+    /// libvirt connection every virsh call and the spice viewer use, overridden by LIBVIRT_URI
+    pub libvirt_uri: String,
+    /// minutes the vm may run with no graphical login session before it is shut down, 0 to never. overridden by IDLE_SHUTDOWN_MINUTES
+    pub idle_shutdown_minutes: u64,
+    /// whether lg and spice launches create a virtual mouse. off for a usb mouse passed through in the xml. overridden by USE_VIRTUAL_MOUSE
+    pub use_virtual_mouse: bool,
+    /// how lg and spice guests get pointer input. virtio_tablet needs no virtual mouse or mouse path. overridden by INPUT_MODE
+    pub input_mode: InputMode,
+    /// name the virtual mouse is created with
+    pub mouse_name: String,
+    /// whether to suffix mouse_name with the launch time, so a mouse left by a crashed launch can't block the next one
+    pub mouse_name_unique: bool,
+    /// looking glass shared memory, a /dev/shm file or a kvmfr device, given to the connecting user. defaults to /dev/shm/looking-glass, set it to "" to leave it alone
+    pub lg_shm_path: Option<String>,
+    /// mode the shared memory is given, e.g. 0o660
+    pub lg_shm_mode: u32,
+    /// module loaded to bind the passthrough devices, e.g. vfio-pci or vfio_pci
+    pub vfio_module: String,
+    /// options passed to modprobe with vfio_module, e.g. ["ids=10de:2684", "disable_vga=1"]
+    pub vfio_options: Vec<String>,
+    /// whether to load vfio_module before a launch and unload it after. off when it is bound by the initramfs or driverctl
+    pub manage_vfio: bool,
+    /// whether to serve the json status on STATUS_SOCKET_PATH, for monitors that don't want to speak dbus
+    pub status_socket: bool,
+    /// path of the unix socket json control interface, none to leave it off. overridden by WINDOWS_LAUNCHER_SOCKET
+    pub control_socket: Option<String>,
+    /// hugepages reserved before the vm starts and given back afterwards, 0 to leave them alone
+    pub hugepages: u64,
+    /// also move host irqs onto host_cpu_mask and make the vm cpus an isolated cpuset partition on machine.slice. overridden by ISOLATE_CPUS
+    pub isolate_cpus: bool,
+    /// executable run before the host is touched for a launch, with VM_TYPE and VM_NAME set. a nonzero exit fails the launch
+    pub pre_launch_hook: Option<String>,
+    /// executable run after a session is cleaned up, with VM_TYPE and VM_NAME set
+    pub post_shutdown_hook: Option<String>,
+    /// shell command run as every graphical user after a direct session gives the gpu back, e.g. "autorandr --change". overridden by MONITOR_RESTORE_CMD
+    pub monitor_restore_cmd: Option<String>,
+    /// size of each hugepage in kib, 1048576 for 1GiB pages or 2048 for 2MiB pages
+    pub hugepage_size_kb: u64,
+    /// named overrides of the vm specific fields, selected per launch
+    pub profiles: HashMap<String, Profile>
+}
+
+/// A named vm setup. every field left out keeps the value from the top level config
+#[derive(Deserialize, Debug, Clone, Default, PartialEq)]
+#[serde(default)]
+pub struct Profile{
+    pub lg_xml_path: Option<String>,
+    pub spice_xml_path: Option<String>,
+    pub spice_lite_xml_path: Option<String>,
+    pub direct_xml_path: Option<String>,
+    pub gpu_pci_ids: Option<Vec<String>>,
+    pub nvidia_modules: Option<Vec<String>>,
+    pub extra_pci_ids: Option<Vec<String>>,
+    pub extra_modules: Option<Vec<String>>,
+    pub host_cpu_mask: Option<String>,
+    pub default_cpu_mask: Option<String>,
+    pub vm_name: Option<String>,
+    pub hugepages: Option<u64>,
+    pub hugepage_size_kb: Option<u64>,
+    pub monitor_restore_cmd: Option<String>,
+    pub input_mode: Option<InputMode>,
+    pub xml_substitutions: Option<HashMap<String, String>>
+}
+impl Default for Config{
+    fn default() -> Self {
+        Self {
+            lg_xml_path: None,
+            spice_xml_path: None,
+            spice_lite_xml_path: None,
+            direct_xml_path: None,
+            gpu_pci_ids: vec!["pci_0000_01_00_0".to_string(), "pci_0000_01_00_1".to_string()],
+            nvidia_modules: vec!["nvidia_uvm".to_string(), "nvidia_drm".to_string(), "nvidia_modeset".to_string(), "nvidia".to_string()],
+            extra_pci_ids: vec![],
+            extra_modules: vec![],
+            host_cpu_mask: HOST_ALLOWED_CPUS.to_string(),
+            default_cpu_mask: DEFAULT_ALLOWED_CPUS.to_string(),
+            vm_name: "windows".to_string(),
+            vm_governor: "performance".to_string(),
+            vm_poll_interval_secs: 2,
+            vm_max_wait_secs: None,
+            shutdown_grace_secs: 30,
+            shutdown_retry: true,
+            log_dir: DEFAULT_LOG_DIR.to_string(),
+            log_keep: DEFAULT_LOG_KEEP,
+            notifications: true,
+            user_connect_timeout_secs: 120,
+            launch_timeout_secs: 600,
+            force_kill_gpu_procs: false,
+            gpu_process_names: ["sddm", "gdm", "X", "Xorg", "Xwayland"].iter().map(|name| name.to_string()).collect(),
+            user_units: ["pipewire.socket", "pipewire-pulse.socket"].iter().map(|unit| unit.to_string()).collect(),
+            user_units_settle_ms: 500,
+            xml_substitutions: HashMap::new(),
+            lg_viewer_cmd: "looking-glass-client -T -s input:captureOnFocus".to_string(),
+            spice_viewer_cmd: "virt-viewer".to_string(),
+            viewer_policy: "active".to_string(),
+            viewer_users: vec![],
+            display_service: "display-manager.service".to_string(),
+            display_service_user: false,
+            igpu_host: false,
+            gpu_settle_secs: 10,
+            dbus_timeout_secs: 2,
+            idle_shutdown_minutes: 0,
+            mouse_name: "WindowsMouse".to_string(),
+            mouse_name_unique: false,
+            use_virtual_mouse: true,
+            input_mode: InputMode::Evdev,
+            lg_shm_path: Some("/dev/shm/looking-glass".to_string()),
+            lg_shm_mode: 0o660,
+            vfio_module: "vfio-pci".to_string(),
+            vfio_options: vec![],
+            manage_vfio: true,
+            status_socket: false,
+            control_socket: None,
+            hugepages: 0,
+            isolate_cpus: false,
+            pre_launch_hook: None,
+            post_shutdown_hook: None,
+            monitor_restore_cmd: None,
+            libvirt_uri: "qemu:///system".to_string(),
+            hugepage_size_kb: 1048576,
+            profiles: HashMap::new()
+        }
+    }
+}
+impl Config{
+    /// Loads the config from WINDOWS_VM_CONFIG or the default path, then applies environment overrides
+    /// A missing file at the default path is not an error, and gives the default config
+    pub fn load() -> Result<Config, ConfigError>{
+        let (path, required) = match std::env::var("WINDOWS_VM_CONFIG") {
+            Ok(path) => (path, true),
+            Err(_) => (DEFAULT_CONFIG_PATH.to_string(), false)
+        };
+        let mut config = if required || Path::new(&path).exists() {
+            let contents = std::fs::read_to_string(&path).map_err(|err| ConfigError::FailedToReadConfig(path.clone(), err))?;
+            toml::from_str(&contents).map_err(|err| ConfigError::FailedToParseConfig(path.clone(), err))?
+        } else {Config::default()};
+        config.apply_env();
+        Ok(config)
+    }
+    /// Overrides fields with any of the older environment variables that are set
+    pub fn apply_env(&mut self){
+        if let Ok(path) = std::env::var("WINDOWS_LG_XML") {self.lg_xml_path = Some(path);}
+        if let Ok(path) = std::env::var("WINDOWS_SPICE_XML") {self.spice_xml_path = Some(path);}
+        if let Ok(path) = std::env::var("WINDOWS_SPICE_LITE_XML") {self.spice_lite_xml_path = Some(path);}
+        if let Ok(path) = std::env::var("WINDOWS_DIRECT_XML") {self.direct_xml_path = Some(path);}
+        if let Ok(cpus) = std::env::var("HOST_ALLOWED_CPUS") {self.host_cpu_mask = cpus;}
+        if let Ok(cpus) = std::env::var("DEFAULT_ALLOWED_CPUS") {self.default_cpu_mask = cpus;}
+        if let Ok(governor) = std::env::var("VM_GOVERNOR") {self.vm_governor = governor;}
+        if let Ok(service) = std::env::var("DISPLAY_SERVICE") {self.display_service = service;}
+        if let Ok(cmd) = std::env::var("LG_VIEWER_CMD") {self.lg_viewer_cmd = cmd;}
+        if let Ok(cmd) = std::env::var("SPICE_VIEWER_CMD") {self.spice_viewer_cmd = cmd;}
+        env_bool("FORCE_KILL_GPU_PROCS", &mut self.force_kill_gpu_procs);
+        env_bool("ISOLATE_CPUS", &mut self.isolate_cpus);
+        env_bool("IGPU_HOST", &mut self.igpu_host);
+        if let Ok(users) = std::env::var("VIEWER_USERS") {self.viewer_users = users.split(',').map(str::trim).filter(|user| !user.is_empty()).map(str::to_string).collect();}
+        if let Ok(units) = std::env::var("USER_UNITS_TO_STOP") {self.user_units = units.split(',').map(str::trim).filter(|unit| !unit.is_empty()).map(str::to_string).collect();}
+        if let Ok(uri) = std::env::var("LIBVIRT_URI") {self.libvirt_uri = uri;}
+        if let Ok(cmd) = std::env::var("MONITOR_RESTORE_CMD") {self.monitor_restore_cmd = Some(cmd).filter(|cmd| !cmd.is_empty());}
+        env_bool("USE_VIRTUAL_MOUSE", &mut self.use_virtual_mouse);
+        if let Ok(mode) = std::env::var("INPUT_MODE") {
+            match InputMode::from_name(&mode) {
+                Some(mode) => {self.input_mode = mode;},
+                None => {log::warn!("Ignoring INPUT_MODE, it is not evdev or virtio_tablet: {}", mode);}
+            }
+        }
+        env_bool("STATUS_SOCKET", &mut self.status_socket);
+        if let Ok(path) = std::env::var("WINDOWS_LAUNCHER_SOCKET") {self.control_socket = Some(path).filter(|path| !path.is_empty());}
+        env_u64("USER_CONNECT_TIMEOUT", "seconds", &mut self.user_connect_timeout_secs);
+        env_u64("LAUNCH_TIMEOUT_SECS", "seconds", &mut self.launch_timeout_secs);
+        env_u64("SHUTDOWN_GRACE_SECS", "seconds", &mut self.shutdown_grace_secs);
+        env_u64("DBUS_TIMEOUT_SECS", "seconds", &mut self.dbus_timeout_secs);
+        env_u64("USER_UNITS_SETTLE_MS", "milliseconds", &mut self.user_units_settle_ms);
+        env_u64("IDLE_SHUTDOWN_MINUTES", "minutes", &mut self.idle_shutdown_minutes);
+    }
+    /// returns the config with the named profile applied over it, or None if there is no such profile
+    pub fn with_profile(&self, name: &str) -> Option<Config>{
+        let profile = self.profiles.get(name)?.clone();
+        let mut config = self.clone();
+        if profile.lg_xml_path.is_some() {config.lg_xml_path = profile.lg_xml_path;}
+        if profile.spice_xml_path.is_some() {config.spice_xml_path = profile.spice_xml_path;}
+        if profile.spice_lite_xml_path.is_some() {config.spice_lite_xml_path = profile.spice_lite_xml_path;}
+        if profile.direct_xml_path.is_some() {config.direct_xml_path = profile.direct_xml_path;}
+        if let Some(ids) = profile.gpu_pci_ids {config.gpu_pci_ids = ids;}
+        if let Some(modules) = profile.nvidia_modules {config.nvidia_modules = modules;}
+        if let Some(ids) = profile.extra_pci_ids {config.extra_pci_ids = ids;}
+        if let Some(modules) = profile.extra_modules {config.extra_modules = modules;}
+        if let Some(cpus) = profile.host_cpu_mask {config.host_cpu_mask = cpus;}
+        if let Some(cpus) = profile.default_cpu_mask {config.default_cpu_mask = cpus;}
+        if let Some(name) = profile.vm_name {config.vm_name = name;}
+        if let Some(count) = profile.hugepages {config.hugepages = count;}
+        if let Some(size) = profile.hugepage_size_kb {config.hugepage_size_kb = size;}
+        if profile.monitor_restore_cmd.is_some() {config.monitor_restore_cmd = profile.monitor_restore_cmd;}
+        if let Some(mode) = profile.input_mode {config.input_mode = mode;}
+        if let Some(substitutions) = profile.xml_substitutions {config.xml_substitutions.extend(substitutions);}
+        Some(config)
+    }
+    /// returns this config with the fields of other that may change while a vm is running
+    /// the rest describe what the running launch changed on the host, and cleanup needs them as they were to undo it
+    pub fn with_live_changes(&self, other: &Config) -> Config{
+        let mut config = self.clone();
+        config.lg_xml_path = other.lg_xml_path.clone();
+        config.spice_xml_path = other.spice_xml_path.clone();
+        config.spice_lite_xml_path = other.spice_lite_xml_path.clone();
+        config.direct_xml_path = other.direct_xml_path.clone();
+        config.xml_substitutions = other.xml_substitutions.clone();
+        config.vm_poll_interval_secs = other.vm_poll_interval_secs;
+        config.vm_max_wait_secs = other.vm_max_wait_secs;
+        config.launch_timeout_secs = other.launch_timeout_secs;
+        config.shutdown_grace_secs = other.shutdown_grace_secs;
+        config.shutdown_retry = other.shutdown_retry;
+        config.log_dir = other.log_dir.clone();
+        config.log_keep = other.log_keep;
+        config.notifications = other.notifications;
+        config.user_connect_timeout_secs = other.user_connect_timeout_secs;
+        config.force_kill_gpu_procs = other.force_kill_gpu_procs;
+        config.gpu_process_names = other.gpu_process_names.clone();
+        config.user_units_settle_ms = other.user_units_settle_ms;
+        config.lg_viewer_cmd = other.lg_viewer_cmd.clone();
+        config.spice_viewer_cmd = other.spice_viewer_cmd.clone();
+        config.viewer_policy = other.viewer_policy.clone();
+        config.viewer_users = other.viewer_users.clone();
+        config.gpu_settle_secs = other.gpu_settle_secs;
+        config.dbus_timeout_secs = other.dbus_timeout_secs;
+        config.idle_shutdown_minutes = other.idle_shutdown_minutes;
+        config.mouse_name_unique = other.mouse_name_unique;
+        config.pre_launch_hook = other.pre_launch_hook.clone();
+        config.post_shutdown_hook = other.post_shutdown_hook.clone();
+        config.monitor_restore_cmd = other.monitor_restore_cmd.clone();
+        // the running profile is compared through its applied config, the others can change freely
+        config.profiles = other.profiles.clone();
+        config
+    }
+    /// returns the profile names, sorted
+    pub fn profile_names(&self) -> Vec<String>{
+        let mut names = self.profiles.keys().cloned().collect::<Vec<String>>();
+        names.sort();
+        names
+    }
+    /// returns how long a dbus call waits for its reply
+    pub fn dbus_timeout(&self) -> Duration{
+        Duration::from_secs(self.dbus_timeout_secs)
+    }
+    /// whether a launch of vm_type creates a virtual mouse
+    pub fn virtual_mouse(&self, vm_type: &VmType) -> bool{
+        self.use_virtual_mouse && self.input_mode == InputMode::Evdev && vm_type.has_mouse()
+    }
+    /// whether a launch of vm_type gets a virtio tablet added to its xml instead of a virtual mouse
+    pub fn virtio_tablet(&self, vm_type: &VmType) -> bool{
+        self.input_mode == InputMode::VirtioTablet && vm_type.has_mouse()
+    }
+    /// whether the user with uid and name is allowed a viewer by viewer_users
+    pub fn viewer_user_allowed(&self, uid: u32, name: &str) -> bool{
+        self.viewer_users.is_empty() || self.viewer_users.iter().any(|user| user == name || user.parse::<u32>() == Ok(uid))
+    }
+    /// returns the xml path for the vm type, if one was configured
+    pub fn xml_path(&self, vm_type: &VmType) -> Option<String>{
+        match vm_type {
+            VmType::LookingGlass => self.lg_xml_path.clone(),
+            VmType::Spice => self.spice_xml_path.clone(),
+            VmType::SpiceLite => self.spice_lite_xml_path.clone(),
+            VmType::Direct => self.direct_xml_path.clone()
+        }
+    }
+}
+
+/// sets field from the boolean env var name, if it is set. 1, true, yes and on are on, 0, false, no and off are off
+/// anything else is ignored with a warning
+fn env_bool(name: &str, field: &mut bool){
+    let Ok(value) = std::env::var(name) else {return;};
+    match value.to_lowercase().as_str() {
+        "1" | "true" | "yes" | "on" => {*field = true;},
+        "0" | "false" | "no" | "off" => {*field = false;},
+        _ => {log::warn!("Ignoring {}, it is not 1/true/yes/on or 0/false/no/off: {}", name, value);}
+    }
+}
+
+/// sets field from the env var name, if it is set. a value that isn't a number is ignored with a warning naming the unit
+fn env_u64(name: &str, unit: &str, field: &mut u64){
+    match std::env::var(name).map(|value| value.parse::<u64>()) {
+        Ok(Ok(value)) => {*field = value;},
+        Ok(Err(err)) => {log::warn!("Ignoring {}, it is not a number of {}: {}", name, unit, err);},
+        Err(_) => {}
+    }
+}
@@ -0,0 +1,40 @@
+/*
+    Reads iommu group membership from sysfs
+    Used to check that passthrough devices are isolated, and to show the groups for debugging
+*/
+
+use std::collections::BTreeMap;
+
+/// where the kernel lists iommu groups, empty or missing when the iommu is off
+pub const IOMMU_GROUPS_PATH: &str = "/sys/kernel/iommu_groups";
+
+/// Returns every iommu group number mapped to the sysfs addresses of its devices, e.g. 0000:01:00.0
+/// fails if the iommu group directory can't be read
+pub fn iommu_groups() -> std::io::Result<BTreeMap<u32, Vec<String>>>{
+    let mut groups = BTreeMap::new();
+    for group in std::fs::read_dir(IOMMU_GROUPS_PATH)?.flatten() {
+        let Ok(number) = group.file_name().to_string_lossy().parse::<u32>() else {continue;};
+        let mut devices = std::fs::read_dir(group.path().join("devices"))?.flatten()
+            .map(|device| device.file_name().to_string_lossy().to_string())
+            .collect::<Vec<String>>();
+        devices.sort();
+        groups.insert(number, devices);
+    }
+    Ok(groups)
+}
+
+/// Returns the addresses of the devices sharing an iommu group with the device at address, including itself
+pub fn group_members(address: &str) -> Option<Vec<String>>{
+    let members = std::fs::read_dir(format!("/sys/bus/pci/devices/{}/iommu_group/devices", address)).ok()?;
+    Some(members.flatten().map(|member| member.file_name().to_string_lossy().to_string()).collect())
+}
+
+/// Returns the pci class of the device at address, like 0x030000, if it can be read
+pub fn device_class(address: &str) -> Option<String>{
+    std::fs::read_to_string(format!("/sys/bus/pci/devices/{}/class", address)).ok().map(|class| class.trim().to_string())
+}
+
+/// Returns whether the device at address is a pci bridge, which can share a group with passed through devices
+pub fn is_bridge(address: &str) -> bool{
+    device_class(address).is_some_and(|class| class.starts_with("0x0604"))
+}
@@ -3,52 +3,101 @@
     It works with the server to execute the necessaty actions and work when requested.
 */
 
-use std::{env::VarError, error::Error, fmt::Display, fs::File, io::{Read, Write}, path::Path, process::Stdio, sync::{atomic::{AtomicBool, Ordering}, Arc, Mutex}, time::Duration};
-use dbus::{arg::Variant, nonblock::{Proxy, SyncConnection}};
-use crate::server::{ServerData, ServerError, UserConnectedFuture, VmLaunchFuture, VmPauseFuture, VmShutdownFuture};
+use std::{collections::HashMap, error::Error, fmt::Display, fs::{File, OpenOptions}, io::{Read, Write}, os::unix::fs::{FileTypeExt, MetadataExt, OpenOptionsExt, PermissionsExt}, path::{Path, PathBuf}, process::Stdio, sync::{atomic::{AtomicBool, Ordering}, Arc, Mutex}, task::Poll, time::{Duration, Instant}};
+use dbus::{arg::Variant, channel::Channel, message::MatchRule, nonblock::{stdintf::org_freedesktop_dbus::Properties, MsgMatch, Proxy, SyncConnection}};
+use futures::Future;
+use nix::{sys::signal::{kill, Signal}, unistd::Pid};
+use serde::{Deserialize, Serialize};
+use tokio::signal::unix::{signal, SignalKind};
+use crate::{command::{CommandRunner, SystemRunner}, config::Config, iommu::{group_members, iommu_groups, is_bridge}, logs::create_log_file, metrics, server::{hookable::Hookable, LockOrRecover, request_shutdown, SystemBus, ServerData, ServerError, UserConnectedFuture, VmLaunchFuture, VmLaunchedFuture, VmPauseFuture, VmShutdownFuture}};
 
-#[derive(Debug, Default, Clone)]
+#[derive(Debug, Default, Clone, PartialEq)]
 pub enum VmState{
     #[default] Inactive,
     Activating,
     Launched,
     ShuttingDown
 }
-impl ToString for VmState{
-    fn to_string(&self) -> String {
-        match self{
+impl Display for VmState{
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self{
             Self::Inactive => "Not Running",
             Self::Activating => "Starting up",
             Self::Launched => "Running",
             Self::ShuttingDown => "Stopping"
-        }.to_string()
+        })
+    }
+}
+impl VmState{
+    /// whether the vm can go from this state to next
+    /// a launch can be stopped while it is still activating, every other state only moves forward
+    pub fn can_transition_to(&self, next: &VmState) -> bool {
+        matches!((self, next),
+            (Self::Inactive, Self::Activating) |
+            (Self::Activating, Self::Launched) |
+            (Self::Activating, Self::ShuttingDown) |
+            (Self::Launched, Self::ShuttingDown) |
+            (Self::ShuttingDown, Self::Inactive))
     }
 }
-#[derive(Debug, Default, Clone)]
+#[derive(Debug, Default, Clone, PartialEq)]
 pub enum VmType{
     #[default] LookingGlass,
-    Spice
+    Spice,
+    /// spice without any host changes, no cpu pinning, governor, or virtual mouse
+    SpiceLite,
+    /// gpu passthrough to a physical monitor, with no viewer or virtual mouse
+    Direct
 }
-impl ToString for VmType{
-    fn to_string(&self) -> String {
-        match self {
+impl Display for VmType{
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
             Self::LookingGlass => "Looking Glass",
-            Self::Spice => "Spice"
-        }.to_string()
+            Self::Spice => "Spice",
+            Self::SpiceLite => "Spice Lite",
+            Self::Direct => "Direct"
+        })
+    }
+}
+impl VmType{
+    /// parses the stable short codes used on the socket and dbus, e.g. lg or spice-lite
+    pub fn from_code(code: &str) -> Option<VmType>{
+        match code {
+            "lg" => Some(VmType::LookingGlass),
+            "spice" => Some(VmType::Spice),
+            "spice-lite" => Some(VmType::SpiceLite),
+            "direct" => Some(VmType::Direct),
+            _ => None
+        }
+    }
+    /// whether the launch creates a virtual mouse from a host pointer device
+    pub fn has_mouse(&self) -> bool{
+        matches!(self, Self::LookingGlass | Self::Spice)
+    }
+    /// returns the stable short code parsed by from_code. Display is for people, this is for clients to match on
+    pub fn as_code(&self) -> &'static str{
+        match self {
+            Self::LookingGlass => "lg",
+            Self::Spice => "spice",
+            Self::SpiceLite => "spice-lite",
+            Self::Direct => "direct"
+        }
     }
 }
 
+/// libvirt's VIR_DOMAIN_EVENT_STOPPED lifecycle event code
+pub const LIBVIRT_DOMAIN_EVENT_STOPPED: i32 = 5;
+
 /// Represents all ways the session program can fail
 #[derive(Debug)]
 pub enum LauncherError{
     ServerError(ServerError),
-    FailedToLockData,
     FailedToSetCPUs(dbus::Error),
     FailedToReadCPUDir(std::io::Error),
     FailedToCreateMouse(dbus::Error),
-    FailedToGetXmlPath(VarError),
+    FailedToGetXmlPath(VmType),
     FailedToReadXmlPath(String, std::io::Error),
-    FailedToCreateXmlFile(std::io::Error),
+    FailedToCreateXmlFile(PathBuf, std::io::Error),
     FailedtoCreateLogFile(std::io::Error),
     FailedToLaunchVM(std::io::Error),
     FailedToStopDP(dbus::Error),
@@ -66,602 +115,2284 @@ pub enum LauncherError{
     FailedToRestartDP(dbus::Error),
     FailedToGetUsers(dbus::Error),
     FailedToGetVmState(std::io::Error),
-    FailedToGetEvents(std::io::Error)
+    InvalidCpuList(String),
+    InvalidCpuMask(String),
+    SystemdJobsTimedOut(Duration),
+    VmWaitTimeout(Duration),
+    LaunchTimeout(Duration),
+    XmlMissingPlaceholder(String),
+    XmlMissingDevices,
+    VirtualMouseServiceMissing,
+    FailedToListenForSignals(std::io::Error),
+    HostAlreadyModified,
+    IommuNotEnabled,
+    IommuGroupShared(String, Vec<String>),
+    GovernorTaskFailed(tokio::task::JoinError),
+    LgShmMissing(String),
+    FailedToSetupLgShm(String, std::io::Error),
+    /// every step of a cleanup that failed, in the order they failed
+    CleanupErrors(Vec<LauncherError>),
+    GpuStillInUse(Vec<String>),
+    UnitActionFailed(String, String, dbus::Error),
+    ServiceNotStarted(String, String),
+    VmCreateFailed(String),
+    FailedToReserveHugepages(String, std::io::Error),
+    HugepagesUnavailable(u64, u64),
+    IsolationTaskFailed(tokio::task::JoinError),
+    FailedToRunHook(String, std::io::Error),
+    HookFailed(String, std::process::ExitStatus),
+    FailedToRestoreMonitors(String, std::io::Error),
+    MonitorRestoreFailed(String, std::process::ExitStatus)
 }
 impl Display for LauncherError{
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         let _ = f.write_str(&match self {
             Self::ServerError(err) => err.to_string(),
-            Self::FailedToLockData => format!("Could not lock ServerData"),
             Self::FailedToSetCPUs(err) => format!("Could not set AllowedCPUs with err: {}", *err),
             Self::FailedToReadCPUDir(err) => format!("Could not read the cpu directory: {}", *err),
             Self::FailedToCreateMouse(err) => format!("Could not create a virtual mouse: {}", *err),
-            Self::FailedToGetXmlPath(err) => format!("Could not get the xml path from the environment variables: {}", *err),
+            Self::FailedToGetXmlPath(vm_type) => format!("No xml path is configured for {}, set it in the config file or environment variables", *vm_type),
             Self::FailedToReadXmlPath(path, err) => format!("Could not read the xml path: {}, with err: {}", *path, *err),
-            Self::FailedToCreateXmlFile(err) => format!("Failed to create the xml file at /tmp/windows.xml: {}", *err),
+            Self::FailedToCreateXmlFile(path, err) => format!("Failed to create the xml file at {}: {}", path.display(), *err),
             Self::FailedtoCreateLogFile(err) => format!("Failed to create vm log file: {}", *err),
             Self::FailedToLaunchVM(err) => format!("Failed to launch the vm with virsh: {}", *err),
             Self::FailedToStopDP(err) => format!("Could not stop the display manager: {}", *err),
-            Self::ProcessesDidNotExit => format!("Waited 2 seconds, but processes that use the gpu did not close after stopping the display manager and pipewire"),
+            Self::ProcessesDidNotExit => "Waited 2 seconds, but processes that use the gpu did not close after stopping the display manager and user units".to_string(),
             Self::FailedToGetProcesses(err) => format!("Could not get root processes from ps: {}", *err),
             Self::FailedToUnloadKernelModule(name, err) => format!("Failed to unload kernel module {}, with err: {}", *name, *err),
             Self::ModprobeRemoveReturnedErr(name, stderr) => format!("Modprobe returned err while unloading {}, with stderr: {}", *name, *stderr),
             Self::FailedToDisconnectGPU(pci, err) => format!("Failed to disconnect pci {}, with err: {}", *pci, *err),
             Self::FailedToLoadKernelModule(name, err) => format!("Failed to load kernel module {}, with err: {}", *name, *err),
-            Self::FailedToStartDP(err) => format!("Failed to start display-manager.service with err: {}", *err),
+            Self::FailedToStartDP(err) => format!("Failed to start the display service with err: {}", *err),
             Self::FailedToShutdownVm(err) => format!("Failed to shutdown the vm with virsh: {}", *err),
             Self::FailedToDestroyVm(err) => format!("Failed to destroy the vm with virsh: {}", *err),
             Self::FailedToStopVirtualMouse(err) => format!("Failed to stop the virtual mouse: {}", *err),
             Self::FailedToConnectGPU(pci, err) => format!("Failed to reconnect gpu: {}, with err: {}", *pci, *err),
-            Self::FailedToRestartDP(err) => format!("Failed to restart display-manager.service: {}", *err),
+            Self::FailedToRestartDP(err) => format!("Failed to restart the display service: {}", *err),
             Self::FailedToGetUsers(err) => format!("Failed to get users from login1: {}", *err),
             Self::FailedToGetVmState(err) => format!("failed to get vm state from virsh: {}", *err),
-            Self::FailedToGetEvents(err) => format!("Failed to get events from virsh: {}", *err)
+            Self::InvalidCpuList(list) => format!("Could not parse the cpu list: {}, expected a list like 0-3,8", *list),
+            Self::InvalidCpuMask(reason) => format!("Invalid cpu masks, nothing was changed: {}", *reason),
+            Self::SystemdJobsTimedOut(timeout) => format!("Systemd jobs did not finish within {:?}", *timeout),
+            Self::VmWaitTimeout(timeout) => format!("The vm did not close within {:?}", *timeout),
+            Self::LaunchTimeout(timeout) => format!("The vm was not running within {:?} of the launch request, the launch was aborted", *timeout),
+            Self::XmlMissingPlaceholder(token) => format!("The vm xml does not contain the placeholder {}, the virtual mouse would not be passed through", *token),
+            Self::XmlMissingDevices => "The vm xml has no <devices> element to add the virtio tablet to".to_string(),
+            Self::VirtualMouseServiceMissing => "Nothing owns org.cws.VirtualMouse on the system bus. Install TrackpadEvdevConverter and start its systemd service".to_string(),
+            Self::FailedToListenForSignals(err) => format!("Could not listen for termination signals: {}", *err),
+            Self::HostAlreadyModified => format!("{} shows the host is already set up for a vm, attach or recover it first", STATE_FILE_PATH),
+            Self::IommuNotEnabled => "/sys/kernel/iommu_groups is empty. Boot with intel_iommu=on or amd_iommu=on, and enable VT-d or AMD-Vi in the firmware".to_string(),
+            Self::IommuGroupShared(pci, others) => format!("{} shares its iommu group with devices that are not passed through: {}", *pci, others.join(", ")),
+            Self::GovernorTaskFailed(err) => format!("The blocking task changing the cpu governor failed: {}", *err),
+            Self::LgShmMissing(path) => format!("{} does not exist, load the kvmfr module or set lg_shm_path to a file under /dev/shm", *path),
+            Self::FailedToSetupLgShm(path, err) => format!("Could not give the launching user {}: {}", *path, *err),
+            Self::CleanupErrors(errors) => format!("{} cleanup steps failed, the host may not be fully restored:{}", errors.len(),
+                errors.iter().map(|err| format!("\n  - {}", err)).collect::<String>()),
+            Self::GpuStillInUse(procs) => format!("Processes still hold the gpu, close them or set force_kill_gpu_procs: {}", procs.join(", ")),
+            Self::UnitActionFailed(action, unit, err) => format!("Could not {} {}: {}", *action, *unit, *err),
+            Self::ServiceNotStarted(service, state) => format!("{} is {} after starting it again, see journalctl -u {}", *service, *state, *service),
+            Self::VmCreateFailed(stderr) => format!("virsh could not start the vm: {}", *stderr),
+            Self::FailedToReserveHugepages(path, err) => format!("Could not reserve hugepages through {}: {}", *path, *err),
+            Self::HugepagesUnavailable(wanted, got) => format!("Only {} of {} hugepages could be reserved, memory is too fragmented. reserve them at boot with the hugepages kernel parameter", *got, *wanted),
+            Self::IsolationTaskFailed(err) => format!("The blocking task moving irqs off the vm cpus failed: {}", *err),
+            Self::FailedToRunHook(hook, err) => format!("Could not run the hook {}: {}", *hook, *err),
+            Self::HookFailed(hook, status) => format!("The hook {} failed with {}", *hook, *status),
+            Self::FailedToRestoreMonitors(user, err) => format!("Could not run the monitor restore command for {}: {}", *user, *err),
+            Self::MonitorRestoreFailed(user, status) => format!("The monitor restore command failed for {} with {}", *user, *status)
         });
         Ok(())
     }
 }
 impl Error for LauncherError{}
+impl LauncherError {
+    /// turns the errors collected by a cleanup into a single result
+    pub fn from_cleanup(errors: Vec<LauncherError>) -> Result<(), LauncherError> {
+        if errors.is_empty() {Ok(())} else {Err(Self::CleanupErrors(errors))}
+    }
+}
+
+/// Logs a failed cleanup step as it happens, and keeps it to be reported at the end
+fn cleanup_failed(errors: &mut Vec<LauncherError>, err: LauncherError) {
+    log::error!("Cleanup step failed: {}", err);
+    errors.push(err);
+}
+
+/// where the reversible parts of SystemState are saved, so a restarted server can undo them
+pub const STATE_FILE_PATH: &str = "/run/windows-vm-launcher/state.json";
+
+/// Serializable copy of SystemState, written to STATE_FILE_PATH after each change
+#[derive(Serialize, Deserialize, Default, Debug)]
+#[serde(default)]
+pub struct SavedSystemState{
+    cpus_limited: (bool, bool, bool),
+    governor_originals: Vec<(String, String)>,
+    virtual_mouse_create: bool,
+    vm_launched: bool,
+    vm_destroyed: bool,
+    dp_stopped: bool,
+    pw_stopped: bool,
+    nvidia_unloaded: Vec<String>,
+    gpu_dettached: Vec<String>,
+    extra_unloaded: Vec<String>,
+    extra_detached: Vec<String>,
+    services_stopped: Vec<String>,
+    vfio_loaded: bool,
+    vm_name: String,
+    vm_type: String,
+    mouse_name: String,
+    shm_created: Option<String>,
+    shm_original: Option<(String, u32, u32)>,
+    hugepages_original: Option<(String, u64)>,
+    irq_originals: Vec<(String, String)>,
+    machine_isolated: bool,
+    gpu_drivers: Vec<(String, String)>
+}
 
 /// Represents the state of the system, and all changes we have made
 #[derive(Default, Debug)]
 pub struct SystemState{
     cpus_limited: (AtomicBool, AtomicBool, AtomicBool),
-    performance_governor: AtomicBool,
+    /// scaling_governor files we have written the vm governor to, with the governor each had before
+    governor_originals: Mutex<Vec<(String, String)>>,
     virtual_mouse_create: AtomicBool,
     vm_launched: AtomicBool,
+    /// whether the vm was already destroyed, so cleanup doesn't try again
+    vm_destroyed: AtomicBool,
     dp_stopped: AtomicBool,
     pw_stopped: AtomicBool,
-    nvidia_unloaded: (AtomicBool, AtomicBool, AtomicBool, AtomicBool),
-    gpu_dettached: (AtomicBool, AtomicBool),
-    vfio_loaded: AtomicBool
+    /// nvidia modules unloaded, in the order they were unloaded
+    nvidia_unloaded: Mutex<Vec<String>>,
+    /// pci devices detached, in the order they were detached
+    gpu_dettached: Mutex<Vec<String>>,
+    /// modules unloaded for the extra passthrough devices, in the order they were unloaded
+    extra_unloaded: Mutex<Vec<String>>,
+    /// extra passthrough devices detached, in the order they were detached
+    extra_detached: Mutex<Vec<String>>,
+    /// gpu devices moved to vfio-pci on their own through sysfs, with the driver each was bound to, empty for none
+    gpu_drivers: Mutex<Vec<(String, String)>>,
+    /// system services stopped because they held the gpu in igpu_host mode
+    services_stopped: Mutex<Vec<String>>,
+    vfio_loaded: AtomicBool,
+    /// name the virtual mouse was created with, so cleanup destroys that exact mouse
+    mouse_name: Mutex<String>,
+    /// looking glass shared memory file we created, removed afterwards
+    shm_created: Mutex<Option<String>>,
+    /// path, owner uid, and mode of an existing shared memory device before we took it over
+    shm_original: Mutex<Option<(String, u32, u32)>>,
+    /// nr_hugepages file we changed, and the count it had before
+    hugepages_original: Mutex<Option<(String, u64)>>,
+    /// smp_affinity_list files we pointed at the host cpus, with the cpu list each had before
+    irq_originals: Mutex<Vec<(String, String)>>,
+    /// whether machine.slice was limited to the vm cpus and made an isolated partition
+    machine_isolated: AtomicBool,
+    /// libvirt domain name of the vm being launched
+    vm_name: Mutex<String>,
+    /// short name of the vm type being launched, empty once it is cleaned up
+    vm_type: Mutex<String>,
+    /// keeps the state out of STATE_FILE_PATH, for tests
+    memory_only: bool
 }
 impl SystemState {
     pub fn revert(&self) {
         self.cpus_limited.0.store(false, Ordering::Relaxed);
         self.cpus_limited.1.store(false, Ordering::Relaxed);
         self.cpus_limited.2.store(false, Ordering::Relaxed);
-        self.performance_governor.store(false, Ordering::Relaxed);
         self.virtual_mouse_create.store(false, Ordering::Relaxed);
         self.vm_launched.store(false, Ordering::Relaxed);
+        self.vm_destroyed.store(false, Ordering::Relaxed);
         self.dp_stopped.store(false, Ordering::Relaxed);
         self.pw_stopped.store(false, Ordering::Relaxed);
-        self.nvidia_unloaded.0.store(false, Ordering::Relaxed);
-        self.nvidia_unloaded.1.store(false, Ordering::Relaxed);
-        self.nvidia_unloaded.2.store(false, Ordering::Relaxed);
-        self.nvidia_unloaded.3.store(false, Ordering::Relaxed);
-        self.gpu_dettached.0.store(false, Ordering::Relaxed);
-        self.gpu_dettached.1.store(false, Ordering::Relaxed);
+        self.governor_originals.lock_or_recover().clear();
+        self.nvidia_unloaded.lock_or_recover().clear();
+        self.gpu_dettached.lock_or_recover().clear();
+        self.extra_unloaded.lock_or_recover().clear();
+        self.extra_detached.lock_or_recover().clear();
+        self.services_stopped.lock_or_recover().clear();
         self.vfio_loaded.store(false, Ordering::Relaxed);
+        self.mouse_name.lock_or_recover().clear();
+        self.vm_type.lock_or_recover().clear();
+        *self.shm_created.lock_or_recover() = None;
+        *self.shm_original.lock_or_recover() = None;
+        *self.hugepages_original.lock_or_recover() = None;
+        self.irq_originals.lock_or_recover().clear();
+        self.machine_isolated.store(false, Ordering::Relaxed);
+        self.gpu_drivers.lock_or_recover().clear();
+        // nothing is left to undo
+        if !self.memory_only && Path::new(STATE_FILE_PATH).exists() {
+            if let Err(err) = std::fs::remove_file(STATE_FILE_PATH) {log::warn!("Could not remove the state file: {}", err);}
+        }
+    }
+    /// writes the current state to STATE_FILE_PATH. failures are logged, since the launch can go on without it
+    pub fn save(&self) {
+        if self.memory_only {return;}
+        let saved = SavedSystemState{
+            cpus_limited: (self.cpus_limited.0.load(Ordering::Relaxed), self.cpus_limited.1.load(Ordering::Relaxed), self.cpus_limited.2.load(Ordering::Relaxed)),
+            governor_originals: self.governor_originals(),
+            virtual_mouse_create: self.virtual_mouse_create.load(Ordering::Relaxed),
+            vm_launched: self.vm_launched.load(Ordering::Relaxed),
+            vm_destroyed: self.vm_destroyed.load(Ordering::Relaxed),
+            dp_stopped: self.dp_stopped.load(Ordering::Relaxed),
+            pw_stopped: self.pw_stopped.load(Ordering::Relaxed),
+            nvidia_unloaded: SystemState::tracked(&self.nvidia_unloaded),
+            gpu_dettached: SystemState::tracked(&self.gpu_dettached),
+            extra_unloaded: SystemState::tracked(&self.extra_unloaded),
+            extra_detached: SystemState::tracked(&self.extra_detached),
+            services_stopped: SystemState::tracked(&self.services_stopped),
+            vfio_loaded: self.vfio_loaded.load(Ordering::Relaxed),
+            vm_name: self.vm_name(),
+            vm_type: self.vm_type(),
+            mouse_name: self.mouse_name(),
+            shm_created: self.shm_created.lock_or_recover().clone(),
+            shm_original: self.shm_original.lock_or_recover().clone(),
+            hugepages_original: self.hugepages_original.lock_or_recover().clone(),
+            irq_originals: self.irq_originals.lock_or_recover().clone(),
+            machine_isolated: self.machine_isolated.load(Ordering::Relaxed),
+            gpu_drivers: self.gpu_drivers.lock_or_recover().clone()
+        };
+        let result = Path::new(STATE_FILE_PATH).parent().map_or(Ok(()), std::fs::create_dir_all)
+            .and_then(|_| serde_json::to_vec(&saved).map_err(std::io::Error::from))
+            .and_then(|json| std::fs::write(STATE_FILE_PATH, json));
+        if let Err(err) = result {log::warn!("Could not save the system state to {}: {}", STATE_FILE_PATH, err);}
+    }
+    /// loads a state file left behind by a previous server into this state. returns whether one was found
+    pub fn restore_saved(&self) -> bool {
+        if self.memory_only {return false;}
+        let saved = match std::fs::read(STATE_FILE_PATH) {
+            Ok(json) => match serde_json::from_slice::<SavedSystemState>(&json) {
+                Ok(saved) => saved,
+                Err(err) => {log::warn!("Ignoring unreadable state file {}: {}", STATE_FILE_PATH, err); return false;}
+            },
+            Err(_) => {return false;}
+        };
+        self.cpus_limited.0.store(saved.cpus_limited.0, Ordering::Relaxed);
+        self.cpus_limited.1.store(saved.cpus_limited.1, Ordering::Relaxed);
+        self.cpus_limited.2.store(saved.cpus_limited.2, Ordering::Relaxed);
+        *self.governor_originals.lock_or_recover() = saved.governor_originals;
+        self.virtual_mouse_create.store(saved.virtual_mouse_create, Ordering::Relaxed);
+        self.vm_launched.store(saved.vm_launched, Ordering::Relaxed);
+        self.vm_destroyed.store(saved.vm_destroyed, Ordering::Relaxed);
+        self.dp_stopped.store(saved.dp_stopped, Ordering::Relaxed);
+        self.pw_stopped.store(saved.pw_stopped, Ordering::Relaxed);
+        *self.nvidia_unloaded.lock_or_recover() = saved.nvidia_unloaded;
+        *self.gpu_dettached.lock_or_recover() = saved.gpu_dettached;
+        *self.extra_unloaded.lock_or_recover() = saved.extra_unloaded;
+        *self.extra_detached.lock_or_recover() = saved.extra_detached;
+        *self.services_stopped.lock_or_recover() = saved.services_stopped;
+        self.vfio_loaded.store(saved.vfio_loaded, Ordering::Relaxed);
+        self.set_vm_name(saved.vm_name);
+        *self.vm_type.lock_or_recover() = saved.vm_type;
+        *self.mouse_name.lock_or_recover() = saved.mouse_name;
+        *self.shm_created.lock_or_recover() = saved.shm_created;
+        *self.shm_original.lock_or_recover() = saved.shm_original;
+        *self.hugepages_original.lock_or_recover() = saved.hugepages_original;
+        *self.irq_originals.lock_or_recover() = saved.irq_originals;
+        self.machine_isolated.store(saved.machine_isolated, Ordering::Relaxed);
+        *self.gpu_drivers.lock_or_recover() = saved.gpu_drivers;
+        true
+    }
+    /// whether any gpu device is currently detached from the host
+    pub fn gpu_detached(&self) -> bool {
+        !SystemState::tracked(&self.gpu_dettached).is_empty() || !self.gpu_drivers.lock_or_recover().is_empty()
+    }
+    /// records that the vm was destroyed outside of cleanup
+    pub fn set_vm_destroyed(&self) {
+        self.vm_destroyed.store(true, Ordering::Relaxed);
+        self.save();
+    }
+    /// whether the display manager is currently stopped by us
+    pub fn dp_stopped(&self) -> bool {
+        self.dp_stopped.load(Ordering::Relaxed)
+    }
+    /// whether vfio-pci is currently loaded by us
+    pub fn vfio_loaded(&self) -> bool {
+        self.vfio_loaded.load(Ordering::Relaxed)
+    }
+    /// returns the libvirt domain name of the vm being launched
+    pub fn vm_name(&self) -> String {
+        self.vm_name.lock_or_recover().clone()
+    }
+    pub fn set_vm_name(&self, name: String) {
+        *self.vm_name.lock_or_recover() = name;
+    }
+    /// returns the short name of the vm type being launched, e.g. lg
+    pub fn vm_type(&self) -> String {
+        self.vm_type.lock_or_recover().clone()
+    }
+    /// returns the name of the virtual mouse we created, empty if none was
+    pub fn mouse_name(&self) -> String {
+        self.mouse_name.lock_or_recover().clone()
+    }
+    /// returns the governor files we changed, along with the governor to restore to each
+    pub fn governor_originals(&self) -> Vec<(String, String)> {
+        self.governor_originals.lock_or_recover().clone()
+    }
+    /// adds an item to one of the tracked lists
+    fn track(list: &Mutex<Vec<String>>, item: &str) {
+        list.lock_or_recover().push(item.to_string());
+    }
+    /// returns a copy of one of the tracked lists
+    fn tracked(list: &Mutex<Vec<String>>) -> Vec<String> {
+        list.lock_or_recover().clone()
     }
 }
 
 /// Asynchronous loop which handles all system setup. should never return
-pub async fn launcher(data: Arc<Mutex<ServerData>>, conn: Arc<SyncConnection>) -> Result<(), LauncherError>{
-    let system_state = Arc::new(SystemState::default());
+/// bus is asked for its connection at each step, so a reconnected bus is picked up
+pub async fn launcher(data: Arc<Mutex<ServerData>>, bus: SystemBus) -> Result<(), LauncherError>{
+    let system_state = data.lock_or_recover().system_state.clone();
+    // a previous server may have died mid launch, leaving the host half setup
+    if system_state.restore_saved() {
+        log::warn!("Found state left by a previous server, cleaning up");
+        let config = data.lock_or_recover().launch_config();
+        if let Err(err) = LauncherError::from_cleanup(timed_cleanup(&data, system_state.clone(), bus.conn(), &config).await) {log::error!("Recovery {}", err);}
+    }
+    let mut terminate = signal(SignalKind::terminate()).map_err(LauncherError::FailedToListenForSignals)?;
+    let mut interrupt = signal(SignalKind::interrupt()).map_err(LauncherError::FailedToListenForSignals)?;
     let data_copy = data.clone();
     tokio::spawn(async move {
         let mut current_pause = false;
         loop{
-            current_pause = match (VmPauseFuture{cur_pause_state: current_pause.clone(), data: data_copy.clone()}).await {
+            current_pause = match (VmPauseFuture{cur_pause_state: current_pause, data: data_copy.clone()}).await {
                 Err(err) => {return err;},
                 Ok(pause) => pause
             };
+            let (vm_name, uri) = {
+                let guard = data_copy.lock_or_recover();
+                (guard.vm_name.clone(), guard.config.libvirt_uri.clone())
+            };
             if current_pause {
-                println!("Pausing VM");
-                let _ = tokio::process::Command::new("virsh").args(["-cqemu:///system", "suspend", "windows"])
+                log::info!("Pausing VM");
+                let _ = virsh(&uri).args(["suspend", &vm_name])
                     .stderr(Stdio::null()).stdout(Stdio::null()).output().await;
             }else {
-                println!("Resuming VM");
-                let _ = tokio::process::Command::new("virsh").args(["-cqemu:///system", "resume", "windows"])
+                log::info!("Resuming VM");
+                let _ = virsh(&uri).args(["resume", &vm_name])
                     .stderr(Stdio::null()).stdout(Stdio::null()).output().await;
             }
         }
     });
+    tokio::spawn(idle_shutdown(data.clone(), bus.clone()));
     loop{
         // wait for vm to be requested
-        println!("Waiting for vm launch to be requested...");
-        VmLaunchFuture{data: data.clone()}.await.map_err(|err| LauncherError::ServerError(err))?;
+        log::info!("Waiting for vm launch to be requested...");
+        tokio::select! {
+            result = VmLaunchFuture{data: data.clone()} => {result.map_err(LauncherError::ServerError)?;},
+            _ = terminated(&mut terminate, &mut interrupt) => {
+                log::info!("Stopping server");
+                return Ok(());
+            }
+        }
         // do work
-        println!("Spawning VM Launch");
-        let handle = tokio::spawn(launch_vm(data.clone(), system_state.clone(), conn.clone()));
+        log::info!("Spawning VM Launch");
+        let launch_timeout = Some(data.lock_or_recover().launch_config().launch_timeout_secs)
+            .filter(|secs| *secs > 0).map(Duration::from_secs);
+        let mut handle = tokio::spawn(launch_vm(data.clone(), system_state.clone(), bus.conn()));
         // wait for work to finish, or shutdown signal
         tokio::select! {
-            result = handle => {
-                println!("VM Launch Finished");
+            result = &mut handle => {
+                log::info!("VM Launch Finished");
                 if let Ok(Err(err)) = result {  
-                    let _ = cleanup(system_state, conn).await;
+                    let config = data.lock_or_recover().launch_config();
+                    // the launch error is what the user needs, cleanup failures were logged as they happened
+                    if let Err(cleanup_err) = LauncherError::from_cleanup(timed_cleanup(&data, system_state, bus.conn(), &config).await) {log::error!("{}", cleanup_err);}
                     return Err(err);
                 }
-                if let Ok(mut guard) = data.lock() {guard.vm_state.set(VmState::ShuttingDown);}
+                // a shutdown request can land as the launch finishes
+                let mut guard = data.lock_or_recover();
+                if *guard.vm_state.get() != VmState::ShuttingDown {guard.vm_state.try_set(VmState::ShuttingDown);}
             },
             result = VmShutdownFuture{data: data.clone()} => {
-                println!("Shutdown Interrupted Vm Launch");
-                result.map_err(|err| LauncherError::ServerError(err))?;
+                log::info!("Shutdown Interrupted Vm Launch");
+                // stop the launch where it is, cleanup undoes whatever it got through
+                handle.abort();
+                result.map_err(LauncherError::ServerError)?;
+            },
+            timeout = launch_deadline(data.clone(), launch_timeout) => {
+                // a hung step would otherwise hold the gpu until someone asks for a shutdown
+                log::error!("The launch did not finish within {:?}, aborting", timeout);
+                handle.abort();
+                let config = data.lock_or_recover().launch_config();
+                if let Err(cleanup_err) = LauncherError::from_cleanup(timed_cleanup(&data, system_state, bus.conn(), &config).await) {log::error!("{}", cleanup_err);}
+                return Err(LauncherError::LaunchTimeout(timeout));
+            },
+            _ = terminated(&mut terminate, &mut interrupt) => {
+                // the host has to be put back before the process exits
+                log::info!("Server stopped during a launch, cleaning up");
+                handle.abort();
+                let config = data.lock_or_recover().launch_config();
+                return LauncherError::from_cleanup(timed_cleanup(&data, system_state, bus.conn(), &config).await);
             }
         }
         // cleanup
-        println!("Cleaning up...");
-        let mut errors = cleanup(system_state.clone(), conn.clone()).await;
-        if errors.len() > 0 {return Err(errors.remove(0));};
-        let mut guard = match data.lock() {Ok(guard) => guard, _ => {return Err(LauncherError::FailedToLockData);}};
+        log::info!("Cleaning up...");
+        let config = data.lock_or_recover().launch_config();
+        LauncherError::from_cleanup(timed_cleanup(&data, system_state.clone(), bus.conn(), &config).await)?;
+        let mut guard = data.lock_or_recover();
         guard.user_connected.set(false);
-        guard.vm_state.set(VmState::Inactive);
+        guard.vm_state.try_set(VmState::Inactive);
     }
 }
 
+/// resolves with the timeout if the vm isn't running within it. never resolves once the vm is running, or without a timeout
+async fn launch_deadline(data: Arc<Mutex<ServerData>>, timeout: Option<Duration>) -> Duration{
+    let Some(timeout) = timeout else {return std::future::pending().await;};
+    match tokio::time::timeout(timeout, VmLaunchedFuture{data}).await {
+        Err(_) => timeout,
+        Ok(_) => std::future::pending().await
+    }
+}
+
+/// how often the idle shutdown task looks for display sessions
+pub const IDLE_CHECK_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Shuts the running vm down once no graphical login session has existed for idle_shutdown_minutes. never returns
+/// the minutes are read on every check, so a reloaded config applies. direct vms are left alone, since they have no host display by design
+pub async fn idle_shutdown(data: Arc<Mutex<ServerData>>, bus: SystemBus){
+    let mut last_display = tokio::time::Instant::now();
+    loop {
+        tokio::time::sleep(IDLE_CHECK_INTERVAL).await;
+        let (state, vm_type, dbus_timeout, idle_minutes) = {
+            let guard = data.lock_or_recover();
+            (guard.vm_state.get().clone(), guard.vm_type.clone(), guard.config.dbus_timeout(), guard.config.idle_shutdown_minutes)
+        };
+        let idle_after = Duration::from_secs(idle_minutes * 60);
+        if idle_minutes == 0 || state != VmState::Launched || vm_type == VmType::Direct || has_display_session(bus.conn(), dbus_timeout).await {
+            last_display = tokio::time::Instant::now();
+            continue;
+        }
+        if last_display.elapsed() >= idle_after {
+            log::info!("No display session for {} minutes, shutting down the vm", idle_after.as_secs() / 60);
+            if let Err(err) = request_shutdown(data.clone()).await {log::error!("Idle shutdown failed: {}", err);}
+            last_display = tokio::time::Instant::now();
+        }
+    }
+}
+
+/// Returns whether any login session is an x11 or wayland session. errors count as a display, so the vm is never shut down by mistake
+pub async fn has_display_session(conn: Arc<SyncConnection>, dbus_timeout: Duration) -> bool{
+    let login_proxy = Proxy::new("org.freedesktop.login1", "/org/freedesktop/login1", dbus_timeout, conn.clone());
+    let sessions = match login_proxy.method_call::<(Vec<(String, u32, String, String, dbus::Path)>,), _, _, _>("org.freedesktop.login1.Manager", "ListSessions", ()).await {
+        Ok((sessions,)) => sessions,
+        Err(err) => {log::warn!("Could not list login sessions for the idle check: {}", err); return true;}
+    };
+    for (_, _, _, _, path) in sessions {
+        let session_proxy = Proxy::new("org.freedesktop.login1", path, dbus_timeout, conn.clone());
+        if let Ok(session_type) = session_proxy.get::<String>("org.freedesktop.login1.Session", "Type").await {
+            if session_type == "x11" || session_type == "wayland" {return true;}
+        }
+    }
+    false
+}
+
+/// resolves when the process is asked to stop with SIGTERM or SIGINT
+pub async fn terminated(terminate: &mut tokio::signal::unix::Signal, interrupt: &mut tokio::signal::unix::Signal){
+    tokio::select! {
+        _ = terminate.recv() => {},
+        _ = interrupt.recv() => {}
+    }
+}
+
+/// Runs one launch phase, recording how long it took and whether it failed in the server metrics
+async fn timed<T>(data: &Arc<Mutex<ServerData>>, phase: &str, work: impl Future<Output = Result<T, LauncherError>>) -> Result<T, LauncherError>{
+    let started = Instant::now();
+    let result = work.await;
+    data.lock_or_recover().metrics.record(phase, started, result.is_ok());
+    result
+}
+
+/// cleanup, recorded in the server metrics like the launch phases. any failed step counts as a failed cleanup
+async fn timed_cleanup(data: &Arc<Mutex<ServerData>>, state: Arc<SystemState>, conn: Arc<SyncConnection>, config: &Config) -> Vec<LauncherError>{
+    let started = Instant::now();
+    let errors = cleanup(state, conn, config).await;
+    data.lock_or_recover().metrics.record(metrics::CLEANUP, started, errors.is_empty());
+    errors
+}
+
 /// asynchronous function, responsible for doing essentially all of the vm launching
 pub async fn launch_vm(data: Arc<Mutex<ServerData>>, state: Arc<SystemState>, conn: Arc<SyncConnection>) -> Result<(), LauncherError>{
-    let vm_type = data.lock().map_err(|_| LauncherError::FailedToLockData)?.vm_type.clone();
+    let (vm_type, vm_name, config) = {
+        let guard = data.lock_or_recover();
+        (guard.vm_type.clone(), guard.vm_name.clone(), guard.launch_config())
+    };
+    state.set_vm_name(vm_name.clone());
+    *state.vm_type.lock_or_recover() = vm_type.as_code().to_string();
+    state.save();
+    // a bad xml fails here, before the desktop is stopped and the gpu is detached
+    let xml = read_xml_template(&vm_type, &config)?;
+    // the masks are checked before anything is touched, since a bad host mask can freeze the host
+    if vm_type != VmType::SpiceLite {check_cpu_masks(&config)?;}
+    if let Some(hook) = config.pre_launch_hook.as_ref() {
+        log::info!("Running the pre launch hook");
+        run_hook(hook, vm_type.as_code(), &vm_name).await?;
+    }
     match vm_type {
         VmType::LookingGlass => {
-            println!("Disconnecting GPU");
-            dc_gpu_lg(state.clone(), conn.clone()).await?;
-            println!("Waiting for user connection");
-            UserConnectedFuture{data: data.clone()}.await.map_err(|err| LauncherError::ServerError(err))?;
+            log::info!("Disconnecting GPU");
+            timed(&data, metrics::GPU_DETACH, dc_gpu_lg(state.clone(), conn.clone(), &config)).await?;
+            log::info!("Waiting for user connection");
+            UserConnectedFuture{data: data.clone()}.await.map_err(LauncherError::ServerError)?;
+        },
+        VmType::Direct => {
+            // the guest drives its own monitor, so there is no host login to wait for
+            log::info!("Disconnecting GPU");
+            timed(&data, metrics::GPU_DETACH, dc_gpu_lg(state.clone(), conn.clone(), &config)).await?;
         },
-        VmType::Spice => {
-            println!("Waiting for user connection");
-            UserConnectedFuture{data: data.clone()}.await.map_err(|err| LauncherError::ServerError(err))?;
+        VmType::Spice | VmType::SpiceLite => {
+            log::info!("Waiting for user connection");
+            UserConnectedFuture{data: data.clone()}.await.map_err(LauncherError::ServerError)?;
         }
     }
     // setup the pc
-    println!("Setting up PC...");
-    let mouse_path = data.lock().map_err(|_|LauncherError::FailedToLockData)?.mouse_path.clone();
-    setup_pc(state.clone(), conn.clone(), mouse_path, vm_type.clone()).await?;
+    log::info!("Setting up PC...");
+    let (mouse_path, user) = {
+        let guard = data.lock_or_recover();
+        (guard.mouse_path.clone(), guard.connected_uid)
+    };
+    let xml_path = timed(&data, metrics::SETUP, setup_pc(state.clone(), conn.clone(), mouse_path, vm_type.clone(), user, xml, &config)).await?;
     // launch vm
-    println!("Starting VM");
-    start_vm(state.clone()).await?;
+    log::info!("Starting VM");
+    timed(&data, metrics::VM_BOOT, start_vm(state.clone(), &config, &xml_path)).await?;
     // inform users that state has changed
-    if let Ok(mut guard) = data.lock() {guard.vm_state.set(VmState::Launched);} else {return Err(LauncherError::FailedToLockData);}
+    // a shutdown requested while the vm started is left in place, and handled once the launch returns
+    data.lock_or_recover().vm_state.try_set(VmState::Launched);
     // wait for vm to shutdown
-    println!("Waiting for vm to close");
-    wait_on_vm(state.clone()).await?;
+    log::info!("Waiting for vm to close");
+    wait_on_vm(state.clone(), conn.clone(), &config).await?;
     Ok(())
 }
 
 /// asynchronous function responsible for reverting changes done in launch_vm. any errors are stored and returned at the end, will attempt to revert all changes regardless of errors
-pub async fn cleanup(state: Arc<SystemState>, conn: Arc<SyncConnection>) -> Vec<LauncherError>{
+pub async fn cleanup(state: Arc<SystemState>, conn: Arc<SyncConnection>, config: &Config) -> Vec<LauncherError>{
     let mut errors: Vec<LauncherError> = vec![];
+    let vm_name = state.vm_name();
+    let vm_type = state.vm_type();
     // make sure vm is shutdown
-    if state.vm_launched.load(Ordering::Relaxed) {
+    if state.vm_launched.load(Ordering::Relaxed) && !state.vm_destroyed.load(Ordering::Relaxed) {
         // resume just in case
-        let _ = tokio::process::Command::new("virsh").args(["-cqemu:///system", "resume", "windows"])
+        let _ = virsh(&config.libvirt_uri).args(["resume", &vm_name])
             .stderr(Stdio::null()).stdout(Stdio::null()).output().await;
-        println!("Shutting Down VM");
-        if let Err(err) = tokio::process::Command::new("virsh").args(["-cqemu:///system", "shutdown", "windows"]).status().await {
-            errors.push(LauncherError::FailedToShutdownVm(err));
-        };
+        // a guest still booting or installing updates can miss the first request, so it is sent again halfway through
+        let grace = Duration::from_secs(config.shutdown_grace_secs);
+        let waits = if config.shutdown_retry {vec![grace / 2, grace - grace / 2]} else {vec![grace]};
         let mut success = false;
-        println!("Waiting for vm to shutdown");
-        match tokio::process::Command::new("virsh").args(["-cqemu:///system", "domstate", "windows"]).output().await {
-            Ok(output) => {if !output.status.success() {success = true;} else {
-                let mut inner_success = false;
-                loop{
-                    let output = tokio::process::Command::new("virsh")
-                        .args(["-cqemu:///system", "event", "--event", "lifecycle", "--domain", "windows"])
-                        .stderr(Stdio::null()).stdout(Stdio::null())
-                        .output();
-                    let result = tokio::select! {
-                        result = output => {result},
-                        _ = tokio::time::sleep(Duration::from_secs(30)) => {break;}
-                    };
-                    match result {
-                        Err(err) => {errors.push(LauncherError::FailedToGetEvents(err)); break;},
-                        Ok(output) => {
-                            if String::from_utf8_lossy(&output.stdout).contains("Shutdown Finished after guest request") {inner_success = true; break;}
-                        }
-                    }
-                }
-                if inner_success {loop{
-                    let child = match tokio::process::Command::new("virsh")
-                        .args(["-cqemu:///system", "event", "--event", "lifecycle", "--domain", "windows"])
-                        .stderr(Stdio::null()).stdout(Stdio::null()).spawn() 
-                    {
-                        Err(err) => {errors.push(LauncherError::FailedToGetEvents(err)); break;},
-                        Ok(result) => result
-                    };
-                    match tokio::process::Command::new("virsh").args(["-cqemu:///system", "domstate", "windows"]).output().await {
-                        Err(err) => {errors.push(LauncherError::FailedToGetVmState(err)); break;},
-                        Ok(output) => {if !output.status.success() {success = true; break;}}
-                    }
-                    let result = tokio::select! {
-                        result = child.wait_with_output() => {result},
-                        _ = tokio::time::sleep(Duration::from_secs(30)) => {break;}
-                    };
-                    match result {
-                        Err(err) => {errors.push(LauncherError::FailedToGetEvents(err)); break;},
-                        Ok(output) => {
-                            if String::from_utf8_lossy(&output.stdout).contains("Stopped Shutdown") {success = true; break;}
-                        }
-                    }
-                }}
-            }},
-            Err(err) => {errors.push(LauncherError::FailedToShutdownVm(err));}
+        for (attempt, wait) in waits.into_iter().enumerate() {
+            if attempt == 0 {log::info!("Shutting Down VM");} else {log::info!("VM is still running, sending the shutdown again");}
+            if let Err(err) = virsh(&config.libvirt_uri).args(["shutdown", &vm_name]).status().await {
+                cleanup_failed(&mut errors, LauncherError::FailedToShutdownVm(err));
+            };
+            log::info!("Waiting up to {:?} for vm to shutdown", wait);
+            match tokio::time::timeout(wait, wait_for_vm_stop(conn.clone(), &config.libvirt_uri, &vm_name, Duration::from_secs(1), config.dbus_timeout())).await {
+                Ok(Ok(())) => {success = true; break;},
+                Ok(Err(err)) => {cleanup_failed(&mut errors, err); break;},
+                Err(_) => {}
+            }
         }
         if !success {
-            println!("Destroying VM");
-            if let Err(err) = tokio::process::Command::new("virsh").args(["-cqemu:///windows", "destroy", "windows"]).status().await {
-                errors.push(LauncherError::FailedToDestroyVm(err));
-            }
+            if let Err(err) = destroy_vm(&config.libvirt_uri, &vm_name).await {cleanup_failed(&mut errors, err);}
+            state.set_vm_destroyed();
         }
     }
     // undo state changes
     // stop virtual mouse
     if state.virtual_mouse_create.load(Ordering::Relaxed) {
-        println!("Stopping Virtual Mouse");
-        let proxy = Proxy::new("org.cws.VirtualMouse", "/org/cws/VirtualMouse", Duration::from_secs(2), conn.clone());
+        log::info!("Stopping Virtual Mouse");
+        let proxy = Proxy::new("org.cws.VirtualMouse", "/org/cws/VirtualMouse", config.dbus_timeout(), conn.clone());
+        // state files from before the name was saved fall back to the configured name
+        let mouse_name = Some(state.mouse_name()).filter(|name| !name.is_empty()).unwrap_or(config.mouse_name.clone());
         // ignore failures, since the mouse may have been destroyed for other reasons
-        let _ = proxy.method_call::<(String, String, String), _, _, _>("org.cws.VirtualMouse.Manager", "DestroyMouse", ("WindowsMouse",)).await;
-    }
-    println!("Undoing governor and cpu limiting");
-    // undo performance governor
-    if state.performance_governor.load(Ordering::Relaxed) {
-        match Path::new("/sys/devices/system/cpu/").read_dir() {
-            Err(err) => {errors.push(LauncherError::FailedToReadCPUDir(err));}
-            Ok(dir) => {
-                let mut files = dir.into_iter().flatten().filter_map(|dir| {
-                    if dir.file_type().unwrap().is_file() || !dir.file_name().to_str().unwrap().starts_with("cpu") {return None;}
-                    File::create(dir.path().join("cpufreq/scaling_governor")).ok()
-                }).collect::<Vec<File>>();
-                for file in files.iter_mut(){
-                    let _ = file.write("performance".as_bytes());
-                }
-            }
-        };
+        let _ = proxy.method_call::<(String, String, String), _, _, _>("org.cws.VirtualMouse.Manager", "DestroyMouse", (mouse_name,)).await;
     }
+    restore_lg_shm(state.clone());
+    // the vm is gone, so its memory can go back to the host
+    restore_hugepages(state.clone());
+    log::info!("Undoing governor and cpu limiting");
+    // restore each governor to what it was before launch
+    if let Err(err) = restore_governors(state.governor_originals()).await {cleanup_failed(&mut errors, err);}
+    for err in release_vm_cpus(state.clone(), conn.clone(), config).await {cleanup_failed(&mut errors, err);}
     // undo cpu limiting
-    if state.cpus_limited.0.load(Ordering::Relaxed) {
-        let proxy = Proxy::new(
-            "org.freedesktop.systemd1", 
-            "/org/freedesktop/systemd1/unit/user_2eslice", 
-            Duration::from_secs(2), conn.clone());
-        if let Err(err) = proxy.method_call::<(), _, _, _>(
-            "org.freedesktop.systemd1.Unit", 
-            "SetProperties", 
-            (true, vec![("AllowedCPUs", Variant(vec![0_u8, 240_u8, 15_u8, 0_u8, 0_u8, 0_u8, 0_u8, 0_u8]))])
-        ).await {errors.push(LauncherError::FailedToSetCPUs(err));}
-    }
-    if state.cpus_limited.1.load(Ordering::Relaxed) {
-        let proxy = Proxy::new(
-            "org.freedesktop.systemd1", 
-            "/org/freedesktop/systemd1/unit/system_2eslice", 
-            Duration::from_secs(2), conn.clone());
-        if let Err(err) = proxy.method_call::<(), _, _, _>(
-            "org.freedesktop.systemd1.Unit", 
-            "SetProperties", 
-            (true, vec![("AllowedCPUs", Variant(vec![0_u8, 240_u8, 15_u8, 0_u8, 0_u8, 0_u8, 0_u8, 0_u8]))])
-        ).await {errors.push(LauncherError::FailedToSetCPUs(err));}
-    }
-    if state.cpus_limited.2.load(Ordering::Relaxed) {
-        let proxy = Proxy::new(
-            "org.freedesktop.systemd1", 
-            "/org/freedesktop/systemd1/unit/unit_2escope", 
-            Duration::from_secs(2), conn.clone());
-        if let Err(err) = proxy.method_call::<(), _, _, _>(
-            "org.freedesktop.systemd1.Unit", 
-            "SetProperties", 
-            (true, vec![("AllowedCPUs", Variant(vec![0_u8, 240_u8, 15_u8, 0_u8, 0_u8, 0_u8, 0_u8, 0_u8]))])
-        ).await {errors.push(LauncherError::FailedToSetCPUs(err));}
+    match parse_cpu_list(&config.default_cpu_mask).map(|cpus| cpu_mask(&cpus)) {
+        Err(err) => {cleanup_failed(&mut errors, err);},
+        Ok(mask) => {
+            if state.cpus_limited.0.load(Ordering::Relaxed) {
+                if let Err(err) = set_allowed_cpus(conn.clone(), "/org/freedesktop/systemd1/unit/user_2eslice", mask.clone(), config.dbus_timeout()).await {cleanup_failed(&mut errors, err);}
+            }
+            if state.cpus_limited.1.load(Ordering::Relaxed) {
+                if let Err(err) = set_allowed_cpus(conn.clone(), "/org/freedesktop/systemd1/unit/system_2eslice", mask.clone(), config.dbus_timeout()).await {cleanup_failed(&mut errors, err);}
+            }
+            if state.cpus_limited.2.load(Ordering::Relaxed) {
+                if let Err(err) = set_allowed_cpus(conn.clone(), "/org/freedesktop/systemd1/unit/unit_2escope", mask, config.dbus_timeout()).await {cleanup_failed(&mut errors, err);}
+            }
+        }
     }
     // undo gpu disconnection
-    println!("Reconnecting gpu");
-    errors.extend(rc_gpu(state.clone(), conn.clone()).await);
+    log::info!("Reconnecting gpu");
+    errors.extend(rc_gpu(state.clone(), conn.clone(), config).await);
+    // the host only lost its outputs if the gpu drove the host displays during the session
+    if let Some(cmd) = config.monitor_restore_cmd.as_ref().filter(|_| vm_type == VmType::Direct.as_code()) {
+        log::info!("Restoring the monitor layout");
+        errors.extend(restore_monitors(conn.clone(), cmd, config.dbus_timeout()).await);
+    }
     // revert state to default
     state.revert();
+    // only a cleanup after a launch has a session to run the hook for
+    if let Some(hook) = config.post_shutdown_hook.as_ref().filter(|_| !vm_type.is_empty()) {
+        log::info!("Running the post shutdown hook");
+        if let Err(err) = run_hook(hook, &vm_type, &vm_name).await {cleanup_failed(&mut errors, err);}
+    }
     errors
 }
 
+/// Runs a hook executable with the vm type and domain name in its environment, failing if it exits nonzero
+pub async fn run_hook(hook: &str, vm_type: &str, vm_name: &str) -> Result<(), LauncherError>{
+    let status = tokio::process::Command::new(hook).env("VM_TYPE", vm_type).env("VM_NAME", vm_name).stdin(Stdio::null()).status().await
+        .map_err(|err| LauncherError::FailedToRunHook(hook.to_string(), err))?;
+    if !status.success() {return Err(LauncherError::HookFailed(hook.to_string(), status));}
+    Ok(())
+}
+
 /// Disconnects the gpu from the system
-pub async fn dc_gpu_lg(state: Arc<SystemState>, conn: Arc<SyncConnection>) -> Result<(), LauncherError>{
-    // stop display manager
-    println!("Stopping Display Manager");
-    let proxy = Proxy::new("org.freedesktop.systemd1", "/org/freedesktop/systemd1", Duration::from_secs(2), conn.clone());
-    let _: (dbus::Path,) = proxy.method_call("org.freedesktop.systemd1.Manager", "StopUnit", ("display-manager.service", "replace")).await
-        .map_err(|err| LauncherError::FailedToStopDP(err))?;
-    state.dp_stopped.store(true, Ordering::Relaxed);
-    // stop pipewire
-    println!("Stopping Pipewire");
-    let login_proxy = Proxy::new("org.freedesktop.login1", "/org/freedesktop/login1", Duration::from_secs(2), conn.clone());
-    let (users,) = login_proxy.method_call::<(Vec<(u32, String, dbus::Path)>,), _, _, _>("org.freedesktop.login1.Manager", "ListUsers", ()).await
-        .map_err(|err| LauncherError::FailedToGetUsers(err))?;
-    for (user, _, _) in users.iter(){
-        let _ = tokio::process::Command::new("systemctl").args(["--user", &format!("--machine={}@", user), "stop", "pipewire.socket"])
-            .stderr(Stdio::null()).stdout(Stdio::null()).status().await;
-        let _ = tokio::process::Command::new("systemctl").args(["--user", &format!("--machine={}@", user), "stop", "pipewire-pulse.socket"])
-            .stderr(Stdio::null()).stdout(Stdio::null()).status().await;
+pub async fn dc_gpu_lg(state: Arc<SystemState>, conn: Arc<SyncConnection>, config: &Config) -> Result<(), LauncherError>{
+    // fail while the desktop is still up if passthrough can't work
+    iommu_preflight(&config.gpu_pci_ids.iter().chain(config.extra_pci_ids.iter()).cloned().collect::<Vec<String>>())?;
+    // a host gpu on the same driver keeps the desktop, and only the configured gpu is moved off the driver
+    let host_gpus = host_gpus_sharing_driver(Path::new(PCI_BUS_PATH), &config.gpu_pci_ids);
+    // stop display manager, unless the desktop runs on another gpu
+    if config.igpu_host {
+        log::info!("Leaving {} running on the igpu", config.display_service);
+    } else if !host_gpus.is_empty() {
+        log::info!("Leaving {} running on {}", config.display_service, host_gpus.join(", "));
+    } else {
+        log::info!("Stopping {}", config.display_service);
+        match display_service_action(conn.clone(), config, "stop").await {
+            Ok(job) => {
+                state.dp_stopped.store(true, Ordering::Relaxed);
+                state.save();
+                if let Some(job) = job {wait_for_jobs(conn.clone(), &[job], UNIT_JOB_TIMEOUT, config.dbus_timeout()).await?;}
+            },
+            // nothing to stop, and nothing to start again later
+            Err(err) if err.name() == Some("org.freedesktop.systemd1.NoSuchUnit") => {
+                log::warn!("{} is not loaded, continuing without stopping it", config.display_service);
+            },
+            Err(err) => {return Err(LauncherError::FailedToStopDP(err));}
+        }
     }
+    // stop the user units, like pipewire
+    log::info!("Stopping user units: {}", config.user_units.join(", "));
+    user_units_action(conn.clone(), &ordered_user_units(config, "stop"), "stop", config.dbus_timeout(), Some(UNIT_JOB_TIMEOUT)).await.map_err(LauncherError::FailedToGetUsers)?;
     state.pw_stopped.store(true, Ordering::Release);
-    // wait for processes to close
-    println!("Waiting for processes to close");
+    state.save();
+    // the stop jobs are done, but clients of the units, like screencasts, can still be letting go of the gpu
+    if !config.user_units.is_empty() && config.user_units_settle_ms > 0 {
+        tokio::time::sleep(Duration::from_millis(config.user_units_settle_ms)).await;
+    }
+    // with the desktop left up, only the services actually using the gpu are stopped
+    if config.igpu_host {stop_gpu_services(&state, conn.clone(), config.dbus_timeout()).await;}
+    // processes of the host gpu can't be told apart from those of the passthrough gpu on the same driver, so they aren't waited on
+    if host_gpus.is_empty() {wait_for_gpu_release(config).await?;}
+    bind_vfio(&state, &SystemRunner, Path::new(PCI_BUS_PATH), config).await?;
+    // restart the user units
+    log::info!("Starting user units");
+    if let Err(err) = user_units_action(conn.clone(), &ordered_user_units(config, "start"), "start", config.dbus_timeout(), None).await {
+        log::warn!("Could not list users to start the user units for: {}", err);
+    }
+    state.pw_stopped.store(false, Ordering::Relaxed);
+    state.save();
+    Ok(())
+}
+
+/// Waits for the processes using the gpu to close, killing them if force_kill_gpu_procs is set
+pub async fn wait_for_gpu_release(config: &Config) -> Result<(), LauncherError>{
+    log::info!("Waiting for processes to close");
     let mut success = false;
     for _ in 0..20{
-        let output = tokio::process::Command::new("ps").args(["-u", "root"]).stderr(Stdio::null()).stdout(Stdio::piped()).output().await
-            .map_err(|err| LauncherError::FailedToGetProcesses(err))?.stdout;
+        if config.igpu_host {
+            if gpu_processes().is_empty() {success = true; break;}
+            tokio::time::sleep(Duration::from_secs_f32(0.1)).await;
+            continue;
+        }
+        // only the command name column, so names don't match against arguments or other columns
+        let output = tokio::process::Command::new("ps").args(["-u", "root", "-o", "comm="]).stderr(Stdio::null()).stdout(Stdio::piped()).output().await
+            .map_err(LauncherError::FailedToGetProcesses)?.stdout;
         let output = String::from_utf8_lossy(&output);
-        if output.contains("sddm") || output.contains("X") {
+        if output.lines().any(|command| config.gpu_process_names.iter().any(|name| command.trim() == name)) {
             tokio::time::sleep(Duration::from_secs_f32(0.1)).await;
             continue;
         };
         success = true; break;
     }
-    if !success {return Err(LauncherError::ProcessesDidNotExit);}
-    // unload nvidia
-    println!("Unloading Nvidia Modules");
-    let out = tokio::process::Command::new("modprobe").args(["-f", "-r", "nvidia_uvm"]).output().await
-        .map_err(|err| LauncherError::FailedToUnloadKernelModule("nvidia_uvm".to_string(), err))?;
-    if out.stderr.len() > 0 && !String::from_utf8(out.stderr.clone()).unwrap().contains("not found") {
-        return Err(LauncherError::ModprobeRemoveReturnedErr("nvidia_uvm".to_string(), String::from_utf8(out.stderr.clone()).unwrap()));
-    }
-    state.nvidia_unloaded.0.store(true, Ordering::Relaxed);
-    let out = tokio::process::Command::new("modprobe").args(["-f", "-r", "nvidia_drm"]).output().await
-        .map_err(|err| LauncherError::FailedToUnloadKernelModule("nvidia_drm".to_string(), err))?;
-    if out.stderr.len() > 0 && !String::from_utf8(out.stderr.clone()).unwrap().contains("not found") {
-        return Err(LauncherError::ModprobeRemoveReturnedErr("nvidia_drm".to_string(), String::from_utf8(out.stderr.clone()).unwrap()));
-    }
-    state.nvidia_unloaded.1.store(true, Ordering::Relaxed);
-    let out = tokio::process::Command::new("modprobe").args(["-f", "-r", "nvidia_modeset"]).output().await
-        .map_err(|err| LauncherError::FailedToUnloadKernelModule("nvidia_modeset".to_string(), err))?;
-    if out.stderr.len() > 0 && !String::from_utf8(out.stderr.clone()).unwrap().contains("not found") {
-        return Err(LauncherError::ModprobeRemoveReturnedErr("nvidia_modeset".to_string(), String::from_utf8(out.stderr.clone()).unwrap()));
-    }
-    state.nvidia_unloaded.2.store(true, Ordering::Relaxed);
-    let out = tokio::process::Command::new("modprobe").args(["-f", "-r", "nvidia"]).output().await
-        .map_err(|err| LauncherError::FailedToUnloadKernelModule("nvidia".to_string(), err))?;
-    if out.stderr.len() > 0 && !String::from_utf8(out.stderr.clone()).unwrap().contains("not found") {
-        return Err(LauncherError::ModprobeRemoveReturnedErr("nvidia".to_string(), String::from_utf8(out.stderr.clone()).unwrap()));
-    }
-    state.nvidia_unloaded.3.store(true, Ordering::Relaxed);
-    // disconnect
-    println!("Disconnecting GPU");
-    let _ = tokio::process::Command::new("virsh").args(["nodedev-detach", "pci_0000_01_00_0"]).status().await
-        .map_err(|err| LauncherError::FailedToDisconnectGPU("pci_0000_01_00_0".to_string(), err))?;
-    state.gpu_dettached.0.store(true, Ordering::Relaxed);
-    let _ = tokio::process::Command::new("virsh").args(["nodedev-detach", "pci_0000_01_00_1"]).status().await
-        .map_err(|err| LauncherError::FailedToDisconnectGPU("pci_0000_01_00_1".to_string(), err))?;
-    state.gpu_dettached.1.store(true, Ordering::Relaxed);
-    // load vfio
-    println!("Loading VFIO");
-    let _ = tokio::process::Command::new("modprobe").args(["vfio-pci"]).status().await
-        .map_err(|err| LauncherError::FailedToLoadKernelModule("vfio-pci".to_string(), err))?;
-    state.vfio_loaded.store(true, Ordering::Relaxed);
-    // restart pipewire
-    println!("Starting Pipewire");
-    for (user, _, _) in users.iter(){
-        let _ = tokio::process::Command::new("systemctl").args(["--user", &format!("--machine={}@", user), "start", "pipewire.socket"])
-            .stderr(Stdio::null()).stdout(Stdio::null()).status().await;
-        let _ = tokio::process::Command::new("systemctl").args(["--user", &format!("--machine={}@", user), "start", "pipewire-pulse.socket"])
-            .stderr(Stdio::null()).stdout(Stdio::null()).status().await;
+    if !success {
+        if !config.force_kill_gpu_procs && config.igpu_host {
+            return Err(LauncherError::GpuStillInUse(gpu_processes().iter().map(|(pid, name)| format!("{} ({})", pid, name)).collect()));
+        }
+        if !config.force_kill_gpu_procs {return Err(LauncherError::ProcessesDidNotExit);}
+        log::warn!("Processes did not close, killing everything holding the gpu");
+        kill_gpu_processes().await;
     }
-    state.pw_stopped.store(false, Ordering::Relaxed);
     Ok(())
 }
 
-/// Reconnects the gpu, by doing any necessary steps as determined by state. errors are ignored, and returned at the end as a list
-pub async fn rc_gpu(state: Arc<SystemState>, conn: Arc<SyncConnection>) -> Vec<LauncherError> {
+/// Runs a systemd action, "stop", "start", or "restart", on the configured display service
+/// a system unit goes through the systemd manager and returns its job
+/// a user unit is run for every logged in user, where failures are only logged like they are for user_units
+pub async fn display_service_action(conn: Arc<SyncConnection>, config: &Config, action: &str) -> Result<Option<dbus::Path<'static>>, dbus::Error>{
+    if config.display_service_user {
+        let job_timeout = (action == "stop").then_some(UNIT_JOB_TIMEOUT);
+        user_units_action(conn, &[config.display_service.as_str()], action, config.dbus_timeout(), job_timeout).await?;
+        return Ok(None);
+    }
+    let method = unit_method(action);
+    let proxy = Proxy::new("org.freedesktop.systemd1", "/org/freedesktop/systemd1", config.dbus_timeout(), conn);
+    let (job,): (dbus::Path<'static>,) = proxy.method_call("org.freedesktop.systemd1.Manager", method, (config.display_service.as_str(), "replace")).await?;
+    Ok(Some(job))
+}
+
+/// returns the configured user units in the order action runs on them, as listed to stop and reversed otherwise
+pub fn ordered_user_units<'a>(config: &'a Config, action: &str) -> Vec<&'a str>{
+    let units = config.user_units.iter().map(String::as_str);
+    if action == "stop" {units.collect()} else {units.rev().collect()}
+}
+
+/// returns the systemd manager method for an action, "stop", "start", or "restart"
+fn unit_method(action: &str) -> &'static str{
+    match action {"stop" => "StopUnit", "start" => "StartUnit", _ => "RestartUnit"}
+}
+
+/// Connects to the systemd user manager of a user directly over its private socket, which lets root in without a session bus or systemctl
+/// returns None for users without a running manager, like system accounts with a greeter session, and for managers that can't be reached
+/// the returned handle drives the connection and should be aborted when done
+async fn user_manager(uid: u32, name: &str, dbus_timeout: Duration) -> Option<(tokio::task::JoinHandle<()>, Proxy<'static, Arc<SyncConnection>>)>{
+    let socket = format!("/run/user/{}/systemd/private", uid);
+    if !Path::new(&socket).exists() {
+        log::debug!("{} has no systemd user manager running, skipping", name);
+        return None;
+    }
+    // opening the channel blocks on the handshake, so it runs off the runtime and a hung manager only costs dbus_timeout
+    let address = format!("unix:path={}", socket);
+    let channel = match tokio::time::timeout(dbus_timeout, tokio::task::spawn_blocking(move || Channel::open_private(&address))).await {
+        Ok(Ok(Ok(channel))) => channel,
+        Ok(Ok(Err(err))) => {log::warn!("Could not reach the systemd user manager of {}: {}", name, err); return None;},
+        Ok(Err(err)) => {log::warn!("Could not reach the systemd user manager of {}: {}", name, err); return None;},
+        Err(_) => {log::warn!("The systemd user manager of {} did not answer within {:?}, skipping", name, dbus_timeout); return None;}
+    };
+    let (resource, user_conn) = match dbus_tokio::connection::from_channel::<SyncConnection>(channel) {
+        Ok(connection) => connection,
+        Err(err) => {log::warn!("Could not reach the systemd user manager of {}: {}", name, err); return None;}
+    };
+    let handle = tokio::spawn(async move {let _ = resource.await;});
+    Some((handle, Proxy::new("org.freedesktop.systemd1", "/org/freedesktop/systemd1", dbus_timeout, user_conn)))
+}
+
+/// returns the uid and name of every logged in user
+async fn logged_in_users(conn: Arc<SyncConnection>, dbus_timeout: Duration) -> Result<Vec<(u32, String)>, dbus::Error>{
+    let login_proxy = Proxy::new("org.freedesktop.login1", "/org/freedesktop/login1", dbus_timeout, conn);
+    let (users,) = login_proxy.method_call::<(Vec<(u32, String, dbus::Path)>,), _, _, _>("org.freedesktop.login1.Manager", "ListUsers", ()).await?;
+    Ok(users.into_iter().map(|(uid, name, _)| (uid, name)).collect())
+}
+
+/// Runs action, "stop", "start", or "restart", on units in the systemd user manager of every logged in user
+/// only listing the users can fail, managers and units that can't be reached are logged and skipped
+/// users without a running manager are looked up again on the next call
+/// with job_timeout, each manager's jobs are waited on before moving on, and a timeout is only logged
+pub async fn user_units_action(conn: Arc<SyncConnection>, units: &[&str], action: &str, dbus_timeout: Duration, job_timeout: Option<Duration>) -> Result<(), dbus::Error>{
+    for (uid, name) in logged_in_users(conn, dbus_timeout).await? {
+        let Some((handle, proxy)) = user_manager(uid, &name, dbus_timeout).await else {continue;};
+        let mut jobs = vec![];
+        for unit in units {
+            match proxy.method_call::<(dbus::Path<'static>,), _, _, _>("org.freedesktop.systemd1.Manager", unit_method(action), (*unit, "replace")).await {
+                Ok((job,)) => {jobs.push(job);},
+                // not every user has every unit
+                Err(err) if err.name() == Some("org.freedesktop.systemd1.NoSuchUnit") => {},
+                Err(err) => {log::warn!("Could not {} {} for {}: {}", action, unit, name, err);}
+            }
+        }
+        // the jobs only exist on this user's manager, so they are waited on before its connection is dropped
+        if let Some(timeout) = job_timeout.filter(|_| !jobs.is_empty()) {
+            if let Err(err) = wait_for_jobs(proxy.connection.clone(), &jobs, timeout, dbus_timeout).await {log::warn!("Could not {} the user units of {}: {}", action, name, err);}
+        }
+        handle.abort();
+    }
+    Ok(())
+}
+
+/// Runs cmd with sh as every user with a graphical session, in the environment of their systemd user manager
+/// users without DISPLAY or WAYLAND_DISPLAY have nothing to restore and are skipped
+pub async fn restore_monitors(conn: Arc<SyncConnection>, cmd: &str, dbus_timeout: Duration) -> Vec<LauncherError>{
+    let mut errors = vec![];
+    let users = match logged_in_users(conn, dbus_timeout).await {
+        Ok(users) => users,
+        Err(err) => {cleanup_failed(&mut errors, LauncherError::FailedToGetUsers(err)); return errors;}
+    };
+    for (uid, name) in users {
+        let Some((handle, proxy)) = user_manager(uid, &name, dbus_timeout).await else {continue;};
+        let env = proxy.get::<Vec<String>>("org.freedesktop.systemd1.Manager", "Environment").await;
+        handle.abort();
+        let env = match env {
+            Ok(vars) => vars.iter().filter_map(|var| var.split_once('=')).map(|(key, value)| (key.to_string(), value.to_string())).collect::<Vec<(String, String)>>(),
+            Err(err) => {log::warn!("Could not read the user manager environment of {}: {}", name, err); continue;}
+        };
+        if !env.iter().any(|(key, value)| (key == "DISPLAY" || key == "WAYLAND_DISPLAY") && !value.is_empty()) {continue;}
+        log::info!("Restoring the monitor layout for {}", name);
+        match tokio::process::Command::new("runuser").args(["-u", &name, "--", "sh", "-c", cmd])
+            .env_clear().envs(env).stdin(Stdio::null()).status().await {
+            Ok(status) if status.success() => {},
+            Ok(status) => {cleanup_failed(&mut errors, LauncherError::MonitorRestoreFailed(name, status));},
+            Err(err) => {cleanup_failed(&mut errors, LauncherError::FailedToRestoreMonitors(name, err));}
+        }
+    }
+    errors
+}
+
+/// Checks that the iommu is enabled and that every device in devices is in a group with only other devices in devices
+/// pci bridges are allowed in a group, since they are never bound to a driver we need. run before anything is stopped
+pub fn iommu_preflight(devices: &[String]) -> Result<(), LauncherError>{
+    if iommu_groups().map(|groups| groups.is_empty()).unwrap_or(true) {return Err(LauncherError::IommuNotEnabled);}
+    let addresses = devices.iter().filter_map(|pci| pci_sysfs_address(pci)).collect::<Vec<String>>();
+    for pci in devices {
+        let Some(address) = pci_sysfs_address(pci) else {continue;};
+        let Some(members) = group_members(&address) else {continue;};
+        let others = members.into_iter()
+            .filter(|member| !addresses.contains(member) && !is_bridge(member))
+            .collect::<Vec<String>>();
+        if !others.is_empty() {return Err(LauncherError::IommuGroupShared(pci.clone(), others));}
+    }
+    Ok(())
+}
+
+/// Converts a libvirt node device name like pci_0000_01_00_0 to its sysfs address, 0000:01:00.0
+pub fn pci_sysfs_address(pci: &str) -> Option<String>{
+    let parts = pci.strip_prefix("pci_")?.split('_').collect::<Vec<&str>>();
+    match parts.as_slice() {
+        [domain, bus, slot, function] => Some(format!("{}:{}:{}.{}", domain, bus, slot, function)),
+        _ => None
+    }
+}
+
+/// Waits until every device is bound to a host driver, anything but vfio-pci, or timeout passes
+/// a timeout is only logged, since the display service may still come up fine. returns whether they all were bound
+pub async fn wait_for_host_drivers(bus: &Path, devices: &[String], timeout: Duration) -> bool{
+    let bound = |pci: &String| {
+        let Some(address) = pci_sysfs_address(pci) else {return true;};
+        std::fs::read_link(bus.join("devices").join(address).join("driver"))
+            .is_ok_and(|driver| driver.file_name().is_some_and(|name| name != "vfio-pci"))
+    };
+    let wait = async {
+        while !devices.iter().all(bound) {
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        }
+    };
+    if tokio::time::timeout(timeout, wait).await.is_err() {
+        log::warn!("The gpu was not bound to its host driver within {:?}, continuing anyway", timeout);
+        return false;
+    }
+    true
+}
+
+/// Hands the gpu and extra passthrough devices over to vfio, tracking each step in state as it completes
+/// bus is the sysfs pci bus, PCI_BUS_PATH outside of tests
+pub async fn bind_vfio(state: &SystemState, runner: &dyn CommandRunner, bus: &Path, config: &Config) -> Result<(), LauncherError>{
+    let host_gpus = host_gpus_sharing_driver(bus, &config.gpu_pci_ids);
+    if host_gpus.is_empty() {
+        // unload nvidia
+        log::info!("Unloading Nvidia Modules");
+        for module in config.nvidia_modules.iter() {
+            unload_module(runner, module).await?;
+            SystemState::track(&state.nvidia_unloaded, module);
+            state.save();
+        }
+        // disconnect
+        log::info!("Disconnecting GPU");
+        for pci in config.gpu_pci_ids.iter() {
+            detach_device(runner, &config.libvirt_uri, pci).await?;
+            SystemState::track(&state.gpu_dettached, pci);
+            state.save();
+        }
+    } else {
+        // the driver stays loaded for the host gpu, so each configured device is moved to vfio-pci on its own, which needs vfio loaded first
+        log::info!("Keeping {} on the host, moving only the configured gpu to vfio-pci", host_gpus.join(", "));
+        load_vfio(state, runner, config).await?;
+        for pci in config.gpu_pci_ids.iter() {
+            let address = pci_sysfs_address(pci)
+                .ok_or(LauncherError::FailedToDisconnectGPU(pci.clone(), std::io::Error::new(std::io::ErrorKind::InvalidInput, "not a pci_dddd_bb_ss_f name")))?;
+            let driver = vfio_bind_device(bus, &address).map_err(|err| LauncherError::FailedToDisconnectGPU(pci.clone(), err))?;
+            state.gpu_drivers.lock_or_recover().push((address, driver.unwrap_or_default()));
+            state.save();
+        }
+    }
+    // extra passthrough devices go after the gpu
+    if !config.extra_pci_ids.is_empty() {
+        log::info!("Disconnecting extra passthrough devices");
+        for module in config.extra_modules.iter() {
+            unload_module(runner, module).await?;
+            SystemState::track(&state.extra_unloaded, module);
+            state.save();
+        }
+        for pci in config.extra_pci_ids.iter() {
+            detach_device(runner, &config.libvirt_uri, pci).await?;
+            SystemState::track(&state.extra_detached, pci);
+            state.save();
+        }
+    }
+    load_vfio(state, runner, config).await
+}
+
+/// Loads vfio_module with its options, unless it is bound some other way like an initramfs or driverctl, or we already loaded it
+pub async fn load_vfio(state: &SystemState, runner: &dyn CommandRunner, config: &Config) -> Result<(), LauncherError>{
+    if !config.manage_vfio || state.vfio_loaded.load(Ordering::Relaxed) {return Ok(());}
+    log::info!("Loading {}", config.vfio_module);
+    let args: Vec<&str> = std::iter::once(config.vfio_module.as_str()).chain(config.vfio_options.iter().map(String::as_str)).collect();
+    let _ = runner.run("modprobe", &args).await
+        .map_err(|err| LauncherError::FailedToLoadKernelModule(config.vfio_module.clone(), err))?;
+    state.vfio_loaded.store(true, Ordering::Relaxed);
+    state.save();
+    Ok(())
+}
+
+/// Returns the devices tracked in state to the host, in the reverse order of bind_vfio
+/// errors are collected rather than stopping early. also returns whether anything was changed on the host
+pub async fn unbind_vfio(state: &SystemState, runner: &dyn CommandRunner, bus: &Path, config: &Config) -> (Vec<LauncherError>, bool){
     let mut errors: Vec<LauncherError> = vec![];
-    let mut reset_dp = false; let mut reset_pw = false;
-    // do any work to reconnect the gpu
+    let mut changed = false;
+    // devices moved on their own go back to their driver while vfio is still loaded
+    // the host desktop kept its own gpu, so this doesn't count as a change that needs the display service restarted
+    let gpu_drivers = state.gpu_drivers.lock_or_recover().clone();
+    for (address, driver) in gpu_drivers.iter().rev() {
+        log::info!("Returning {} to {}", address, if driver.is_empty() {"the first matching driver"} else {driver});
+        if let Err(err) = vfio_unbind_device(bus, address, Some(driver.as_str()).filter(|driver| !driver.is_empty())) {
+            cleanup_failed(&mut errors, LauncherError::FailedToConnectGPU(address.clone(), err));
+        }
+    }
     // unload vfio
     if state.vfio_loaded.load(Ordering::Relaxed) {
-        println!("Unloading vfio");
-        match tokio::process::Command::new("modprobe").args(["-f", "-r", "vfio-pci"]).output().await {
-            Err(err) => {errors.push(LauncherError::FailedToUnloadKernelModule("vfio-pci".to_string(), err));},
+        log::info!("Unloading {}", config.vfio_module);
+        match runner.run("modprobe", &["-f", "-r", &config.vfio_module]).await {
+            Err(err) => {cleanup_failed(&mut errors, LauncherError::FailedToUnloadKernelModule(config.vfio_module.clone(), err));},
             Ok(out) => {
-                if out.stderr.len() > 0 && !String::from_utf8(out.stderr.clone()).unwrap().contains("not found") {
-                    errors.push(LauncherError::ModprobeRemoveReturnedErr("vfio-pci".to_string(), String::from_utf8(out.stderr.clone()).unwrap()));
+                let stderr = String::from_utf8_lossy(&out.stderr);
+                if !stderr.is_empty() && !stderr.contains("not found") {
+                    cleanup_failed(&mut errors, LauncherError::ModprobeRemoveReturnedErr(config.vfio_module.clone(), stderr.to_string()));
                 }
             }
         }
-        reset_dp = true; reset_pw = true;
+        changed = gpu_drivers.is_empty();
+    }
+    // reattach extra passthrough devices first, since they were detached last
+    for pci in SystemState::tracked(&state.extra_detached).iter().rev() {
+        log::info!("Reconnecting {}", pci);
+        if let Err(err) = reattach_device(runner, &config.libvirt_uri, pci).await {cleanup_failed(&mut errors, err);}
+    }
+    for module in SystemState::tracked(&state.extra_unloaded).iter().rev() {
+        log::info!("Loading {}", module);
+        if let Err(err) = load_module(runner, module).await {cleanup_failed(&mut errors, err);}
     }
     // reattach gpu
-    if state.gpu_dettached.0.load(Ordering::Relaxed) {
-        println!("Reconnecting gpu 0");
-        if let Err(err) = tokio::process::Command::new("virsh").args(["nodedev-reattach", "pci_0000_01_00_0"]).status().await{
-            errors.push(LauncherError::FailedToConnectGPU("pci_0000_01_00_0".to_string(), err));
-        }
-        reset_dp = true; reset_pw = true;
+    for pci in SystemState::tracked(&state.gpu_dettached).iter() {
+        log::info!("Reconnecting {}", pci);
+        if let Err(err) = reattach_device(runner, &config.libvirt_uri, pci).await {cleanup_failed(&mut errors, err);}
+        changed = true;
     }
-    if state.gpu_dettached.1.load(Ordering::Relaxed) {
-        println!("Reconnecting gpu 1");
-        if let Err(err) = tokio::process::Command::new("virsh").args(["nodedev-reattach", "pci_0000_01_00_1"]).status().await{
-            errors.push(LauncherError::FailedToConnectGPU("pci_0000_01_00_1".to_string(), err));
-        }
-        reset_dp = true; reset_pw = true;
+    // load nvidia, in the reverse order it was unloaded
+    for module in SystemState::tracked(&state.nvidia_unloaded).iter().rev() {
+        log::info!("Loading {}", module);
+        if let Err(err) = load_module(runner, module).await {cleanup_failed(&mut errors, err);}
+        changed = true;
     }
-    // load nvidia
-    if state.nvidia_unloaded.3.load(Ordering::Relaxed) {
-        println!("Loading nvidia");
-        if let Err(err) = tokio::process::Command::new("modprobe").args(["nvidia"]).status().await{
-            errors.push(LauncherError::FailedToLoadKernelModule("nvidia".to_string(), err));
+    (errors, changed)
+}
+
+/// sysfs directory of the pci bus
+pub const PCI_BUS_PATH: &str = "/sys/bus/pci";
+
+/// returns the driver a pci device, like 0000:01:00.0, is bound to under bus
+pub fn pci_driver(bus: &Path, address: &str) -> Option<String>{
+    std::fs::read_link(bus.join("devices").join(address).join("driver")).ok()
+        .and_then(|driver| driver.file_name().map(|name| name.to_string_lossy().to_string()))
+}
+
+/// Returns the display devices under bus, other than gpu_pci_ids, bound to the same driver as one of gpu_pci_ids
+/// the driver can't be unloaded while one of these drives the host desktop
+pub fn host_gpus_sharing_driver(bus: &Path, gpu_pci_ids: &[String]) -> Vec<String>{
+    let configured = gpu_pci_ids.iter().filter_map(|pci| pci_sysfs_address(pci)).collect::<Vec<String>>();
+    let drivers = configured.iter().filter_map(|address| pci_driver(bus, address)).filter(|driver| driver != "vfio-pci").collect::<Vec<String>>();
+    if drivers.is_empty() {return vec![];}
+    let Ok(devices) = bus.join("devices").read_dir() else {return vec![];};
+    let mut host_gpus = devices.flatten().map(|entry| entry.file_name().to_string_lossy().to_string())
+        .filter(|address| !configured.contains(address))
+        .filter(|address| std::fs::read_to_string(bus.join("devices").join(address).join("class")).is_ok_and(|class| class.starts_with("0x03")))
+        .filter(|address| pci_driver(bus, address).is_some_and(|driver| drivers.contains(&driver)))
+        .collect::<Vec<String>>();
+    host_gpus.sort();
+    host_gpus
+}
+
+/// Moves a single pci device to vfio-pci through sysfs, leaving every other device on its driver alone
+/// returns the driver it was bound to, so vfio_unbind_device can give it back
+pub fn vfio_bind_device(bus: &Path, address: &str) -> std::io::Result<Option<String>>{
+    let device = bus.join("devices").join(address);
+    let driver = pci_driver(bus, address);
+    std::fs::write(device.join("driver_override"), "vfio-pci")?;
+    if driver.is_some() {std::fs::write(device.join("driver").join("unbind"), address)?;}
+    std::fs::write(bus.join("drivers_probe"), address)?;
+    Ok(driver)
+}
+
+/// Returns a pci device moved by vfio_bind_device to driver, or to whichever driver claims it if it had none
+pub fn vfio_unbind_device(bus: &Path, address: &str, driver: Option<&str>) -> std::io::Result<()>{
+    let device = bus.join("devices").join(address);
+    std::fs::write(device.join("driver_override"), "\n")?;
+    // unloading vfio may have unbound it already
+    if pci_driver(bus, address).is_some() {std::fs::write(device.join("driver").join("unbind"), address)?;}
+    match driver {
+        Some(driver) => std::fs::write(bus.join("drivers").join(driver).join("bind"), address),
+        None => std::fs::write(bus.join("drivers_probe"), address)
+    }
+}
+
+/// Detaches a libvirt node device from the host. a failed virsh is an error, since the device would stay on the host
+pub async fn detach_device(runner: &dyn CommandRunner, uri: &str, pci: &str) -> Result<(), LauncherError>{
+    let out = runner.run("virsh", &["-c", uri, "nodedev-detach", pci]).await
+        .map_err(|err| LauncherError::FailedToDisconnectGPU(pci.to_string(), err))?;
+    if !out.status.success() {return Err(LauncherError::FailedToDisconnectGPU(pci.to_string(), stderr_error(&out)));}
+    Ok(())
+}
+
+/// Reattaches a libvirt node device to the host. a failed virsh is an error, since the device would stay on vfio
+pub async fn reattach_device(runner: &dyn CommandRunner, uri: &str, pci: &str) -> Result<(), LauncherError>{
+    let out = runner.run("virsh", &["-c", uri, "nodedev-reattach", pci]).await
+        .map_err(|err| LauncherError::FailedToConnectGPU(pci.to_string(), err))?;
+    if !out.status.success() {return Err(LauncherError::FailedToConnectGPU(pci.to_string(), stderr_error(&out)));}
+    Ok(())
+}
+
+/// Loads a kernel module with modprobe
+pub async fn load_module(runner: &dyn CommandRunner, module: &str) -> Result<(), LauncherError>{
+    let out = runner.run("modprobe", &[module]).await
+        .map_err(|err| LauncherError::FailedToLoadKernelModule(module.to_string(), err))?;
+    if !out.status.success() {return Err(LauncherError::FailedToLoadKernelModule(module.to_string(), stderr_error(&out)));}
+    Ok(())
+}
+
+/// wraps the stderr of a failed command as an io error
+fn stderr_error(out: &std::process::Output) -> std::io::Error {
+    std::io::Error::other(format!("{} {}", out.status, String::from_utf8_lossy(&out.stderr).trim()))
+}
+
+/// Returns the pid and command name of every process with a /dev/nvidia* device open, found through /proc/*/fd
+pub fn gpu_processes() -> Vec<(i32, String)>{
+    let Ok(procs) = Path::new("/proc").read_dir() else {return vec![];};
+    procs.flatten().filter_map(|entry| {
+        let pid = entry.file_name().to_string_lossy().parse::<i32>().ok()?;
+        let holds_gpu = entry.path().join("fd").read_dir().ok()?.flatten()
+            .any(|fd| std::fs::read_link(fd.path()).is_ok_and(|target| target.to_string_lossy().starts_with("/dev/nvidia")));
+        if !holds_gpu {return None;}
+        let name = std::fs::read_to_string(entry.path().join("comm")).unwrap_or_default().trim().to_string();
+        Some((pid, name))
+    }).collect()
+}
+
+/// Sends SIGTERM to every process holding the gpu, then SIGKILL to any still holding it a second later
+pub async fn kill_gpu_processes(){
+    let procs = gpu_processes();
+    if procs.is_empty() {return;}
+    for (pid, _) in procs.iter() {let _ = kill(Pid::from_raw(*pid), Signal::SIGTERM);}
+    tokio::time::sleep(Duration::from_secs(1)).await;
+    for (pid, _) in gpu_processes() {let _ = kill(Pid::from_raw(pid), Signal::SIGKILL);}
+    log::warn!("Killed gpu processes: {}", procs.iter().map(|(pid, name)| format!("{} ({})", pid, name)).collect::<Vec<String>>().join(", "));
+}
+
+/// Returns the system services with a process holding the gpu, found through each process's cgroup
+pub fn gpu_services() -> Vec<String>{
+    let mut services: Vec<String> = vec![];
+    for (pid, _) in gpu_processes() {
+        let Ok(cgroup) = std::fs::read_to_string(format!("/proc/{}/cgroup", pid)) else {continue;};
+        // cgroup v2 has a single line like 0::/system.slice/nvidia-persistenced.service
+        let service = cgroup.lines().filter_map(|line| line.rsplit(':').next()).find_map(|path| path.strip_prefix("/system.slice/"))
+            .and_then(|path| path.split('/').next()).filter(|unit| unit.ends_with(".service"));
+        if let Some(service) = service.filter(|service| !services.iter().any(|known| known == service)) {services.push(service.to_string());}
+    }
+    services
+}
+
+/// Stops every system service holding the gpu, tracking each so rc_gpu starts it again
+pub async fn stop_gpu_services(state: &SystemState, conn: Arc<SyncConnection>, dbus_timeout: Duration){
+    for service in gpu_services() {
+        log::info!("Stopping {}, which holds the gpu", service);
+        match system_unit_action(conn.clone(), &service, "stop", dbus_timeout).await {
+            Ok(_) => {
+                SystemState::track(&state.services_stopped, &service);
+                state.save();
+            },
+            Err(err) => {log::warn!("{}", err);}
         }
-        reset_dp = true; reset_pw = true;
     }
-    if state.nvidia_unloaded.2.load(Ordering::Relaxed) {
-        println!("Loading nvidia");
-        if let Err(err) = tokio::process::Command::new("modprobe").args(["nvidia_modeset"]).status().await{
-            errors.push(LauncherError::FailedToLoadKernelModule("nvidia_modeset".to_string(), err));
+}
+
+/// Runs action, "stop" or "start", on a system unit through the systemd manager, waiting up to UNIT_JOB_TIMEOUT for its job
+/// returns the ActiveState the unit is left in, e.g. "active" or "failed"
+pub async fn system_unit_action(conn: Arc<SyncConnection>, unit: &str, action: &str, dbus_timeout: Duration) -> Result<String, LauncherError>{
+    let err = |err: dbus::Error| LauncherError::UnitActionFailed(action.to_string(), unit.to_string(), err);
+    let manager = Proxy::new("org.freedesktop.systemd1", "/org/freedesktop/systemd1", dbus_timeout, conn.clone());
+    let (job,): (dbus::Path<'static>,) = manager.method_call("org.freedesktop.systemd1.Manager", unit_method(action), (unit, "replace")).await.map_err(err)?;
+    wait_for_jobs(conn.clone(), &[job], UNIT_JOB_TIMEOUT, dbus_timeout).await?;
+    // loaded rather than looked up, since a stopped unit can be unloaded once its job is done
+    let (path,): (dbus::Path<'static>,) = manager.method_call("org.freedesktop.systemd1.Manager", "LoadUnit", (unit,)).await.map_err(err)?;
+    Proxy::new("org.freedesktop.systemd1", path, dbus_timeout, conn).get::<String>("org.freedesktop.systemd1.Unit", "ActiveState").await.map_err(err)
+}
+
+/// attempts made to unload a module that is still in use
+pub const MODULE_UNLOAD_ATTEMPTS: usize = 5;
+/// delay between attempts to unload a module that is still in use
+pub const MODULE_UNLOAD_RETRY_DELAY: Duration = Duration::from_millis(500);
+
+/// Unloads a kernel module with modprobe. a module that isn't loaded is not an error
+/// a module that is still in use is retried, since gpu users can take a moment to let go
+pub async fn unload_module(runner: &dyn CommandRunner, module: &str) -> Result<(), LauncherError>{
+    for attempt in 1..=MODULE_UNLOAD_ATTEMPTS {
+        let out = runner.run("modprobe", &["-f", "-r", module]).await
+            .map_err(|err| LauncherError::FailedToUnloadKernelModule(module.to_string(), err))?;
+        let stderr = String::from_utf8_lossy(&out.stderr);
+        if stderr.is_empty() || stderr.contains("not found") {return Ok(());}
+        if !stderr.contains("in use") || attempt == MODULE_UNLOAD_ATTEMPTS {
+            return Err(LauncherError::ModprobeRemoveReturnedErr(module.to_string(), stderr.to_string()));
         }
-        reset_dp = true; reset_pw = true;
+        log::warn!("{} is still in use, retrying ({}/{})", module, attempt, MODULE_UNLOAD_ATTEMPTS);
+        tokio::time::sleep(MODULE_UNLOAD_RETRY_DELAY).await;
     }
-    if state.nvidia_unloaded.1.load(Ordering::Relaxed) {
-        println!("Loading nvidia");
-        if let Err(err) = tokio::process::Command::new("modprobe").args(["nvidia_drm"]).status().await{
-            errors.push(LauncherError::FailedToLoadKernelModule("nvidia_drm".to_string(), err));
+    Ok(())
+}
+
+/// how long a systemd job to stop or start a unit gets before it counts as hung
+pub const UNIT_JOB_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Waits until every systemd job in jobs has finished, which is when its object no longer exists. fails after timeout
+pub async fn wait_for_jobs(conn: Arc<SyncConnection>, jobs: &[dbus::Path<'_>], timeout: Duration, dbus_timeout: Duration) -> Result<(), LauncherError>{
+    let wait = async {
+        for job in jobs {
+            let proxy = Proxy::new("org.freedesktop.systemd1", job.clone(), dbus_timeout, conn.clone());
+            while proxy.get::<String>("org.freedesktop.systemd1.Job", "State").await.is_ok() {
+                tokio::time::sleep(Duration::from_millis(100)).await;
+            }
         }
-        reset_dp = true; reset_pw = true;
+    };
+    tokio::time::timeout(timeout, wait).await.map_err(|_| LauncherError::SystemdJobsTimedOut(timeout))
+}
+
+/// Reconnects the gpu, by doing any necessary steps as determined by state. errors are ignored, and returned at the end as a list
+pub async fn rc_gpu(state: Arc<SystemState>, conn: Arc<SyncConnection>, config: &Config) -> Vec<LauncherError> {
+    let mut errors: Vec<LauncherError> = vec![];
+    let mut reset_dp = false; let mut reset_pw = false;
+    // do any work to reconnect the gpu
+    let (unbind_errors, reset) = unbind_vfio(&state, &SystemRunner, Path::new(PCI_BUS_PATH), config).await;
+    errors.extend(unbind_errors);
+    if reset {reset_dp = true; reset_pw = true;}
+    let reattached = SystemState::tracked(&state.gpu_dettached);
+    // the display service can come up on a half initialized gpu, so give the drivers a chance to bind first
+    if !reattached.is_empty() {
+        log::info!("Waiting for the gpu to settle");
+        wait_for_host_drivers(Path::new(PCI_BUS_PATH), &reattached, Duration::from_secs(config.gpu_settle_secs)).await;
     }
-    if state.nvidia_unloaded.0.load(Ordering::Relaxed) {
-        println!("Loading nvidia");
-        if let Err(err) = tokio::process::Command::new("modprobe").args(["nvidia_uvm"]).status().await{
-            errors.push(LauncherError::FailedToLoadKernelModule("nvidia_uvm".to_string(), err));
+    for service in SystemState::tracked(&state.services_stopped).iter() {
+        log::info!("Starting {}", service);
+        match system_unit_action(conn.clone(), service, "start", config.dbus_timeout()).await {
+            Ok(active) if active == "active" => {},
+            Ok(active) => {cleanup_failed(&mut errors, LauncherError::ServiceNotStarted(service.clone(), active));},
+            Err(err) => {cleanup_failed(&mut errors, err);}
         }
-        reset_dp = true; reset_pw = true;
     }
     // if the dp or pw is not started, start it
     if state.dp_stopped.load(Ordering::Relaxed) {
-        println!("Starting Display Manager");
-        let proxy = Proxy::new("org.freedesktop.systemd1", "/org/freedesktop/systemd1", Duration::from_secs(2), conn.clone());
-        if let Err(err) = proxy.method_call::<(dbus::Path,), _, _, _>("org.freedesktop.systemd1.Manager", "StartUnit", ("display-manager.service", "replace")).await{
-            errors.push(LauncherError::FailedToStartDP(err));
+        log::info!("Starting {}", config.display_service);
+        if let Err(err) = display_service_action(conn.clone(), config, "start").await {
+            cleanup_failed(&mut errors, LauncherError::FailedToStartDP(err));
         }
         reset_dp = false;
     }
     if state.pw_stopped.load(Ordering::Relaxed) {
-        println!("Starting Pipewire");
-        let login_proxy = Proxy::new("org.freedesktop.login1", "/org/freedesktop/login1", Duration::from_secs(2), conn.clone());
-        match login_proxy.method_call::<(Vec<(u32, String, dbus::Path)>,), _, _, _>("org.freedesktop.login1.Manager", "ListUsers", ()).await{
-            Ok((users,)) => {
-                for (user, _, _) in users.iter(){
-                    let _ = tokio::process::Command::new("systemctl").args(["--user", &format!("--machine={}@", user), "start", "pipewire.socket"])
-                        .stderr(Stdio::null()).stdout(Stdio::null()).status().await;
-                    let _ = tokio::process::Command::new("systemctl").args(["--user", &format!("--machine={}@", user), "start", "pipewire-pulse.socket"])
-                        .stderr(Stdio::null()).stdout(Stdio::null()).status().await;
-                }
-            },
-            Err(err) => {errors.push(LauncherError::FailedToGetUsers(err));}
+        log::info!("Starting user units");
+        if let Err(err) = user_units_action(conn.clone(), &ordered_user_units(config, "start"), "start", config.dbus_timeout(), None).await {
+            cleanup_failed(&mut errors, LauncherError::FailedToGetUsers(err));
         }
         reset_pw = false;
     }
     // if we did any work to reconnect the gpu, restart dp
     if reset_pw {
-        println!("Resetting Pipewire");
-        let login_proxy = Proxy::new("org.freedesktop.login1", "/org/freedesktop/login1", Duration::from_secs(2), conn.clone());
-        match login_proxy.method_call::<(Vec<(u32, String, dbus::Path)>,), _, _, _>("org.freedesktop.login1.Manager", "ListUsers", ()).await{
-            Ok((users,)) => {
-                for (user, _, _) in users.iter(){
-                    let _ = tokio::process::Command::new("systemctl").args(["--user", &format!("--machine={}@", user), "restart", "pipewire.socket"])
-                        .stderr(Stdio::null()).stdout(Stdio::null()).status().await;
-                    let _ = tokio::process::Command::new("systemctl").args(["--user", &format!("--machine={}@", user), "restart", "pipewire-pulse.socket"])
-                        .stderr(Stdio::null()).stdout(Stdio::null()).status().await;
-                }
-            },
-            Err(err) => {errors.push(LauncherError::FailedToGetUsers(err));}
+        log::info!("Resetting user units");
+        if let Err(err) = user_units_action(conn.clone(), &ordered_user_units(config, "restart"), "restart", config.dbus_timeout(), None).await {
+            cleanup_failed(&mut errors, LauncherError::FailedToGetUsers(err));
         }
     }
-    if reset_dp {
-        println!("Resetting Display Manager");
-        let proxy = Proxy::new("org.freedesktop.systemd1", "/org/freedesktop/systemd1", Duration::from_secs(2), conn.clone());
-        if let Err(err) = proxy.method_call::<(dbus::Path,), _, _, _>("org.freedesktop.systemd1.Manager", "RestartUnit", ("display-manager.service", "replace")).await{
-            errors.push(LauncherError::FailedToRestartDP(err));
+    // a desktop on the igpu never lost the gpu it runs on
+    if reset_dp && !config.igpu_host {
+        log::info!("Resetting {}", config.display_service);
+        if let Err(err) = display_service_action(conn.clone(), config, "restart").await {
+            cleanup_failed(&mut errors, LauncherError::FailedToRestartDP(err));
         }
     }
     errors
 }
 
-/// Performance Enhancements, Virtual Mouse, Create Xml
-pub async fn setup_pc(state: Arc<SystemState>, conn: Arc<SyncConnection>, mouse_path: String, vm_type: VmType) -> Result<(), LauncherError>{
-    // set available gpu's
-    let proxy = Proxy::new(
-        "org.freedesktop.systemd1", 
-        "/org/freedesktop/systemd1/unit/user_2eslice", 
-        Duration::from_secs(2), conn.clone());
-    let _: () = proxy.method_call(
-        "org.freedesktop.systemd1.Unit", 
-        "SetProperties", 
-        (true, vec![("AllowedCPUs", Variant(vec![0_u8, 240_u8, 15_u8, 0_u8, 0_u8, 0_u8, 0_u8, 0_u8]))])
-    ).await.map_err(|err| LauncherError::FailedToSetCPUs(err))?;
-    state.cpus_limited.0.store(true, Ordering::Relaxed);
-    let proxy = Proxy::new(
-        "org.freedesktop.systemd1", 
-        "/org/freedesktop/systemd1/unit/system_2eslice", 
-        Duration::from_secs(2), conn.clone());
-    let _: () = proxy.method_call(
-        "org.freedesktop.systemd1.Unit", 
-        "SetProperties", 
-        (true, vec![("AllowedCPUs", Variant(vec![0_u8, 240_u8, 15_u8, 0_u8, 0_u8, 0_u8, 0_u8, 0_u8]))])
-    ).await.map_err(|err| LauncherError::FailedToSetCPUs(err))?;
-    state.cpus_limited.1.store(true, Ordering::Relaxed);
-    let proxy = Proxy::new(
-        "org.freedesktop.systemd1", 
-        "/org/freedesktop/systemd1/unit/unit_2escope", 
-        Duration::from_secs(2), conn.clone());
-    let _: () = proxy.method_call(
+/// cpus the host is limited to while the vm is running, by default
+pub const HOST_ALLOWED_CPUS: &str = "12-19";
+/// cpus the host is given back after the vm closes, by default
+pub const DEFAULT_ALLOWED_CPUS: &str = "0-19";
+
+/// Parses a cpu list like "0-3,8" into the list of cpu indices it contains
+pub fn parse_cpu_list(list: &str) -> Result<Vec<usize>, LauncherError>{
+    let mut cpus = vec![];
+    for part in list.split(',').map(|part| part.trim()).filter(|part| !part.is_empty()) {
+        let parse = |num: &str| num.trim().parse::<usize>().map_err(|_| LauncherError::InvalidCpuList(list.to_string()));
+        match part.split_once('-') {
+            Some((start, end)) => {
+                let (start, end) = (parse(start)?, parse(end)?);
+                if start > end {return Err(LauncherError::InvalidCpuList(list.to_string()));}
+                cpus.extend(start..=end);
+            },
+            None => {cpus.push(parse(part)?);}
+        }
+    }
+    Ok(cpus)
+}
+
+/// lists the cpus the kernel has online, like 0-15
+pub const ONLINE_CPUS_PATH: &str = "/sys/devices/system/cpu/online";
+
+/// Checks host_cpu_mask and default_cpu_mask against the online cpus, so a typo can't pin the host to no cpus at all
+/// if the online cpus can't be read, only the emptiness checks are done
+pub fn check_cpu_masks(config: &Config) -> Result<(), LauncherError>{
+    let online = match std::fs::read_to_string(ONLINE_CPUS_PATH) {
+        Ok(list) => Some(parse_cpu_list(list.trim())?),
+        Err(err) => {log::warn!("Could not read {}, not checking the cpu masks against it: {}", ONLINE_CPUS_PATH, err); None}
+    };
+    validate_cpu_masks(config, online.as_deref())
+}
+
+/// Checks that host_cpu_mask and default_cpu_mask are non empty sets of online cpus, and that isolate_cpus leaves the vm some cpus
+pub fn validate_cpu_masks(config: &Config, online: Option<&[usize]>) -> Result<(), LauncherError>{
+    for (name, list) in [("host_cpu_mask", &config.host_cpu_mask), ("default_cpu_mask", &config.default_cpu_mask)] {
+        let cpus = parse_cpu_list(list)?;
+        if cpus.is_empty() {return Err(LauncherError::InvalidCpuMask(format!("{} is empty", name)));}
+        if let Some(offline) = online.and_then(|online| cpus.iter().find(|cpu| !online.contains(cpu))) {
+            return Err(LauncherError::InvalidCpuMask(format!("{} {} includes cpu {}, which is not online", name, list, offline)));
+        }
+    }
+    if config.isolate_cpus && vm_cpus(config)?.is_empty() {
+        return Err(LauncherError::InvalidCpuMask(format!("default_cpu_mask {} has no cpus outside host_cpu_mask {} to isolate", config.default_cpu_mask, config.host_cpu_mask)));
+    }
+    Ok(())
+}
+
+/// Converts a set of cpu indices into the little endian bitmask systemd expects for AllowedCPUs
+pub fn cpu_mask(cpus: &[usize]) -> Vec<u8>{
+    let len = cpus.iter().max().map_or(0, |max| max / 8 + 1).max(8);
+    let mut mask = vec![0_u8; len];
+    for cpu in cpus {mask[cpu / 8] |= 1 << (cpu % 8);}
+    mask
+}
+
+/// Sets the AllowedCPUs property of the systemd unit at unit_path
+pub async fn set_allowed_cpus(conn: Arc<SyncConnection>, unit_path: &str, mask: Vec<u8>, dbus_timeout: Duration) -> Result<(), LauncherError>{
+    let proxy = Proxy::new("org.freedesktop.systemd1", unit_path, dbus_timeout, conn);
+    proxy.method_call::<(), _, _, _>(
         "org.freedesktop.systemd1.Unit", 
         "SetProperties", 
-        (true, vec![("AllowedCPUs", Variant(vec![0_u8, 240_u8, 15_u8, 0_u8, 0_u8, 0_u8, 0_u8, 0_u8]))])
-    ).await.map_err(|err| LauncherError::FailedToSetCPUs(err))?;
-    state.cpus_limited.2.store(true, Ordering::Relaxed);
-    // Set cpu governor
-    let mut files = Path::new("/sys/devices/system/cpu/").read_dir().map_err(|err| LauncherError::FailedToReadCPUDir(err))?
-        .into_iter().flatten().filter_map(|dir| {
-            if dir.file_type().unwrap().is_file() || !dir.file_name().to_str().unwrap().starts_with("cpu") {return None;}
-            File::create(dir.path().join("cpufreq/scaling_governor")).ok()
-        }).collect::<Vec<File>>();
-    for file in files.iter_mut(){
-        let _ = file.write("performance".as_bytes());
-    }
-    state.performance_governor.store(true, Ordering::Relaxed);
-    // create virtual mouse
-    let proxy = Proxy::new(
-        "org.cws.VirtualMouse", 
-        "/org/cws/VirtualMouse", 
-        Duration::from_secs(2), conn.clone());
-    let (_, _, outputpath): (String, String, String) = proxy.method_call(
-        "org.cws.VirtualMouse.Manager", 
-        "CreateMouse", 
-        ("WindowsMouse", mouse_path)
-    ).await.map_err(|err| LauncherError::FailedToCreateMouse(err))?;
-    state.virtual_mouse_create.store(true, Ordering::Relaxed);
-    // create xml
-    let xml_source_path = match vm_type {
-        VmType::LookingGlass => {std::env::var("WINDOWS_LG_XML")},
-        VmType::Spice => {std::env::var("WINDOWS_SPICE_XML")}
-    }.map_err(|err| LauncherError::FailedToGetXmlPath(err))?;
+        (true, vec![("AllowedCPUs", Variant(mask))])
+    ).await.map_err(LauncherError::FailedToSetCPUs)
+}
+
+/// Returns the scaling_governor file of every cpufreq policy
+/// falls back to the per cpu files on systems without /sys/devices/system/cpu/cpufreq/policyN
+pub fn governor_files() -> Result<Vec<PathBuf>, LauncherError>{
+    let mut files = match Path::new("/sys/devices/system/cpu/cpufreq/").read_dir() {
+        Ok(dir) => dir.flatten().filter(|entry| entry.file_name().to_string_lossy().starts_with("policy"))
+            .map(|entry| entry.path().join("scaling_governor")).collect::<Vec<PathBuf>>(),
+        Err(_) => vec![]
+    };
+    if files.is_empty() {
+        files = Path::new("/sys/devices/system/cpu/").read_dir().map_err(LauncherError::FailedToReadCPUDir)?
+            .flatten().filter(|entry| {
+                let name = entry.file_name().to_string_lossy().to_string();
+                name.strip_prefix("cpu").is_some_and(|num| num.parse::<usize>().is_ok())
+            }).map(|entry| entry.path().join("cpufreq/scaling_governor")).collect();
+    }
+    files.sort();
+    Ok(files)
+}
+
+/// Reads the current governor from a scaling_governor file. failures are logged, and give None
+pub fn read_governor(file: &Path) -> Option<String>{
+    match std::fs::read_to_string(file) {
+        Ok(governor) => Some(governor.trim().to_string()),
+        Err(err) => {
+            log::warn!("Skipping {}, could not read the governor: {}", file.display(), err);
+            None
+        }
+    }
+}
+
+/// Writes governor to each scaling_governor file, returning the files that were written
+/// files that are missing or can't be written are logged and skipped
+pub fn write_governor(files: &[PathBuf], governor: &str) -> Vec<PathBuf>{
+    let mut written = vec![];
+    for file in files {
+        if !file.exists() {
+            log::warn!("Skipping {}, it does not exist", file.display());
+            continue;
+        }
+        match OpenOptions::new().write(true).open(file).and_then(|mut handle| handle.write_all(governor.as_bytes())) {
+            Ok(()) => {written.push(file.clone());},
+            Err(err) => {log::warn!("Skipping {}, could not write the governor: {}", file.display(), err);}
+        }
+    }
+    written
+}
+
+/// Writes governor to every cpufreq policy on a blocking thread, since there can be hundreds of sysfs files
+/// returns each file written along with the governor it had before
+pub async fn set_governor(governor: String) -> Result<Vec<(String, String)>, LauncherError>{
+    tokio::task::spawn_blocking(move || {
+        let mut originals = vec![];
+        for file in governor_files()? {
+            let Some(original) = read_governor(&file) else {continue;};
+            if write_governor(std::slice::from_ref(&file), &governor).is_empty() {continue;}
+            originals.push((file.to_string_lossy().to_string(), original));
+        }
+        Ok(originals)
+    }).await.map_err(LauncherError::GovernorTaskFailed)?
+}
+
+/// Writes each file's original governor back on a blocking thread
+pub async fn restore_governors(originals: Vec<(String, String)>) -> Result<(), LauncherError>{
+    tokio::task::spawn_blocking(move || {
+        for (file, original) in originals {
+            write_governor(&[PathBuf::from(file)], &original);
+        }
+    }).await.map_err(LauncherError::GovernorTaskFailed)
+}
+
+/// cgroup v2 partition file of the slice libvirt starts vms in
+pub const MACHINE_PARTITION_PATH: &str = "/sys/fs/cgroup/machine.slice/cpuset.cpus.partition";
+
+/// Returns the cpus given to the vm: those in default_cpu_mask that aren't in host_cpu_mask
+pub fn vm_cpus(config: &Config) -> Result<Vec<usize>, LauncherError>{
+    let host = parse_cpu_list(&config.host_cpu_mask)?;
+    Ok(parse_cpu_list(&config.default_cpu_mask)?.into_iter().filter(|cpu| !host.contains(cpu)).collect())
+}
+
+/// Points every irq that allows it at cpus on a blocking thread, returning each file changed along with its original cpu list
+/// some irqs, like per cpu timers, can't be moved and are skipped
+pub async fn set_irq_affinity(cpus: String) -> Result<Vec<(String, String)>, LauncherError>{
+    tokio::task::spawn_blocking(move || {
+        let mut originals = vec![];
+        let Ok(irqs) = Path::new("/proc/irq").read_dir() else {return originals;};
+        for irq in irqs.flatten().filter(|entry| entry.file_name().to_string_lossy().parse::<u32>().is_ok()) {
+            let file = irq.path().join("smp_affinity_list");
+            let Ok(original) = std::fs::read_to_string(&file) else {continue;};
+            if original.trim() == cpus || std::fs::write(&file, &cpus).is_err() {continue;}
+            originals.push((file.to_string_lossy().to_string(), original.trim().to_string()));
+        }
+        originals
+    }).await.map_err(LauncherError::IsolationTaskFailed)
+}
+
+/// Keeps host work off the vm cpus: moves irqs onto the host cpus, and makes machine.slice an isolated partition of the vm cpus
+pub async fn isolate_vm_cpus(state: Arc<SystemState>, conn: Arc<SyncConnection>, config: &Config) -> Result<(), LauncherError>{
+    let vm = vm_cpus(config)?;
+    if vm.is_empty() {
+        log::warn!("host_cpu_mask covers every cpu in default_cpu_mask, there are no vm cpus to isolate");
+        return Ok(());
+    }
+    let host = parse_cpu_list(&config.host_cpu_mask)?.iter().map(|cpu| cpu.to_string()).collect::<Vec<String>>().join(",");
+    log::info!("Moving irqs onto cpus {}", host);
+    let originals = set_irq_affinity(host).await?;
+    state.irq_originals.lock_or_recover().extend(originals);
+    state.save();
+    log::info!("Isolating the vm cpus in machine.slice");
+    set_allowed_cpus(conn, "/org/freedesktop/systemd1/unit/machine_2eslice", cpu_mask(&vm), config.dbus_timeout()).await?;
+    state.machine_isolated.store(true, Ordering::Relaxed);
+    state.save();
+    // the kernel accepts the write but reports an invalid partition when it can't honor it
+    match std::fs::write(MACHINE_PARTITION_PATH, "isolated").and_then(|_| std::fs::read_to_string(MACHINE_PARTITION_PATH)) {
+        Ok(partition) if partition.trim() == "isolated" => {},
+        Ok(partition) => {log::warn!("machine.slice could not be made an isolated partition: {}", partition.trim());},
+        Err(err) => {log::warn!("Could not write {}, the vm cpus are only limited by AllowedCPUs: {}", MACHINE_PARTITION_PATH, err);}
+    }
+    Ok(())
+}
+
+/// Undoes isolate_vm_cpus, returning any errors
+pub async fn release_vm_cpus(state: Arc<SystemState>, conn: Arc<SyncConnection>, config: &Config) -> Vec<LauncherError>{
+    let mut errors = vec![];
+    let originals = state.irq_originals.lock_or_recover().clone();
+    let restored = tokio::task::spawn_blocking(move || {
+        for (file, original) in originals {
+            if let Err(err) = std::fs::write(&file, original) {log::warn!("Could not restore {}: {}", file, err);}
+        }
+    }).await;
+    if let Err(err) = restored {errors.push(LauncherError::IsolationTaskFailed(err));}
+    if state.machine_isolated.load(Ordering::Relaxed) {
+        if Path::new(MACHINE_PARTITION_PATH).exists() {
+            if let Err(err) = std::fs::write(MACHINE_PARTITION_PATH, "member") {log::warn!("Could not restore {}: {}", MACHINE_PARTITION_PATH, err);}
+        }
+        match parse_cpu_list(&config.default_cpu_mask).map(|cpus| cpu_mask(&cpus)) {
+            Ok(mask) => {
+                if let Err(err) = set_allowed_cpus(conn, "/org/freedesktop/systemd1/unit/machine_2eslice", mask, config.dbus_timeout()).await {errors.push(err);}
+            },
+            Err(err) => {errors.push(err);}
+        }
+    }
+    errors
+}
+
+/// Reverts every change a launch can make to the host, whether or not the server thinks it was made
+/// used to fix a wedged host by hand, so errors are collected and every step is attempted
+pub async fn recover(conn: Arc<SyncConnection>, config: &Config) -> Vec<LauncherError>{
+    let mut errors: Vec<LauncherError> = vec![];
+    let state = assume_detached(config);
+    log::info!("Restoring governor and cpus");
+    // a state file left by the server has the governors from before the launch
+    let saved = SystemState::default();
+    saved.restore_saved();
+    let originals = saved.governor_originals();
+    if !originals.is_empty() {
+        if let Err(err) = restore_governors(originals).await {cleanup_failed(&mut errors, err);}
+    } else {
+        log::warn!("No saved governors to restore, falling back to powersave");
+        let powersave = tokio::task::spawn_blocking(|| governor_files().map(|files| {write_governor(&files, "powersave");})).await;
+        match powersave.map_err(LauncherError::GovernorTaskFailed) {
+            Ok(Ok(())) => {},
+            Ok(Err(err)) | Err(err) => {cleanup_failed(&mut errors, err);}
+        }
+    }
+    match parse_cpu_list(&config.default_cpu_mask).map(|cpus| cpu_mask(&cpus)) {
+        Err(err) => {cleanup_failed(&mut errors, err);},
+        Ok(mask) => {
+            for unit in ["user_2eslice", "system_2eslice", "unit_2escope"] {
+                if let Err(err) = set_allowed_cpus(conn.clone(), &format!("/org/freedesktop/systemd1/unit/{}", unit), mask.clone(), config.dbus_timeout()).await {cleanup_failed(&mut errors, err);}
+            }
+            // undo isolate_cpus, the irqs themselves are left where they are
+            if config.isolate_cpus {
+                let _ = std::fs::write(MACHINE_PARTITION_PATH, "member");
+                if let Err(err) = set_allowed_cpus(conn.clone(), "/org/freedesktop/systemd1/unit/machine_2eslice", mask.clone(), config.dbus_timeout()).await {cleanup_failed(&mut errors, err);}
+            }
+        }
+    }
+    log::info!("Reconnecting gpu");
+    errors.extend(rc_gpu(state.clone(), conn, config).await);
+    // also clears any state file a dead server left behind
+    state.revert();
+    errors
+}
+
+/// Gives uid ownership of the looking glass shared memory at path with the given mode, recording how to undo it
+/// a /dev/shm file is created if missing, a kvmfr device has to exist already
+pub fn setup_lg_shm(state: Arc<SystemState>, path: &str, uid: u32, mode: u32) -> Result<(), LauncherError>{
+    let err = |err: std::io::Error| LauncherError::FailedToSetupLgShm(path.to_string(), err);
+    let file = match std::fs::symlink_metadata(path) {
+        Ok(_) => {
+            let file = open_lg_shm(path, false).map_err(err)?;
+            let meta = file.metadata().map_err(err)?;
+            *state.shm_original.lock_or_recover() = Some((path.to_string(), meta.uid(), meta.mode() & 0o7777));
+            file
+        },
+        Err(_) if path.starts_with("/dev/kvmfr") => {return Err(LauncherError::LgShmMissing(path.to_string()));},
+        Err(_) => {
+            let file = open_lg_shm(path, true).map_err(err)?;
+            *state.shm_created.lock_or_recover() = Some(path.to_string());
+            file
+        }
+    };
+    state.save();
+    log::info!("Giving uid {} {} with mode {:o}", uid, path, mode);
+    // changed through the fd, so the path can't be swapped for a symlink in between
+    std::os::unix::fs::fchown(&file, Some(uid), None).map_err(err)?;
+    file.set_permissions(std::fs::Permissions::from_mode(mode)).map_err(err)?;
+    Ok(())
+}
+
+/// Opens the shared memory without following symlinks, refusing anything but a regular file or char device. create only makes a new file
+fn open_lg_shm(path: &str, create: bool) -> std::io::Result<File>{
+    let allowed = |file_type: std::fs::FileType| file_type.is_file() || file_type.is_char_device();
+    let not_allowed = || std::io::Error::new(std::io::ErrorKind::InvalidInput, "not a regular file or character device");
+    if !create && !allowed(std::fs::symlink_metadata(path)?.file_type()) {return Err(not_allowed());}
+    let mut options = OpenOptions::new();
+    // nonblock so a fifo swapped in can't hang the open
+    options.read(true).custom_flags(nix::libc::O_NOFOLLOW | nix::libc::O_NONBLOCK);
+    if create {options.write(true).create_new(true).mode(0o600);}
+    let file = options.open(path)?;
+    if !allowed(file.metadata()?.file_type()) {return Err(not_allowed());}
+    Ok(file)
+}
+
+/// Removes the shared memory file we created, or gives an existing device back its owner and mode. failures are only logged
+pub fn restore_lg_shm(state: Arc<SystemState>){
+    let created = state.shm_created.lock_or_recover().clone();
+    if let Some(path) = created {
+        log::info!("Removing {}", path);
+        // removing a symlink put in its place only removes the link
+        if let Err(err) = std::fs::remove_file(&path) {log::warn!("Could not remove {}: {}", path, err);}
+    }
+    let original = state.shm_original.lock_or_recover().clone();
+    if let Some((path, uid, mode)) = original {
+        log::info!("Restoring the owner and mode of {}", path);
+        match open_lg_shm(&path, false) {
+            Ok(file) => {
+                if let Err(err) = std::os::unix::fs::fchown(&file, Some(uid), None) {log::warn!("Could not restore the owner of {}: {}", path, err);}
+                if let Err(err) = file.set_permissions(std::fs::Permissions::from_mode(mode)) {log::warn!("Could not restore the mode of {}: {}", path, err);}
+            },
+            Err(err) => {log::warn!("Could not open {} to restore it: {}", path, err);}
+        }
+    }
+}
+
+/// Reserves count hugepages of size_kb, recording the previous count. fails if the kernel can't find enough free memory
+pub fn reserve_hugepages(state: Arc<SystemState>, count: u64, size_kb: u64) -> Result<(), LauncherError>{
+    let path = format!("/sys/kernel/mm/hugepages/hugepages-{}kB/nr_hugepages", size_kb);
+    let err = |err: std::io::Error| LauncherError::FailedToReserveHugepages(path.clone(), err);
+    let read = || std::fs::read_to_string(&path).map_err(err)?.trim().parse::<u64>()
+        .map_err(|parse| err(std::io::Error::new(std::io::ErrorKind::InvalidData, parse)));
+    let original = read()?;
+    if original >= count {
+        log::info!("{} hugepages of {}kB are already reserved", original, size_kb);
+        return Ok(());
+    }
+    *state.hugepages_original.lock_or_recover() = Some((path.clone(), original));
+    state.save();
+    log::info!("Reserving {} hugepages of {}kB", count, size_kb);
+    // compacting first gives the kernel a better chance of finding contiguous memory
+    let _ = std::fs::write("/proc/sys/vm/compact_memory", "1");
+    std::fs::write(&path, count.to_string()).map_err(err)?;
+    let reserved = read()?;
+    if reserved < count {return Err(LauncherError::HugepagesUnavailable(count, reserved));}
+    Ok(())
+}
+
+/// Sets the hugepage count back to what it was before reserve_hugepages. failures are only logged
+pub fn restore_hugepages(state: Arc<SystemState>){
+    let original = state.hugepages_original.lock_or_recover().clone();
+    if let Some((path, count)) = original {
+        log::info!("Restoring {} to {}", path, count);
+        if let Err(err) = std::fs::write(&path, count.to_string()) {log::warn!("Could not restore {}: {}", path, err);}
+    }
+}
+
+/// Returns a state with every configured device and module marked as handed to vfio
+/// dp and pw are left marked running, so rc_gpu restarts them
+fn assume_detached(config: &Config) -> Arc<SystemState>{
+    let state = Arc::new(SystemState::default());
+    state.vfio_loaded.store(config.manage_vfio, Ordering::Relaxed);
+    for module in config.nvidia_modules.iter() {SystemState::track(&state.nvidia_unloaded, module);}
+    for pci in config.gpu_pci_ids.iter() {SystemState::track(&state.gpu_dettached, pci);}
+    for module in config.extra_modules.iter() {SystemState::track(&state.extra_unloaded, module);}
+    for pci in config.extra_pci_ids.iter() {SystemState::track(&state.extra_detached, pci);}
+    state
+}
+
+/// Hands the configured gpu and extra devices to vfio-pci without launching a vm, for testing the passthrough setup
+/// the state file is left behind, so attach_gpu or a restarted server can undo it. a failed detach is undone straight away
+pub async fn detach_gpu(conn: Arc<SyncConnection>, config: &Config) -> Result<(), LauncherError>{
+    let state = Arc::new(SystemState::default());
+    if state.restore_saved() {return Err(LauncherError::HostAlreadyModified);}
+    if let Err(err) = dc_gpu_lg(state.clone(), conn.clone(), config).await {
+        log::error!("Detaching failed, reattaching: {}", err);
+        if let Err(rc_err) = LauncherError::from_cleanup(rc_gpu(state.clone(), conn, config).await) {log::error!("{}", rc_err);}
+        state.revert();
+        return Err(err);
+    }
+    Ok(())
+}
+
+/// Gives the gpu back to the host after detach_gpu. without a state file, every configured device is assumed detached
+pub async fn attach_gpu(conn: Arc<SyncConnection>, config: &Config) -> Vec<LauncherError>{
+    let state = Arc::new(SystemState::default());
+    let state = if state.restore_saved() {state} else {
+        log::warn!("No state file found at {}, reattaching every configured device", STATE_FILE_PATH);
+        assume_detached(config)
+    };
+    let errors = rc_gpu(state.clone(), conn, config).await;
+    state.revert();
+    errors
+}
+
+/// token in the vm xml which is replaced with the virtual mouse event path
+pub const MOUSE_PLACEHOLDER: &str = "VIRTUAL_MOUSE_EVENT_PATH";
+
+/// Returns the pci hostdevs in a domain xml, named like libvirt node devices, e.g. pci_0000_01_00_0
+pub fn hostdev_pci_ids(xml: &str) -> Vec<String>{
+    let mut ids = vec![];
+    for hostdev in xml.split("<hostdev").skip(1) {
+        let hostdev = hostdev.split("</hostdev>").next().unwrap_or_default();
+        if !hostdev.contains("type='pci'") && !hostdev.contains("type=\"pci\"") {continue;}
+        // the guest side address comes after the source, so only look inside it
+        let Some(source) = hostdev.split("<source").nth(1).and_then(|source| source.split("</source>").next()) else {continue;};
+        let Some(address) = source.split("<address").nth(1).and_then(|address| address.split('>').next()) else {continue;};
+        let attr = |name: &str| -> Option<u64> {
+            let value = address.split(&format!("{}=", name)).nth(1)?;
+            let value = value.trim_start_matches(['\'', '"']).split(['\'', '"']).next()?;
+            u64::from_str_radix(value.trim_start_matches("0x"), 16).ok()
+        };
+        if let (Some(domain), Some(bus), Some(slot), Some(function)) = (attr("domain"), attr("bus"), attr("slot"), attr("function")) {
+            ids.push(format!("pci_{:04x}_{:02x}_{:02x}_{:x}", domain, bus, slot, function));
+        }
+    }
+    ids
+}
+
+/// Returns the domain xml with a virtio tablet input, adding one at the end of its devices if it has none
+pub fn with_virtio_tablet(xml: &str) -> Result<String, LauncherError>{
+    let has_tablet = xml.split("<input").skip(1)
+        .map(|input| input.split('>').next().unwrap_or_default())
+        .any(|input| (input.contains("type='tablet'") || input.contains("type=\"tablet\"")) && (input.contains("bus='virtio'") || input.contains("bus=\"virtio\"")));
+    if has_tablet {return Ok(xml.to_string());}
+    let end = xml.rfind("</devices>").ok_or(LauncherError::XmlMissingDevices)?;
+    Ok(format!("{}  <input type='tablet' bus='virtio'/>\n  {}", &xml[..end], &xml[end..]))
+}
+
+/// Replaces every token in text with its value in a single pass, so values are never substituted again
+/// where tokens overlap, the longest one wins
+pub fn substitute(text: &str, substitutions: &HashMap<String, String>) -> String{
+    let mut tokens = substitutions.iter().filter(|(token, _)| !token.is_empty()).collect::<Vec<(&String, &String)>>();
+    tokens.sort_by_key(|(token, _)| std::cmp::Reverse(token.len()));
+    let mut output = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(next) = rest.chars().next() {
+        match tokens.iter().find(|(token, _)| rest.starts_with(token.as_str())) {
+            Some((token, value)) => {
+                output.push_str(value);
+                rest = &rest[token.len()..];
+            },
+            None => {
+                output.push(next);
+                rest = &rest[next.len_utf8()..];
+            }
+        }
+    }
+    output
+}
+
+/// attempts made to create the virtual mouse, since the VirtualMouse service may still be starting right after boot
+pub const CREATE_MOUSE_ATTEMPTS: usize = 4;
+/// delay before the first retry of CreateMouse, doubled after each failed attempt
+pub const CREATE_MOUSE_RETRY_DELAY: Duration = Duration::from_millis(500);
+
+/// Creates a virtual mouse named mouse_name from the pointer device at mouse_path, returning its event path
+/// failures are retried with backoff, and only the last one is returned
+async fn create_mouse(proxy: &Proxy<'_, Arc<SyncConnection>>, mouse_name: &str, mouse_path: &str) -> Result<String, LauncherError>{
+    let mut delay = CREATE_MOUSE_RETRY_DELAY;
+    let mut attempt = 1;
+    loop {
+        let result: Result<(String, String, String), dbus::Error> = proxy.method_call("org.cws.VirtualMouse.Manager", "CreateMouse", (mouse_name, mouse_path)).await;
+        let err = match result {
+            Ok((_, _, outputpath)) => {return Ok(outputpath);},
+            Err(err) => err
+        };
+        if attempt == CREATE_MOUSE_ATTEMPTS {
+            return Err(match err.name() {
+                Some("org.freedesktop.DBus.Error.ServiceUnknown") | Some("org.freedesktop.DBus.Error.NameHasNoOwner") => LauncherError::VirtualMouseServiceMissing,
+                _ => LauncherError::FailedToCreateMouse(err)
+            });
+        }
+        log::warn!("Could not create the virtual mouse, retrying in {:?} ({}/{}): {}", delay, attempt, CREATE_MOUSE_ATTEMPTS, err);
+        tokio::time::sleep(delay).await;
+        delay *= 2;
+        attempt += 1;
+    }
+}
+
+/// directory the generated domain xml files are written to. shared with the state file and status socket, so only writable by root
+pub const XML_DIR: &str = "/run/windows-vm-launcher";
+
+/// returns where the generated xml of the domain vm_name is written, one file per domain so launches of different domains don't collide
+pub fn xml_output_path(vm_name: &str) -> PathBuf{
+    Path::new(XML_DIR).join(format!("{}.xml", vm_name.replace('/', "_")))
+}
+
+/// writes the generated domain xml to path, readable only by root, creating its directory if needed
+/// unlike /tmp, the directory is not world writable, so nobody else can swap the definition before virsh reads it
+pub fn write_xml(path: &Path, xml: &str) -> std::io::Result<()>{
+    // the directory is shared with the status socket, so its mode is only set when we create it
+    if let Some(dir) = path.parent().filter(|dir| !dir.exists()) {
+        std::fs::create_dir_all(dir)?;
+        std::fs::set_permissions(dir, std::fs::Permissions::from_mode(0o755))?;
+    }
+    let mut file = OpenOptions::new().write(true).create(true).truncate(true).mode(0o600).open(path)?;
+    // mode only applies to a new file, an existing one is tightened through the fd
+    file.set_permissions(std::fs::Permissions::from_mode(0o600))?;
+    file.write_all(xml.as_bytes())
+}
+
+/// Reads the domain xml template for vm_type and checks it can be launched, so a bad template fails before the host is touched
+/// the virtio tablet is added here, the mouse and xml_substitutions are filled in by setup_pc
+pub fn read_xml_template(vm_type: &VmType, config: &Config) -> Result<String, LauncherError>{
+    let xml_source_path = config.xml_path(vm_type).ok_or(LauncherError::FailedToGetXmlPath(vm_type.clone()))?;
     let mut xml_string = String::with_capacity(10000);
     match File::open(xml_source_path.clone()).map(|mut file| file.read_to_string(&mut xml_string)) {
         Ok(Ok(_)) => {},
         Ok(Err(err)) => {return Err(LauncherError::FailedToReadXmlPath(xml_source_path, err));}
         Err(err) => {return Err(LauncherError::FailedToReadXmlPath(xml_source_path, err));}
     };
-    xml_string = xml_string.replace("VIRTUAL_MOUSE_EVENT_PATH", &outputpath);
-    match File::create("/tmp/windows.xml").map(|mut file| file.write(xml_string.as_bytes())) {
-        Ok(Ok(_)) => {},
-        Ok(Err(err)) => {return Err(LauncherError::FailedToCreateXmlFile(err));}
-        Err(err) => {return Err(LauncherError::FailedToCreateXmlFile(err));}
+    for pci in hostdev_pci_ids(&xml_string) {
+        if !config.gpu_pci_ids.contains(&pci) && !config.extra_pci_ids.contains(&pci) {log::warn!("The vm xml passes through {}, which is not a configured passthrough device", pci);}
+    }
+    if config.virtual_mouse(vm_type) && !xml_string.contains(MOUSE_PLACEHOLDER) {
+        return Err(LauncherError::XmlMissingPlaceholder(MOUSE_PLACEHOLDER.to_string()));
+    }
+    if config.virtio_tablet(vm_type) {
+        if xml_string.contains(MOUSE_PLACEHOLDER) {log::warn!("The vm xml contains {}, but input_mode is virtio_tablet so no virtual mouse is created for it", MOUSE_PLACEHOLDER);}
+        xml_string = with_virtio_tablet(&xml_string)?;
+    }
+    Ok(xml_string)
+}
+
+/// Performance Enhancements, Virtual Mouse, Create Xml
+/// user is the uid of the first session to connect, which gets the looking glass shared memory. returns the path of the generated xml
+/// xml is the template from read_xml_template, which gets the mouse and xml_substitutions filled in
+pub async fn setup_pc(state: Arc<SystemState>, conn: Arc<SyncConnection>, mouse_path: String, vm_type: VmType, user: Option<u32>, xml: String, config: &Config) -> Result<PathBuf, LauncherError>{
+    // a lite launch leaves the host alone. neither it nor a direct launch has a virtual mouse, and neither does a virtio tablet launch
+    let lite = vm_type == VmType::SpiceLite;
+    let has_mouse = config.virtual_mouse(&vm_type);
+    if !lite {
+        // set available cpu's
+        let mask = cpu_mask(&parse_cpu_list(&config.host_cpu_mask)?);
+        set_allowed_cpus(conn.clone(), "/org/freedesktop/systemd1/unit/user_2eslice", mask.clone(), config.dbus_timeout()).await?;
+        state.cpus_limited.0.store(true, Ordering::Relaxed);
+        state.save();
+        set_allowed_cpus(conn.clone(), "/org/freedesktop/systemd1/unit/system_2eslice", mask.clone(), config.dbus_timeout()).await?;
+        state.cpus_limited.1.store(true, Ordering::Relaxed);
+        state.save();
+        set_allowed_cpus(conn.clone(), "/org/freedesktop/systemd1/unit/unit_2escope", mask, config.dbus_timeout()).await?;
+        state.cpus_limited.2.store(true, Ordering::Relaxed);
+        state.save();
+        // Set cpu governor, remembering the original of each file so it can be restored
+        let originals = set_governor(config.vm_governor.clone()).await?;
+        state.governor_originals.lock_or_recover().extend(originals);
+        state.save();
+        if config.isolate_cpus {isolate_vm_cpus(state.clone(), conn.clone(), config).await?;}
+    }
+    // create virtual mouse
+    let mouse_event = if !has_mouse {None} else {
+        let proxy = Proxy::new(
+            "org.cws.VirtualMouse", 
+            "/org/cws/VirtualMouse", 
+            config.dbus_timeout(), conn.clone());
+        // a unique name can't clash with a mouse a crashed launch never destroyed
+        let mouse_name = if config.mouse_name_unique {format!("{}-{}", config.mouse_name, chrono::Local::now().timestamp())} else {config.mouse_name.clone()};
+        let outputpath = create_mouse(&proxy, &mouse_name, &mouse_path).await?;
+        state.virtual_mouse_create.store(true, Ordering::Relaxed);
+        *state.mouse_name.lock_or_recover() = mouse_name;
+        state.save();
+        Some(outputpath)
     };
-    Ok(())
+    // hand the shared memory to the user running looking-glass-client
+    if vm_type == VmType::LookingGlass {
+        if let Some(path) = config.lg_shm_path.as_ref().filter(|path| !path.is_empty()) {
+            match user {
+                Some(uid) => {setup_lg_shm(state.clone(), path, uid, config.lg_shm_mode)?;},
+                None => {log::warn!("No user has connected, leaving {} as it is", path);}
+            }
+        }
+    }
+    // a domain backed by hugepages can't be created until they are reserved
+    if config.hugepages > 0 {reserve_hugepages(state.clone(), config.hugepages, config.hugepage_size_kb)?;}
+    // fill in the xml
+    let mut substitutions = config.xml_substitutions.iter()
+        .map(|(name, value)| (format!("{{{{{}}}}}", name), value.clone())).collect::<HashMap<String, String>>();
+    if let Some(outputpath) = mouse_event {substitutions.insert(MOUSE_PLACEHOLDER.to_string(), outputpath);}
+    let xml_string = substitute(&xml, &substitutions);
+    if let Some(token) = xml_string.split("{{").nth(1).and_then(|rest| rest.split("}}").next()) {
+        log::warn!("The vm xml contains {{{{{}}}}}, which has no value in xml_substitutions", token);
+    }
+    let xml_path = xml_output_path(&state.vm_name());
+    write_xml(&xml_path, &xml_string).map_err(|err| LauncherError::FailedToCreateXmlFile(xml_path.clone(), err))?;
+    Ok(xml_path)
 }
 
-/// Launch vm
-pub async fn start_vm(state: Arc<SystemState>) -> Result<(), LauncherError>{
-    let log_path = format!("/var/log/windows/vm/log-{}.txt", chrono::Local::now().to_string());
-    let log_file = File::create(&log_path)
-        .map_err(|err| LauncherError::FailedtoCreateLogFile(err))?;
-    let log = Stdio::from(log_file.try_clone().map_err(|err| LauncherError::FailedtoCreateLogFile(err))?);
-    let log_err = Stdio::from(log_file);
-    let _ = tokio::process::Command::new("virsh").args(["-cqemu:///system", &format!("--log={}", log_path), "create", "/tmp/windows.xml"])
-        .stdout(log).stderr(log_err).spawn()
-        .map_err(|err| LauncherError::FailedToLaunchVM(err))?.wait().await;
+/// Launch vm from the xml setup_pc generated at xml_path
+pub async fn start_vm(state: Arc<SystemState>, config: &Config, xml_path: &Path) -> Result<(), LauncherError>{
+    let (log_path, mut log_file) = create_log_file(&config.log_dir, "vm", config.log_keep)
+        .map_err(LauncherError::FailedtoCreateLogFile)?;
+    let log = Stdio::from(log_file.try_clone().map_err(LauncherError::FailedtoCreateLogFile)?);
+    // stderr is kept to explain a failed create, and copied into the log afterwards
+    let output = virsh(&config.libvirt_uri).arg(format!("--log={}", log_path.display())).arg("create").arg(xml_path)
+        .stdout(log).stderr(Stdio::piped()).output().await
+        .map_err(LauncherError::FailedToLaunchVM)?;
+    let _ = log_file.write_all(&output.stderr);
+    if !output.status.success() {
+        return Err(LauncherError::VmCreateFailed(String::from_utf8_lossy(&output.stderr).trim().to_string()));
+    }
+    // virsh can succeed for a domain that stops straight away, e.g. when qemu can't open a device
+    if !vm_running(&config.libvirt_uri, &state.vm_name()).await? {
+        return Err(LauncherError::VmCreateFailed(format!("{} stopped right after it was created, see {}", state.vm_name(), log_path.display())));
+    }
     state.vm_launched.store(true, Ordering::Relaxed);
+    state.save();
     Ok(())
 }
 
-/// wait for vm
-pub async fn wait_on_vm(state: Arc<SystemState>) -> Result<(), LauncherError>{
-    if tokio::process::Command::new("virsh").args(["-cqemu:///system", "domstate", "windows"]).output().await
-        .map_err(|err| LauncherError::FailedToGetVmState(err))?.status.success() 
-    {
-        loop{
-            if String::from_utf8_lossy(&tokio::process::Command::new("virsh")
-            .args(["-cqemu:///system", "event", "--event", "lifecycle", "--domain", "windows"])
-            .stderr(Stdio::null()).stdout(Stdio::null())
-            .output().await.map_err(|err| LauncherError::FailedToGetEvents(err))?.stdout).contains("Shutdown Finished after guest request") {
-                break;
-            }
+/// wait for vm to close. fails if config.vm_max_wait_secs passes first
+pub async fn wait_on_vm(state: Arc<SystemState>, conn: Arc<SyncConnection>, config: &Config) -> Result<(), LauncherError>{
+    let vm_name = state.vm_name();
+    let wait = wait_for_vm_stop(conn, &config.libvirt_uri, &vm_name, Duration::from_secs(config.vm_poll_interval_secs), config.dbus_timeout());
+    match config.vm_max_wait_secs.map(Duration::from_secs) {
+        Some(max_wait) => tokio::time::timeout(max_wait, wait).await.map_err(|_| LauncherError::VmWaitTimeout(max_wait))??,
+        None => wait.await?
+    }
+    state.vm_launched.store(false, Ordering::Relaxed);
+    state.save();
+    Ok(())
+}
+
+/// returns the libvirt-dbus object of the system bus daemon serving uri, if it serves it
+/// the system daemon only connects to the system drivers, so a session or remote uri has none
+pub fn libvirt_dbus_path(uri: &str) -> Option<&'static str>{
+    match uri {
+        "qemu:///system" => Some("/org/libvirt/QEMU"),
+        _ => None
+    }
+}
+
+/// waits for the domain to stop, using libvirt's dbus lifecycle events
+/// if the libvirt dbus interface is unavailable or doesn't serve uri, falls back to polling virsh domstate every interval
+pub async fn wait_for_vm_stop(conn: Arc<SyncConnection>, uri: &str, vm_name: &str, interval: Duration, dbus_timeout: Duration) -> Result<(), LauncherError>{
+    let subscribed = match libvirt_dbus_path(uri) {
+        Some(path) => VmLifecycleFuture::subscribe(conn.clone(), path, vm_name, dbus_timeout).await,
+        None => {
+            log::info!("libvirt dbus doesn't serve {}, polling for the vm to stop", uri);
+            return poll_vm_stop(uri, vm_name, interval).await;
         }
-        loop{
-            let child = tokio::process::Command::new("virsh")
-                .args(["-cqemu:///system", "event", "--event", "lifecycle", "--domain", "windows"])
-                .stderr(Stdio::null()).stdout(Stdio::null()).spawn().map_err(|err| LauncherError::FailedToGetEvents(err))?;
-            if !tokio::process::Command::new("virsh").args(["-cqemu:///system", "domstate", "windows"]).output().await
-                .map_err(|err| LauncherError::FailedToGetVmState(err))?.status.success() {break;}
-            if String::from_utf8_lossy(&child.wait_with_output().await.map_err(|err| LauncherError::FailedToGetEvents(err))?.stdout).contains("Stopped Shutdown") {
-                break;
-            }
+    };
+    match subscribed {
+        Ok((future, handle)) => {
+            // the domain may have stopped before we subscribed
+            let result = match vm_running(uri, vm_name).await {
+                Ok(true) => future.await,
+                Ok(false) => Ok(()),
+                Err(err) => Err(err)
+            };
+            let _ = conn.remove_match(handle.token()).await;
+            result
+        },
+        Err(err) => {
+            log::warn!("Could not subscribe to libvirt lifecycle events, polling instead: {}", err);
+            poll_vm_stop(uri, vm_name, interval).await
         }
     }
-    state.vm_launched.store(false, Ordering::Relaxed);
+}
+
+/// waits for the domain to stop by checking virsh domstate every interval
+async fn poll_vm_stop(uri: &str, vm_name: &str, interval: Duration) -> Result<(), LauncherError>{
+    while vm_running(uri, vm_name).await? {
+        tokio::time::sleep(interval).await;
+    }
     Ok(())
-}
\ No newline at end of file
+}
+
+/// Future which resolves when libvirt reports that a domain has stopped
+pub struct VmLifecycleFuture{
+    pub stopped: Arc<Mutex<Hookable<bool>>>
+}
+impl VmLifecycleFuture{
+    /// Subscribes to org.libvirt DomainEvent signals for the domain vm_name on the connect object path. the match must be removed once the future is done
+    pub async fn subscribe(conn: Arc<SyncConnection>, path: &'static str, vm_name: &str, dbus_timeout: Duration) -> Result<(VmLifecycleFuture, MsgMatch), dbus::Error>{
+        let proxy = Proxy::new("org.libvirt", path, dbus_timeout, conn.clone());
+        let (domain,): (dbus::Path<'static>,) = proxy.method_call("org.libvirt.Connect", "DomainLookupByName", (vm_name,)).await?;
+        let stopped = Arc::new(Mutex::new(Hookable::<bool>::default()));
+        let stopped_copy = stopped.clone();
+        let handle = conn.add_match(MatchRule::new_signal("org.libvirt.Connect", "DomainEvent").with_path(path)).await?
+            .cb(move |_, (path, event, _): (dbus::Path, i32, u32)| {
+                if path == domain && event == LIBVIRT_DOMAIN_EVENT_STOPPED {
+                    stopped_copy.lock_or_recover().set(true);
+                }
+                true
+            });
+        Ok((VmLifecycleFuture{stopped}, handle))
+    }
+}
+impl Future for VmLifecycleFuture{
+    type Output = Result<(), LauncherError>;
+    fn poll(self: std::pin::Pin<&mut Self>, cx: &mut std::task::Context<'_>) -> std::task::Poll<Self::Output> {
+        let mut guard = self.stopped.lock_or_recover();
+        if *guard.get() {Poll::Ready(Ok(()))}
+        else {
+            guard.hook(cx.waker().clone());
+            Poll::Pending
+        }
+    }
+}
+
+/// returns a virsh command connected to the libvirt uri
+pub fn virsh(uri: &str) -> tokio::process::Command{
+    let mut command = tokio::process::Command::new("virsh");
+    command.args(["-c", uri]);
+    command
+}
+
+/// Destroys the domain vm_name at once, without asking the guest to shut down
+pub async fn destroy_vm(uri: &str, vm_name: &str) -> Result<(), LauncherError>{
+    log::info!("Destroying VM");
+    let out = virsh(uri).args(["destroy", vm_name]).output().await
+        .map_err(LauncherError::FailedToDestroyVm)?;
+    if !out.status.success() {
+        return Err(LauncherError::FailedToDestroyVm(std::io::Error::other(String::from_utf8_lossy(&out.stderr).trim().to_string())));
+    }
+    Ok(())
+}
+
+/// returns whether the domain is currently running, according to virsh domstate
+pub async fn vm_running(uri: &str, vm_name: &str) -> Result<bool, LauncherError>{
+    let output = virsh(uri).args(["domstate", vm_name])
+        .stderr(Stdio::null()).kill_on_drop(true).output().await.map_err(LauncherError::FailedToGetVmState)?;
+    Ok(output.status.success() && !String::from_utf8_lossy(&output.stdout).contains("shut off"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{os::unix::process::ExitStatusExt, process::{ExitStatus, Output}};
+    use crate::{command::CommandFuture, config::InputMode};
+
+    /// records every command instead of running it. commands matching a failure get its stderr back
+    #[derive(Default)]
+    struct MockRunner {
+        calls: Mutex<Vec<String>>,
+        failures: Vec<(String, String)>
+    }
+    impl MockRunner {
+        fn failing(command: &str, stderr: &str) -> Self {
+            Self{failures: vec![(command.to_string(), stderr.to_string())], ..Default::default()}
+        }
+        fn calls(&self) -> Vec<String> {
+            self.calls.lock().unwrap().clone()
+        }
+    }
+    impl CommandRunner for MockRunner {
+        fn run<'a>(&'a self, program: &'a str, args: &'a [&'a str]) -> CommandFuture<'a> {
+            let command = std::iter::once(program).chain(args.iter().copied()).collect::<Vec<&str>>().join(" ");
+            self.calls.lock().unwrap().push(command.clone());
+            let stderr = self.failures.iter().find(|(failing, _)| *failing == command).map(|(_, stderr)| stderr.clone()).unwrap_or_default();
+            let status = ExitStatus::from_raw(if stderr.is_empty() {0} else {1 << 8});
+            Box::pin(async move {Ok(Output{status, stdout: vec![], stderr: stderr.into_bytes()})})
+        }
+    }
+
+    fn test_state() -> SystemState {
+        SystemState{memory_only: true, ..Default::default()}
+    }
+
+    fn test_config() -> Config {
+        Config{
+            gpu_pci_ids: vec!["pci_0000_01_00_0".to_string(), "pci_0000_01_00_1".to_string()],
+            nvidia_modules: vec!["nvidia_drm".to_string(), "nvidia".to_string()],
+            extra_pci_ids: vec!["pci_0000_05_00_0".to_string()],
+            extra_modules: vec!["xhci_pci".to_string()],
+            vfio_options: vec!["disable_vga=1".to_string()],
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn cpu_masks_must_be_online_and_non_empty() {
+        let config = Config{host_cpu_mask: "0-3".to_string(), default_cpu_mask: "0-15".to_string(), ..Default::default()};
+        let online = (0..16).collect::<Vec<usize>>();
+        assert!(validate_cpu_masks(&config, Some(&online)).is_ok());
+        let typo = Config{host_cpu_mask: "0-31".to_string(), ..config.clone()};
+        assert!(matches!(validate_cpu_masks(&typo, Some(&online)), Err(LauncherError::InvalidCpuMask(_))));
+        // without the online list only the sets themselves are checked
+        assert!(validate_cpu_masks(&typo, None).is_ok());
+        let empty = Config{host_cpu_mask: " ".to_string(), ..config.clone()};
+        assert!(matches!(validate_cpu_masks(&empty, None), Err(LauncherError::InvalidCpuMask(_))));
+        let nothing_to_isolate = Config{default_cpu_mask: "0-3".to_string(), isolate_cpus: true, ..config};
+        assert!(matches!(validate_cpu_masks(&nothing_to_isolate, Some(&online)), Err(LauncherError::InvalidCpuMask(_))));
+    }
+
+    /// builds a fake pci bus with two nvidia gpus and an nvme drive in a fresh temp directory
+    fn fake_pci_bus(name: &str) -> PathBuf {
+        let bus = std::env::temp_dir().join(format!("windows-launcher-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&bus);
+        for driver in ["nvidia", "nvme", "vfio-pci"] {std::fs::create_dir_all(bus.join("drivers").join(driver)).unwrap();}
+        for (address, class, driver) in [("0000:01:00.0", "0x030000", "nvidia"), ("0000:02:00.0", "0x030000", "nvidia"), ("0000:03:00.0", "0x010802", "nvme")] {
+            let device = bus.join("devices").join(address);
+            std::fs::create_dir_all(&device).unwrap();
+            std::fs::write(device.join("class"), class).unwrap();
+            std::os::unix::fs::symlink(bus.join("drivers").join(driver), device.join("driver")).unwrap();
+        }
+        bus
+    }
+
+    /// a fake pci bus where the configured gpu is the only one on its driver
+    fn single_gpu_bus(name: &str) -> PathBuf {
+        let bus = fake_pci_bus(name);
+        std::fs::remove_dir_all(bus.join("devices/0000:02:00.0")).unwrap();
+        bus
+    }
+
+    #[test]
+    fn only_the_configured_gpu_moves_to_vfio() {
+        let bus = fake_pci_bus("bind");
+        let configured = ["pci_0000_02_00_0".to_string()];
+        assert_eq!(host_gpus_sharing_driver(&bus, &configured), vec!["0000:01:00.0".to_string()]);
+        // with both gpus configured, the driver can be unloaded as before
+        assert!(host_gpus_sharing_driver(&bus, &["pci_0000_01_00_0".to_string(), "pci_0000_02_00_0".to_string()]).is_empty());
+        assert_eq!(vfio_bind_device(&bus, "0000:02:00.0").unwrap(), Some("nvidia".to_string()));
+        let device = bus.join("devices").join("0000:02:00.0");
+        assert_eq!(std::fs::read_to_string(device.join("driver_override")).unwrap(), "vfio-pci");
+        assert_eq!(std::fs::read_to_string(bus.join("drivers/nvidia/unbind")).unwrap(), "0000:02:00.0");
+        assert_eq!(std::fs::read_to_string(bus.join("drivers_probe")).unwrap(), "0000:02:00.0");
+        vfio_unbind_device(&bus, "0000:02:00.0", Some("nvidia")).unwrap();
+        assert_eq!(std::fs::read_to_string(device.join("driver_override")).unwrap(), "\n");
+        assert_eq!(std::fs::read_to_string(bus.join("drivers/nvidia/bind")).unwrap(), "0000:02:00.0");
+        std::fs::remove_dir_all(&bus).unwrap();
+    }
+
+    #[tokio::test]
+    async fn rebind_waits_for_the_host_driver() {
+        let bus = fake_pci_bus("rebind");
+        let gpus = ["pci_0000_01_00_0".to_string(), "pci_0000_02_00_0".to_string()];
+        assert!(wait_for_host_drivers(&bus, &gpus, Duration::from_secs(5)).await);
+        let driver = bus.join("devices/0000:02:00.0/driver");
+        std::fs::remove_file(&driver).unwrap();
+        std::os::unix::fs::symlink(bus.join("drivers/vfio-pci"), &driver).unwrap();
+        assert!(!wait_for_host_drivers(&bus, &gpus, Duration::from_millis(200)).await);
+        // the host driver binding partway through ends the wait
+        let nvidia = bus.join("drivers/nvidia");
+        let rebind = tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_millis(200)).await;
+            std::fs::remove_file(&driver).unwrap();
+            std::os::unix::fs::symlink(nvidia, &driver).unwrap();
+        });
+        assert!(wait_for_host_drivers(&bus, &gpus, Duration::from_secs(5)).await);
+        rebind.await.unwrap();
+        std::fs::remove_dir_all(&bus).unwrap();
+    }
+
+    #[test]
+    fn virtio_tablet_is_added_once() {
+        let xml = "<domain>\n  <devices>\n    <disk/>\n  </devices>\n</domain>";
+        let tablet = with_virtio_tablet(xml).unwrap();
+        assert_eq!(tablet, "<domain>\n  <devices>\n    <disk/>\n    <input type='tablet' bus='virtio'/>\n  </devices>\n</domain>");
+        assert_eq!(with_virtio_tablet(&tablet).unwrap(), tablet);
+        // a usb tablet is not a virtio one
+        assert!(with_virtio_tablet("<devices><input type=\"tablet\" bus=\"usb\"/></devices>").unwrap().contains("bus='virtio'"));
+        assert!(matches!(with_virtio_tablet("<domain/>"), Err(LauncherError::XmlMissingDevices)));
+        let mut config = test_config();
+        config.input_mode = InputMode::VirtioTablet;
+        assert!(!config.virtual_mouse(&VmType::LookingGlass));
+        assert!(config.virtio_tablet(&VmType::Spice) && !config.virtio_tablet(&VmType::SpiceLite));
+    }
+
+    #[test]
+    fn xml_is_written_per_domain_and_private() {
+        assert_eq!(xml_output_path("windows"), Path::new("/run/windows-vm-launcher/windows.xml"));
+        assert_ne!(xml_output_path("windows"), xml_output_path("windows-gaming"));
+        assert_eq!(xml_output_path("../etc/passwd"), Path::new("/run/windows-vm-launcher/.._etc_passwd.xml"));
+        let dir = std::env::temp_dir().join(format!("windows-launcher-xml-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        let path = dir.join("windows.xml");
+        write_xml(&path, "<domain>longer</domain>").unwrap();
+        write_xml(&path, "<domain/>").unwrap();
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "<domain/>");
+        assert_eq!(std::fs::metadata(&path).unwrap().mode() & 0o777, 0o600);
+        assert_eq!(std::fs::metadata(&dir).unwrap().mode() & 0o777, 0o755);
+        // an existing file is made private again, an existing directory keeps its mode
+        std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o644)).unwrap();
+        std::fs::set_permissions(&dir, std::fs::Permissions::from_mode(0o750)).unwrap();
+        write_xml(&path, "<domain/>").unwrap();
+        assert_eq!(std::fs::metadata(&path).unwrap().mode() & 0o777, 0o600);
+        assert_eq!(std::fs::metadata(&dir).unwrap().mode() & 0o777, 0o750);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn lg_shm_refuses_symlinks() {
+        let dir = std::env::temp_dir().join(format!("windows-launcher-shm-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let (target, link) = (dir.join("shadow"), dir.join("looking-glass"));
+        std::fs::write(&target, "").unwrap();
+        std::fs::set_permissions(&target, std::fs::Permissions::from_mode(0o600)).unwrap();
+        std::os::unix::fs::symlink(&target, &link).unwrap();
+        let state = Arc::new(test_state());
+        assert!(matches!(setup_lg_shm(state.clone(), link.to_str().unwrap(), 1000, 0o666), Err(LauncherError::FailedToSetupLgShm(..))));
+        assert_eq!(std::fs::metadata(&target).unwrap().mode() & 0o777, 0o600);
+        assert!(state.shm_original.lock_or_recover().is_none() && state.shm_created.lock_or_recover().is_none());
+        // a missing file is created fresh
+        let shm = dir.join("fresh");
+        setup_lg_shm(state.clone(), shm.to_str().unwrap(), 0, 0o640).unwrap();
+        assert_eq!(std::fs::metadata(&shm).unwrap().mode() & 0o777, 0o640);
+        restore_lg_shm(state);
+        assert!(!shm.exists());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn only_the_system_uri_uses_libvirt_dbus() {
+        assert_eq!(libvirt_dbus_path("qemu:///system"), Some("/org/libvirt/QEMU"));
+        assert_eq!(libvirt_dbus_path("qemu:///session"), None);
+        assert_eq!(libvirt_dbus_path("qemu+ssh://host/system"), None);
+    }
+
+    #[test]
+    fn xml_template_is_checked_before_launch() {
+        let path = std::env::temp_dir().join(format!("windows-launcher-template-{}.xml", std::process::id()));
+        std::fs::write(&path, "<domain><devices><disk/></devices></domain>").unwrap();
+        let config = Config{lg_xml_path: Some(path.to_string_lossy().to_string()), spice_lite_xml_path: Some(path.to_string_lossy().to_string()), ..test_config()};
+        // the virtual mouse needs somewhere to go
+        assert!(matches!(read_xml_template(&VmType::LookingGlass, &config), Err(LauncherError::XmlMissingPlaceholder(_))));
+        assert!(read_xml_template(&VmType::SpiceLite, &config).is_ok());
+        let tablet = Config{input_mode: InputMode::VirtioTablet, ..config};
+        assert!(read_xml_template(&VmType::LookingGlass, &tablet).unwrap().contains("<input type='tablet' bus='virtio'/>"));
+        std::fs::remove_file(&path).unwrap();
+        assert!(matches!(read_xml_template(&VmType::SpiceLite, &tablet), Err(LauncherError::FailedToReadXmlPath(..))));
+    }
+
+    #[test]
+    fn vm_type_codes_round_trip() {
+        for vm_type in [VmType::LookingGlass, VmType::Spice, VmType::SpiceLite, VmType::Direct] {
+            assert_eq!(VmType::from_code(vm_type.as_code()), Some(vm_type.clone()));
+            // display names are for people, and aren't codes
+            assert_eq!(VmType::from_code(&vm_type.to_string()), None);
+        }
+    }
+
+    #[test]
+    fn state_transition_matrix() {
+        use VmState::*;
+        let states = [Inactive, Activating, Launched, ShuttingDown];
+        let legal = [(Inactive, Activating), (Activating, Launched), (Activating, ShuttingDown), (Launched, ShuttingDown), (ShuttingDown, Inactive)];
+        for from in states.iter() {
+            for to in states.iter() {
+                let expected = legal.contains(&(from.clone(), to.clone()));
+                assert_eq!(from.can_transition_to(to), expected, "{:?} -> {:?}", from, to);
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn bind_vfio_runs_steps_in_order_and_tracks_them() {
+        let bus = single_gpu_bus("steps");
+        let (state, runner, config) = (test_state(), MockRunner::default(), test_config());
+        bind_vfio(&state, &runner, &bus, &config).await.unwrap();
+        assert_eq!(runner.calls(), vec![
+            "modprobe -f -r nvidia_drm", "modprobe -f -r nvidia",
+            "virsh -c qemu:///system nodedev-detach pci_0000_01_00_0", "virsh -c qemu:///system nodedev-detach pci_0000_01_00_1",
+            "modprobe -f -r xhci_pci", "virsh -c qemu:///system nodedev-detach pci_0000_05_00_0",
+            "modprobe vfio-pci disable_vga=1"
+        ]);
+        assert_eq!(SystemState::tracked(&state.nvidia_unloaded), config.nvidia_modules);
+        assert_eq!(SystemState::tracked(&state.gpu_dettached), config.gpu_pci_ids);
+        assert_eq!(SystemState::tracked(&state.extra_unloaded), config.extra_modules);
+        assert_eq!(SystemState::tracked(&state.extra_detached), config.extra_pci_ids);
+        assert!(state.vfio_loaded());
+        std::fs::remove_dir_all(&bus).unwrap();
+    }
+
+    #[tokio::test]
+    async fn bind_vfio_failure_leaves_only_completed_steps_tracked() {
+        let bus = single_gpu_bus("bind-failure");
+        let (state, config) = (test_state(), test_config());
+        let runner = MockRunner::failing("modprobe -f -r nvidia", "modprobe: FATAL: Module nvidia is builtin.");
+        let result = bind_vfio(&state, &runner, &bus, &config).await;
+        assert!(matches!(result, Err(LauncherError::ModprobeRemoveReturnedErr(module, _)) if module == "nvidia"));
+        assert_eq!(SystemState::tracked(&state.nvidia_unloaded), vec!["nvidia_drm"]);
+        assert!(!state.gpu_detached());
+        assert!(!state.vfio_loaded());
+        // only the failed step needs undoing, nothing past it was touched
+        let undo = MockRunner::default();
+        let (errors, changed) = unbind_vfio(&state, &undo, &bus, &config).await;
+        assert!(errors.is_empty() && changed);
+        assert_eq!(undo.calls(), vec!["modprobe nvidia_drm"]);
+        std::fs::remove_dir_all(&bus).unwrap();
+    }
+
+    #[tokio::test]
+    async fn bind_vfio_stops_when_a_detach_fails() {
+        let bus = single_gpu_bus("detach-failure");
+        let (state, config) = (test_state(), test_config());
+        let runner = MockRunner::failing("virsh -c qemu:///system nodedev-detach pci_0000_01_00_1", "error: Failed to detach device pci_0000_01_00_1");
+        let result = bind_vfio(&state, &runner, &bus, &config).await;
+        assert!(matches!(result, Err(LauncherError::FailedToDisconnectGPU(pci, _)) if pci == "pci_0000_01_00_1"));
+        assert_eq!(SystemState::tracked(&state.gpu_dettached), vec!["pci_0000_01_00_0"]);
+        assert!(!state.vfio_loaded());
+        let undo = MockRunner::default();
+        let (errors, _) = unbind_vfio(&state, &undo, &bus, &config).await;
+        assert!(errors.is_empty());
+        assert_eq!(undo.calls(), vec!["virsh -c qemu:///system nodedev-reattach pci_0000_01_00_0", "modprobe nvidia", "modprobe nvidia_drm"]);
+        std::fs::remove_dir_all(&bus).unwrap();
+    }
+
+    #[tokio::test]
+    async fn bind_vfio_keeps_a_host_gpu_on_the_shared_driver() {
+        let bus = fake_pci_bus("shared");
+        let state = test_state();
+        let config = Config{gpu_pci_ids: vec!["pci_0000_02_00_0".to_string()], extra_pci_ids: vec![], ..test_config()};
+        let runner = MockRunner::default();
+        bind_vfio(&state, &runner, &bus, &config).await.unwrap();
+        // nvidia stays loaded for 0000:01:00.0, only vfio is loaded
+        assert_eq!(runner.calls(), vec!["modprobe vfio-pci disable_vga=1"]);
+        assert_eq!(*state.gpu_drivers.lock_or_recover(), vec![("0000:02:00.0".to_string(), "nvidia".to_string())]);
+        assert_eq!(std::fs::read_to_string(bus.join("devices/0000:02:00.0/driver_override")).unwrap(), "vfio-pci");
+        let (errors, changed) = unbind_vfio(&state, &runner, &bus, &config).await;
+        assert!(errors.is_empty() && !changed);
+        assert_eq!(std::fs::read_to_string(bus.join("devices/0000:02:00.0/driver_override")).unwrap(), "\n");
+        std::fs::remove_dir_all(&bus).unwrap();
+    }
+
+    #[tokio::test]
+    async fn unbind_vfio_reverses_bind_vfio() {
+        let bus = single_gpu_bus("unbind");
+        let (state, config) = (test_state(), test_config());
+        bind_vfio(&state, &MockRunner::default(), &bus, &config).await.unwrap();
+        let runner = MockRunner::default();
+        let (errors, changed) = unbind_vfio(&state, &runner, &bus, &config).await;
+        assert!(errors.is_empty() && changed);
+        assert_eq!(runner.calls(), vec![
+            "modprobe -f -r vfio-pci",
+            "virsh -c qemu:///system nodedev-reattach pci_0000_05_00_0", "modprobe xhci_pci",
+            "virsh -c qemu:///system nodedev-reattach pci_0000_01_00_0", "virsh -c qemu:///system nodedev-reattach pci_0000_01_00_1",
+            "modprobe nvidia", "modprobe nvidia_drm"
+        ]);
+        std::fs::remove_dir_all(&bus).unwrap();
+    }
+
+    #[tokio::test]
+    async fn unbind_vfio_reports_every_failure() {
+        let bus = single_gpu_bus("unbind-failure");
+        let (state, config) = (test_state(), test_config());
+        bind_vfio(&state, &MockRunner::default(), &bus, &config).await.unwrap();
+        let runner = MockRunner{failures: vec![
+            ("virsh -c qemu:///system nodedev-reattach pci_0000_01_00_1".to_string(), "error: device busy".to_string()),
+            ("modprobe nvidia".to_string(), "modprobe: ERROR: could not insert 'nvidia'".to_string())
+        ], ..Default::default()};
+        let (errors, _) = unbind_vfio(&state, &runner, &bus, &config).await;
+        // the failures don't stop the remaining steps
+        assert_eq!(runner.calls().len(), 7);
+        assert!(matches!(&errors[..], [LauncherError::FailedToConnectGPU(pci, _), LauncherError::FailedToLoadKernelModule(module, _)]
+            if pci == "pci_0000_01_00_1" && module == "nvidia"));
+        let report = LauncherError::from_cleanup(errors).unwrap_err().to_string();
+        assert!(report.starts_with("2 cleanup steps failed"));
+        assert!(report.contains("pci_0000_01_00_1") && report.contains("could not insert 'nvidia'"));
+        std::fs::remove_dir_all(&bus).unwrap();
+    }
+
+    #[tokio::test]
+    async fn unmanaged_vfio_is_left_alone() {
+        let bus = single_gpu_bus("unmanaged");
+        let (state, mut config) = (test_state(), test_config());
+        config.manage_vfio = false;
+        let runner = MockRunner::default();
+        bind_vfio(&state, &runner, &bus, &config).await.unwrap();
+        let (errors, _) = unbind_vfio(&state, &runner, &bus, &config).await;
+        assert!(errors.is_empty());
+        assert!(!runner.calls().iter().any(|call| call.contains("vfio-pci")));
+        assert!(!state.vfio_loaded());
+        std::fs::remove_dir_all(&bus).unwrap();
+    }
+
+    #[tokio::test]
+    async fn unbind_vfio_with_nothing_tracked_does_nothing() {
+        let bus = single_gpu_bus("nothing-tracked");
+        let runner = MockRunner::default();
+        let (errors, changed) = unbind_vfio(&test_state(), &runner, &bus, &test_config()).await;
+        assert!(errors.is_empty() && !changed);
+        assert!(runner.calls().is_empty());
+        std::fs::remove_dir_all(&bus).unwrap();
+    }
+}
@@ -0,0 +1,52 @@
+/*
+    Per launch log files, shared by the root server and the session
+    Each launch gets its own file under <log_dir>/<kind>, named by the time it started
+*/
+
+use std::{fs::File, path::{Path, PathBuf}, time::SystemTime};
+
+/// default directory log files are written under
+pub const DEFAULT_LOG_DIR: &str = "/var/log/windows";
+/// default number of old log files kept per kind
+pub const DEFAULT_LOG_KEEP: usize = 20;
+
+/// Creates a new log file at <log_dir>/<kind>/log-<timestamp>.txt, creating the directory if needed
+/// all but the newest keep existing logs are deleted first. returns the path of the file along with the file
+pub fn create_log_file(log_dir: &str, kind: &str, keep: usize) -> std::io::Result<(PathBuf, File)>{
+    let dir = Path::new(log_dir).join(kind);
+    std::fs::create_dir_all(&dir)?;
+    prune_logs(&dir, keep);
+    // no spaces or colons, so the names are easy to use from a shell
+    let path = dir.join(format!("log-{}.txt", chrono::Local::now().format("%Y-%m-%d_%H-%M-%S")));
+    let file = File::create(&path)?;
+    Ok((path, file))
+}
+
+/// Returns every log-*.txt file in dir along with its modification time, in no particular order
+fn log_files(dir: &Path) -> std::io::Result<Vec<(SystemTime, PathBuf)>>{
+    Ok(dir.read_dir()?.flatten().filter_map(|entry| {
+        let name = entry.file_name().to_string_lossy().to_string();
+        if !name.starts_with("log-") || !name.ends_with(".txt") {return None;}
+        let modified = entry.metadata().and_then(|meta| meta.modified()).ok()?;
+        Some((modified, entry.path()))
+    }).collect())
+}
+
+/// Returns the most recently modified log-*.txt file in dir, if there is one
+pub fn newest_log(dir: &Path) -> Option<PathBuf>{
+    log_files(dir).ok()?.into_iter().max_by_key(|(modified, _)| *modified).map(|(_, path)| path)
+}
+
+/// Deletes all but the newest keep log-*.txt files in dir, by modification time
+/// failures are only logged, since a full log directory shouldn't stop a launch
+pub fn prune_logs(dir: &Path, keep: usize){
+    let mut logs = match log_files(dir) {
+        Ok(logs) => logs,
+        Err(err) => {log::warn!("Could not read the log directory {}: {}", dir.display(), err); return;}
+    };
+    // newest first
+    logs.sort_by_key(|(modified, _)| std::cmp::Reverse(*modified));
+    for (_, path) in logs.into_iter().skip(keep) {
+        if let Err(err) = std::fs::remove_file(&path) {log::warn!("Could not remove old log {}: {}", path.display(), err);}
+    }
+}
@@ -2,14 +2,40 @@ pub mod session;
 pub mod cli;
 pub mod server;
 pub mod launcher;
+pub mod socket_server;
+pub mod config;
+pub mod logs;
+pub mod iommu;
+pub mod command;
+pub mod metrics;
 
 use std::{env::args, error::Error, fmt::Display};
 use cli::{cli, CliError, Command};
+use config::{Config, ConfigError};
 use launcher::LauncherError;
 use nix::unistd::Uid;
 use server::ServerError;
 use session::SessionError;
 
+/// exit code of errors without a more specific one
+pub const EXIT_FAILURE: i32 = 1;
+/// exit code when the config file can't be read or parsed
+pub const EXIT_CONFIG: i32 = 2;
+/// exit code when a command that needs root was run without it
+pub const EXIT_NOT_ROOT: i32 = 3;
+/// exit code when the dbus daemon or the server on it can't be reached, which is usually transient
+pub const EXIT_DBUS_UNAVAILABLE: i32 = 4;
+/// exit code when putting the host back after a vm failed
+pub const EXIT_CLEANUP_FAILED: i32 = 5;
+
+/// whether a dbus call failed because the bus or the service on it couldn't be reached, rather than the call itself failing
+fn bus_unavailable(err: &dbus::Error) -> bool {
+    matches!(err.name(), Some(
+        "org.freedesktop.DBus.Error.ServiceUnknown" | "org.freedesktop.DBus.Error.NameHasNoOwner" | "org.freedesktop.DBus.Error.NoReply" |
+        "org.freedesktop.DBus.Error.NoServer" | "org.freedesktop.DBus.Error.Disconnected" | "org.freedesktop.DBus.Error.Timeout"
+    ))
+}
+
 /// Enum representing app errors
 #[derive(Debug)]
 pub enum AppError{
@@ -18,27 +44,49 @@ pub enum AppError{
     ServerError(ServerError),
     SessionError(SessionError),
     LauncherError(LauncherError),
-    CliError(CliError)
+    CliError(CliError),
+    ConfigError(ConfigError)
 }
 impl Display for AppError{
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         f.write_str(&match self {
-            AppError::MalformedCommand => format!("Command was Malformed"),
-            AppError::ServerNotRunAsRoot => format!("The Server was not run as root"),
+            AppError::MalformedCommand => "Command was Malformed".to_string(),
+            AppError::ServerNotRunAsRoot => "The Server was not run as root".to_string(),
             AppError::ServerError(err) => format!("The system server returned with err: {}", *err),
             AppError::SessionError(err) => format!("Session server returned with err: {}", *err),
             AppError::LauncherError(err) => format!("Launcher failed with err: {}", *err),
-            AppError::CliError(err) => format!("The command failed with err: {}", *err)
+            AppError::CliError(err) => format!("The command failed with err: {}", *err),
+            AppError::ConfigError(err) => format!("Failed to load the config: {}", *err)
         })?;
         Ok(())
     }
 }
 impl Error for AppError{}
+impl AppError{
+    /// returns the process exit code for the error's category, so scripts and systemd can tell them apart
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            AppError::ConfigError(_) | AppError::CliError(CliError::FailedToLoadConfig(_)) | AppError::SessionError(SessionError::FailedToLoadConfig(_)) => EXIT_CONFIG,
+            AppError::ServerNotRunAsRoot | AppError::CliError(CliError::NotRunAsRoot(_)) => EXIT_NOT_ROOT,
+            AppError::ServerError(ServerError::FailedToConnectToSystemBus(_) | ServerError::FailedToGetName(_)) |
+            AppError::SessionError(SessionError::FailedToConnectToSystemBus(_)) |
+            AppError::CliError(CliError::FailedToConnectToSystemBus(_) | CliError::FailedToConnectToSessionBus(_)) => EXIT_DBUS_UNAVAILABLE,
+            AppError::SessionError(SessionError::ServerError(err)) if bus_unavailable(err) => EXIT_DBUS_UNAVAILABLE,
+            AppError::CliError(
+                CliError::FailedToStartUserService(err) | CliError::FailedToQueryState(err) | CliError::FailedToQueryMetrics(err) |
+                CliError::FailedToCallShutdown(err) | CliError::FailedToCancelLaunch(err) | CliError::FailedToCallPause(err) | CliError::FailedToReload(err) |
+                CliError::FailedToLaunchLG(err) | CliError::FailedToLaunchSpice(err) | CliError::FailedToLaunchSpiceLite(err) | CliError::FailedToLaunchDirect(err)
+            ) if bus_unavailable(err) => EXIT_DBUS_UNAVAILABLE,
+            AppError::LauncherError(LauncherError::CleanupErrors(_)) | AppError::CliError(CliError::FailedToRecover(_) | CliError::FailedToAttach(_)) => EXIT_CLEANUP_FAILED,
+            _ => EXIT_FAILURE
+        }
+    }
+}
 
 pub async fn app() -> Result<(), AppError> {
     let arguments = args().skip(1).collect::<Vec<String>>();
 
-    if arguments.len() == 0 {return cli(Command::Help).await.map_err(|err| AppError::CliError(err));}
+    if arguments.is_empty() {return cli(Command::Help).await.map_err(AppError::CliError);}
 
     //server
     if arguments[0] == "--server" {
@@ -46,39 +94,47 @@ pub async fn app() -> Result<(), AppError> {
         if !Uid::effective().is_root() {
             return Err(AppError::ServerNotRunAsRoot);
         }
-        let server_state = server::server().await.map_err(|err| AppError::ServerError(err))?;
-        let result = launcher::launcher(server_state.data.clone(), server_state.conn.clone()).await;
-        let _ = server_state.conn.remove_match(server_state.signal_handle.token()).await;
+        let config = Config::load().map_err(AppError::ConfigError)?;
+        let status_socket = config.status_socket;
+        let control_socket = config.control_socket.clone();
+        let server_state = server::server(config).await.map_err(AppError::ServerError)?;
+        // the unix socket control interface is opt in, and runs alongside dbus
+        let socket_handle = control_socket.map(|path| {
+            let data = server_state.data.clone();
+            tokio::spawn(async move {
+                if let Err(err) = socket_server::socket_server(data, path).await {log::error!("Socket server stopped with err: {}", err);}
+            })
+        });
+        let status_handle = status_socket.then(|| {
+            let data = server_state.data.clone();
+            tokio::spawn(async move {
+                if let Err(err) = socket_server::status_server(data, socket_server::STATUS_SOCKET_PATH.to_string()).await {log::error!("Status socket stopped with err: {}", err);}
+            })
+        });
+        let result = launcher::launcher(server_state.data.clone(), server_state.bus.clone()).await;
         server_state.handle.abort();
+        if let Some(handle) = socket_handle {handle.abort();}
+        if let Some(handle) = status_handle {handle.abort();}
         // killing is the only correct way to end the program, as it shouldnt end by itself
-        return result.map_err(|err| AppError::LauncherError(err));
+        return result.map_err(AppError::LauncherError);
     }
 
     //session server
     if arguments[0] == "--session" {
-        return session::session().await.map_err(|err| AppError::SessionError(err));
+        return session::session().await.map_err(AppError::SessionError);
     }
 
     //cli
-    let command = match arguments[0].as_str() {
-        "--spice" => {
-            if !arguments.len() == 2 {Command::Help}
-            else {Command::Start(launcher::VmType::Spice, arguments[1].to_string())}
-        },
-        "--lg" => {
-            if !arguments.len() == 2 {Command::Help}
-            else {Command::Start(launcher::VmType::LookingGlass, arguments[1].to_string())}
-        }
-        "--open" => {Command::Open},
-        "--query" => {Command::Query},
-        "--shutdown" => {Command::Shutdown},
-        _ => {Command::Help}
-    };
-    cli(command).await.map_err(|err| AppError::CliError(err))
+    let command = cli::parse_command(&arguments);
+    cli(command).await.map_err(AppError::CliError)
 }
 
-/// Main function. Run server, or client commands
+/// Main function. Run server, or client commands. exits with the error's exit_code on failure
 #[tokio::main]
-async fn main() -> Result<(), AppError> {
-    app().await
+async fn main() {
+    env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("info")).init();
+    if let Err(err) = app().await {
+        log::error!("{}", err);
+        std::process::exit(err.exit_code());
+    }
 }
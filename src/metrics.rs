@@ -0,0 +1,65 @@
+/*
+    Timings and outcome counts of the launch phases
+    Kept in memory for as long as the server runs, and served over dbus by the Metrics method
+*/
+
+use std::{collections::HashMap, time::{Duration, Instant}};
+
+/// stopping the display service and handing the gpu to vfio
+pub const GPU_DETACH: &str = "gpu_detach";
+/// cpu pinning, governor, virtual mouse, and the rest of the host setup
+pub const SETUP: &str = "setup";
+/// creating the domain with virsh
+pub const VM_BOOT: &str = "vm_boot";
+/// putting the host back after the vm
+pub const CLEANUP: &str = "cleanup";
+
+/// timing and outcome counts of one phase
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct PhaseMetrics{
+    /// how long the phase took the last time it ran
+    pub last_duration: Duration,
+    pub successes: u64,
+    pub failures: u64
+}
+
+/// Metrics of every phase that has run since the server started
+#[derive(Debug, Default, Clone)]
+pub struct Metrics{
+    phases: HashMap<String, PhaseMetrics>
+}
+impl Metrics{
+    /// records a run of phase that began at started
+    pub fn record(&mut self, phase: &str, started: Instant, success: bool) {
+        let metrics = self.phases.entry(phase.to_string()).or_default();
+        metrics.last_duration = started.elapsed();
+        if success {metrics.successes += 1;} else {metrics.failures += 1;}
+    }
+    /// returns the metrics of phase, if it has run
+    pub fn phase(&self, phase: &str) -> Option<&PhaseMetrics> {
+        self.phases.get(phase)
+    }
+    /// returns each phase's last duration in seconds, successes, and failures, as sent over dbus
+    pub fn report(&self) -> HashMap<String, (f64, u64, u64)> {
+        self.phases.iter()
+            .map(|(phase, metrics)| (phase.clone(), (metrics.last_duration.as_secs_f64(), metrics.successes, metrics.failures)))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn counts_accumulate_and_duration_is_the_last_run() {
+        let mut metrics = Metrics::default();
+        metrics.record(CLEANUP, Instant::now(), true);
+        metrics.record(CLEANUP, Instant::now() - Duration::from_secs(4), false);
+        let cleanup = metrics.phase(CLEANUP).unwrap();
+        assert_eq!((cleanup.successes, cleanup.failures), (1, 1));
+        assert!(cleanup.last_duration >= Duration::from_secs(4));
+        assert!(metrics.phase(GPU_DETACH).is_none());
+        assert_eq!(metrics.report()[CLEANUP].1, 1);
+    }
+}
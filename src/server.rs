@@ -3,14 +3,19 @@
     It holds the current state of the system, and uses it to queue actions like starting the vm
 */
 
-use std::{error::Error, fmt::Display, sync::{Arc, Mutex}, task::Poll};
-use dbus::{arg::{self, PropMap}, channel::MatchingReceiver, message::MatchRule, nonblock::{MsgMatch, SyncConnection}, MethodErr};
+use std::{collections::HashMap, error::Error, fmt::Display, sync::{Arc, Mutex, MutexGuard}, task::Poll, time::Duration};
+use dbus::{arg::{self, PropMap}, channel::{MatchingReceiver, Sender}, message::MatchRule, nonblock::{MsgMatch, Proxy, SyncConnection}, Message, MethodErr};
 use dbus_crossroads::{Crossroads, IfaceBuilder};
 use dbus_tokio::connection::IOResourceError;
 use futures::Future;
 use hookable::Hookable;
 use tokio::task::JoinHandle;
-use crate::launcher::{VmState, VmType};
+use crate::{config::{Config, ConfigError}, launcher::{destroy_vm, SystemState, VmState, VmType}, metrics::Metrics};
+
+/// dbus error name UserConnected replies with when the vm took too long to launch. the caller should call again
+pub const NOT_READY_ERROR: &str = "org.cws.WindowsLauncher.Error.NotReady";
+/// dbus error name returned when a launch is requested while another is in progress or running
+pub const ALREADY_LAUNCHED_ERROR: &str = "org.cws.WindowsLauncher.Error.AlreadyLaunched";
 
 /// Represents all ways the server can fail
 #[derive(Debug)]
@@ -18,17 +23,31 @@ pub enum ServerError{
     FailedToConnectToSystemBus(dbus::Error),
     FailedToGetName(dbus::Error),
     FailedToFindServerData,
-    CouldNotLockServerData,
-    FailedToAddSignalHandler(dbus::Error)
+    FailedToAddSignalHandler(dbus::Error),
+    VmAlreadyLaunched,
+    VmNotRunning,
+    VmNotLaunching,
+    UnknownProfile(String),
+    FailedToReloadConfig(ConfigError),
+    ReloadChangesRunningVm,
+    MouseDeviceMissing(String, std::io::Error),
+    NotAPointerDevice(String)
 }
 impl Display for ServerError{
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         let _ = f.write_str(&match self {
             Self::FailedToConnectToSystemBus(err) => format!("Could not connect to the system dbus: {}", *err),
             Self::FailedToGetName(err) => format!("Could not get the name org.cws.WindowsLauncher on the system dbus: {}", *err),
-            Self::FailedToFindServerData => format!("Could not find ServerData"),
-            Self::CouldNotLockServerData => format!("Could not lock ServerData"),
-            Self::FailedToAddSignalHandler(err) => format!("Failed to add UPower property change signal handler: {}", *err)
+            Self::FailedToFindServerData => "Could not find ServerData".to_string(),
+            Self::FailedToAddSignalHandler(err) => format!("Failed to add UPower property change signal handler: {}", *err),
+            Self::VmAlreadyLaunched => "Vm Already Launched".to_string(),
+            Self::VmNotRunning => "Vm Not Running".to_string(),
+            Self::VmNotLaunching => "Vm Not Launching, it has either not been requested or is already running".to_string(),
+            Self::UnknownProfile(name) => format!("No profile named {} in the config", *name),
+            Self::FailedToReloadConfig(err) => format!("Could not reload the config, keeping the current one: {}", *err),
+            Self::ReloadChangesRunningVm => "The new config changes settings the running vm depends on, like its devices, cpu masks, or services. shut the vm down before reloading it".to_string(),
+            Self::MouseDeviceMissing(path, err) => format!("Could not open the mouse device {}: {}", *path, *err),
+            Self::NotAPointerDevice(path) => format!("{} is not a pointer device. pass the /dev/input/event* node or /dev/input/by-id link of a mouse or touchpad", *path)
         });
         Ok(())
     }
@@ -37,6 +56,7 @@ impl Error for ServerError{}
 
 pub mod hookable{
     use std::task::Waker;
+    use crate::launcher::VmState;
 
     /// Type that allows one to attach wakers to an object and have the wakers called any time the object is changed
     #[derive(Default, Debug, Clone)]
@@ -49,18 +69,67 @@ pub mod hookable{
         pub fn get(&self) -> &T {&self.data}
         pub fn hook(&mut self, waker: Waker) {self.wakers.push(waker);}
     }    
+    impl Hookable<VmState> {
+        /// Moves to next if the current state allows it, logging and ignoring an illegal transition. returns whether it was set
+        pub fn try_set(&mut self, next: VmState) -> bool {
+            if !self.data.can_transition_to(&next) {
+                log::warn!("Ignoring an illegal vm state change from {:?} to {:?}", self.data, next);
+                return false;
+            }
+            self.set(next);
+            true
+        }
+    }
 }
+/// Locking that survives a task panicking while it held the lock
+pub trait LockOrRecover<T>{
+    /// locks the mutex, taking the guard back from a poisoned lock instead of failing
+    fn lock_or_recover(&self) -> MutexGuard<'_, T>;
+}
+impl<T> LockOrRecover<T> for Mutex<T>{
+    fn lock_or_recover(&self) -> MutexGuard<'_, T> {
+        // the locked data is plain state whose fields are each valid on their own, so a panicked writer leaves it usable
+        self.lock().unwrap_or_else(|poisoned| {
+            log::warn!("Recovered a lock poisoned by a panicked task");
+            self.clear_poison();
+            poisoned.into_inner()
+        })
+    }
+}
+
 /// Data held by the server, represents the state of the system
 #[derive(Default, Debug, Clone)]
 pub struct ServerData{
     pub vm_state: Hookable<VmState>,
     pub vm_type: VmType,
+    /// libvirt domain name of the vm
+    pub vm_name: String,
     /// whether or not a user has connected, and a waker to call when the variable changes
     pub user_connected: Hookable<bool>,
     /// path of the mouse to create for the vm
     pub mouse_path: String,
     /// whether or not the lid is closed
-    pub lid_is_closed: Hookable<bool>
+    pub lid_is_closed: Hookable<bool>,
+    /// whether a user has explicitly asked for the vm to be paused
+    pub user_paused: Hookable<bool>,
+    /// configuration loaded at startup, or by the last Reload
+    pub config: Config,
+    /// changes the launcher has made to the host
+    pub system_state: Arc<SystemState>,
+    /// number of session servers that have connected during this launch
+    pub connected_users: usize,
+    /// config profile selected for the current launch, if any
+    pub profile: Option<String>,
+    /// uid of the first session to connect during this launch
+    pub connected_uid: Option<u32>,
+    /// phase timings and failure counts since the server started
+    pub metrics: Metrics
+}
+impl ServerData{
+    /// returns the config for the current launch, with its profile applied
+    pub fn launch_config(&self) -> Config{
+        self.profile.as_ref().and_then(|name| self.config.with_profile(name)).unwrap_or(self.config.clone())
+    }
 }
 
 /// Future which waits for the vm to be launched
@@ -70,15 +139,11 @@ pub struct VmLaunchedFuture{
 impl Future for VmLaunchedFuture{
     type Output = Result<(), ServerError>;
     fn poll(self: std::pin::Pin<&mut Self>, cx: &mut std::task::Context<'_>) -> std::task::Poll<Self::Output> {
-        match self.data.lock() {
-            Ok(mut guard) => {
-                if let VmState::Launched = guard.vm_state.get() {Poll::Ready(Ok(()))}
-                else {
-                    guard.vm_state.hook(cx.waker().clone());
-                    Poll::Pending
-                }
-            },
-            _ => {Poll::Ready(Err(ServerError::CouldNotLockServerData))}
+        let mut guard = self.data.lock_or_recover();
+        if let VmState::Launched = guard.vm_state.get() {Poll::Ready(Ok(()))}
+        else {
+            guard.vm_state.hook(cx.waker().clone());
+            Poll::Pending
         }
     }
 }
@@ -90,15 +155,11 @@ pub struct VmLaunchFuture{
 impl Future for VmLaunchFuture{
     type Output = Result<(), ServerError>;
     fn poll(self: std::pin::Pin<&mut Self>, cx: &mut std::task::Context<'_>) -> std::task::Poll<Self::Output> {
-        match self.data.lock() {
-            Ok(mut guard) => {
-                if let VmState::Activating = guard.vm_state.get() {Poll::Ready(Ok(()))}
-                else {
-                    guard.vm_state.hook(cx.waker().clone());
-                    Poll::Pending
-                }
-            },
-            _ => {Poll::Ready(Err(ServerError::CouldNotLockServerData))}
+        let mut guard = self.data.lock_or_recover();
+        if let VmState::Activating = guard.vm_state.get() {Poll::Ready(Ok(()))}
+        else {
+            guard.vm_state.hook(cx.waker().clone());
+            Poll::Pending
         }
     }
 }
@@ -110,15 +171,11 @@ pub struct UserConnectedFuture{
 impl Future for UserConnectedFuture{
     type Output = Result<(), ServerError>;
     fn poll(self: std::pin::Pin<&mut Self>, cx: &mut std::task::Context<'_>) -> std::task::Poll<Self::Output> {
-        match self.data.lock() {
-            Ok(mut guard) => {
-                if *guard.user_connected.get() {Poll::Ready(Ok(()))}
-                else {
-                    guard.user_connected.hook(cx.waker().clone());
-                    Poll::Pending
-                }
-            },
-            _ => {Poll::Ready(Err(ServerError::CouldNotLockServerData))}
+        let mut guard = self.data.lock_or_recover();
+        if *guard.user_connected.get() {Poll::Ready(Ok(()))}
+        else {
+            guard.user_connected.hook(cx.waker().clone());
+            Poll::Pending
         }
     }
 }
@@ -130,15 +187,11 @@ pub struct VmShutdownFinishedFuture{
 impl Future for VmShutdownFinishedFuture{
     type Output = Result<(), ServerError>;
     fn poll(self: std::pin::Pin<&mut Self>, cx: &mut std::task::Context<'_>) -> std::task::Poll<Self::Output> {
-        match self.data.lock() {
-            Ok(mut guard) => {
-                if let VmState::Inactive = guard.vm_state.get() {Poll::Ready(Ok(()))}
-                else {
-                    guard.vm_state.hook(cx.waker().clone());
-                    Poll::Pending
-                }
-            },
-            _ => {Poll::Ready(Err(ServerError::CouldNotLockServerData))}
+        let mut guard = self.data.lock_or_recover();
+        if let VmState::Inactive = guard.vm_state.get() {Poll::Ready(Ok(()))}
+        else {
+            guard.vm_state.hook(cx.waker().clone());
+            Poll::Pending
         }
     }
 }
@@ -150,15 +203,28 @@ pub struct VmShutdownFuture{
 impl Future for VmShutdownFuture{
     type Output = Result<(), ServerError>;
     fn poll(self: std::pin::Pin<&mut Self>, cx: &mut std::task::Context<'_>) -> std::task::Poll<Self::Output> {
-        match self.data.lock() {
-            Ok(mut guard) => {
-                if let VmState::ShuttingDown = guard.vm_state.get() {Poll::Ready(Ok(()))}
-                else {
-                    guard.vm_state.hook(cx.waker().clone());
-                    Poll::Pending
-                }
-            },
-            _ => {Poll::Ready(Err(ServerError::CouldNotLockServerData))}
+        let mut guard = self.data.lock_or_recover();
+        if let VmState::ShuttingDown = guard.vm_state.get() {Poll::Ready(Ok(()))}
+        else {
+            guard.vm_state.hook(cx.waker().clone());
+            Poll::Pending
+        }
+    }
+}
+
+/// Future which waits for the vm state to differ from last, returning the new state
+pub struct VmStateChangedFuture{
+    pub last: VmState,
+    pub data: Arc<Mutex<ServerData>>
+}
+impl Future for VmStateChangedFuture{
+    type Output = Result<VmState, ServerError>;
+    fn poll(self: std::pin::Pin<&mut Self>, cx: &mut std::task::Context<'_>) -> std::task::Poll<Self::Output> {
+        let mut guard = self.data.lock_or_recover();
+        if *guard.vm_state.get() != self.last {Poll::Ready(Ok(guard.vm_state.get().clone()))}
+        else {
+            guard.vm_state.hook(cx.waker().clone());
+            Poll::Pending
         }
     }
 }
@@ -171,139 +237,438 @@ pub struct VmPauseFuture{
 impl Future for VmPauseFuture{
     type Output = Result<bool, ServerError>;
     fn poll(mut self: std::pin::Pin<&mut Self>, cx: &mut std::task::Context<'_>) -> std::task::Poll<Self::Output> {
-        if let Ok(mut guard) = self.data.lock(){
-            match guard.vm_state.get() {
-                VmState::Launched => {
-                    match (guard.lid_is_closed.get(), self.cur_pause_state) {
-                        (true, true) | (false, false) => {
-                            guard.vm_state.hook(cx.waker().clone());
-                            guard.lid_is_closed.hook(cx.waker().clone());
-                            return Poll::Pending;
-                        },
-                        (true, false) => {return Poll::Ready(Ok(true));},
-                        (false, true) => {return Poll::Ready(Ok(false));}
-                    }
-                },
-                _ => {
-                    guard.vm_state.hook(cx.waker().clone());
-                    guard.lid_is_closed.hook(cx.waker().clone());
+        let mut guard = self.data.lock_or_recover();
+        match guard.vm_state.get() {
+            VmState::Launched => {
+                match (*guard.lid_is_closed.get() || *guard.user_paused.get(), self.cur_pause_state) {
+                    (true, true) | (false, false) => {
+                        guard.vm_state.hook(cx.waker().clone());
+                        guard.lid_is_closed.hook(cx.waker().clone());
+                        guard.user_paused.hook(cx.waker().clone());
+                        return Poll::Pending;
+                    },
+                    (true, false) => {return Poll::Ready(Ok(true));},
+                    (false, true) => {return Poll::Ready(Ok(false));}
                 }
+            },
+            _ => {
+                guard.vm_state.hook(cx.waker().clone());
+                guard.lid_is_closed.hook(cx.waker().clone());
+                guard.user_paused.hook(cx.waker().clone());
             }
-        }else {return Poll::Ready(Err(ServerError::CouldNotLockServerData));}
+        }
+        drop(guard);
         self.cur_pause_state = false;
         Poll::Pending
     }
 }
 
 
+/// Requests a vm launch of the given type. Fails if a vm is already running
+/// the state is checked and set under a single lock, so of two concurrent requests only one can succeed
+/// vm_name selects the libvirt domain, using the configured one if it is None. profile selects a named config profile
+pub fn request_launch(data: &Arc<Mutex<ServerData>>, vm_type: VmType, mouse_path: String, vm_name: Option<String>, profile: Option<String>) -> Result<(), ServerError>{
+    let mut guard = data.lock_or_recover();
+    match guard.vm_state.get() {
+        VmState::Inactive => {
+            if let Some(name) = profile.as_ref().filter(|name| !guard.config.profiles.contains_key(*name)) {
+                return Err(ServerError::UnknownProfile(name.clone()));
+            }
+            guard.profile = profile;
+            guard.vm_type = vm_type;
+            guard.vm_name = vm_name.unwrap_or(guard.launch_config().vm_name);
+            guard.vm_state.try_set(VmState::Activating);
+            guard.user_connected.set(false);
+            guard.connected_users = 0;
+            guard.connected_uid = None;
+            guard.user_paused.set(false);
+            guard.mouse_path = mouse_path;
+            Ok(())
+        },
+        _ => Err(ServerError::VmAlreadyLaunched)
+    }
+}
+
+/// where the kernel describes each input device's capabilities
+pub const SYS_INPUT_PATH: &str = "/sys/class/input";
+
+/// Resolves a mouse path, following by-id and by-path links, and checks it is an evdev node that reports x and y movement
+/// touchpads report absolute rather than relative movement, so either is accepted
+pub fn pointer_device(path: &str) -> Result<String, ServerError>{
+    let resolved = std::fs::canonicalize(path).map_err(|err| ServerError::MouseDeviceMissing(path.to_string(), err))?;
+    let resolved = resolved.to_string_lossy().to_string();
+    let Some(event) = resolved.strip_prefix("/dev/input/").filter(|name| name.starts_with("event")) else {
+        return Err(ServerError::NotAPointerDevice(path.to_string()));
+    };
+    // each capability file is a hex bitmask of event codes, in space separated words with the lowest last
+    let has_xy = |kind: &str| std::fs::read_to_string(format!("{}/{}/device/capabilities/{}", SYS_INPUT_PATH, event, kind)).ok()
+        .and_then(|mask| mask.split_whitespace().last().and_then(|word| u64::from_str_radix(word, 16).ok()))
+        .is_some_and(|mask| mask & 0b11 == 0b11);
+    if !has_xy("rel") && !has_xy("abs") {return Err(ServerError::NotAPointerDevice(path.to_string()));}
+    Ok(resolved)
+}
+
+/// Validates the mouse path of a launch, if the launch creates a virtual mouse from it. otherwise the path is unused and passed through as is
+pub fn launch_mouse_path(data: &Arc<Mutex<ServerData>>, vm_type: &VmType, path: String) -> Result<String, ServerError>{
+    let virtual_mouse = data.lock_or_recover().config.virtual_mouse(vm_type);
+    if virtual_mouse {pointer_device(&path)} else {Ok(path)}
+}
+
+/// Maps a request_launch error to a method error, giving a rejected second launch its own error name
+pub fn launch_err(err: ServerError) -> MethodErr{
+    match err {
+        ServerError::VmAlreadyLaunched => MethodErr::from((ALREADY_LAUNCHED_ERROR, "A vm launch is already in progress or running")),
+        err => MethodErr::failed(&err)
+    }
+}
+
+/// Returns the vm state and type as strings
+pub fn query(data: &Arc<Mutex<ServerData>>) -> (String, String){
+    let guard = data.lock_or_recover();
+    (guard.vm_state.get().to_string(), guard.vm_type.to_string())
+}
+
+/// Returns a detailed view of the vm and the host changes made for it
+pub fn query_detailed(data: &Arc<Mutex<ServerData>>) -> HashMap<String, String>{
+    let mut status = HashMap::new();
+    let (state, vm_type) = query(data);
+    status.insert("vm_state".to_string(), state);
+    status.insert("vm_type".to_string(), vm_type);
+    let guard = data.lock_or_recover();
+    status.insert("gpu_detached".to_string(), guard.system_state.gpu_detached().to_string());
+    status.insert("dp_running".to_string(), (!guard.system_state.dp_stopped()).to_string());
+    status.insert("vfio_loaded".to_string(), guard.system_state.vfio_loaded().to_string());
+    status.insert("connected_users".to_string(), guard.connected_users.to_string());
+    status.insert("vm_name".to_string(), guard.vm_name.clone());
+    drop(guard);
+    status
+}
+
+/// Re-reads the config file into the server, see apply_config
+pub fn reload_config(data: &Arc<Mutex<ServerData>>) -> Result<(), ServerError>{
+    apply_config(data, Config::load().map_err(ServerError::FailedToReloadConfig)?)
+}
+
+/// Replaces the server config. while a vm is starting, running, or stopping, a config that changes anything
+/// but the settings Config::with_live_changes takes is rejected, since cleanup relies on the rest
+pub fn apply_config(data: &Arc<Mutex<ServerData>>, config: Config) -> Result<(), ServerError>{
+    let mut guard = data.lock_or_recover();
+    // compared with the profile applied, since that is what the running launch used
+    let running = guard.launch_config();
+    let old = std::mem::replace(&mut guard.config, config);
+    if *guard.vm_state.get() != VmState::Inactive {
+        let reloaded = guard.launch_config();
+        if running.with_live_changes(&reloaded) != reloaded {
+            guard.config = old;
+            return Err(ServerError::ReloadChangesRunningVm);
+        }
+    }
+    log::info!("Reloaded the config");
+    Ok(())
+}
+
+/// Requests the running vm to be paused or resumed. the vm also stays paused while the lid is closed
+pub fn request_pause(data: &Arc<Mutex<ServerData>>, pause: bool) -> Result<(), ServerError>{
+    let mut guard = data.lock_or_recover();
+    match guard.vm_state.get() {
+        VmState::Launched => {
+            guard.user_paused.set(pause);
+            Ok(())
+        },
+        _ => Err(ServerError::VmNotRunning)
+    }
+}
+
+/// Destroys the vm without waiting for the guest, then shuts down like request_shutdown
+/// returns when the host is cleaned up
+pub async fn request_force_shutdown(data: Arc<Mutex<ServerData>>) -> Result<(), ServerError>{
+    let (vm_name, uri, system_state) = {
+        let guard = data.lock_or_recover();
+        if let VmState::Inactive = guard.vm_state.get() {return Ok(());}
+        (guard.vm_name.clone(), guard.config.libvirt_uri.clone(), guard.system_state.clone())
+    };
+    // a launch that hasn't started the vm yet has nothing to destroy
+    match destroy_vm(&uri, &vm_name).await {
+        Ok(()) => {system_state.set_vm_destroyed();},
+        Err(err) => {log::warn!("Could not destroy the vm: {}", err);}
+    }
+    request_shutdown(data).await
+}
+
+/// Cancels a launch that hasn't started the vm yet, returns once the host is cleaned up
+/// fails if there is no launch in progress, a running vm has to be shut down instead
+pub async fn request_cancel(data: Arc<Mutex<ServerData>>) -> Result<(), ServerError>{
+    {
+        let mut guard = data.lock_or_recover();
+        if let VmState::Activating = guard.vm_state.get() {} else {return Err(ServerError::VmNotLaunching);}
+        guard.vm_state.try_set(VmState::ShuttingDown);
+    }
+    VmShutdownFinishedFuture{data}.await
+}
+
+/// Requests the vm to shutdown, returns when the vm is fully shutdown
+pub async fn request_shutdown(data: Arc<Mutex<ServerData>>) -> Result<(), ServerError>{
+    {
+        let mut guard = data.lock_or_recover();
+        if let VmState::Inactive = guard.vm_state.get() {return Ok(());}
+        if let VmState::ShuttingDown = guard.vm_state.get() {} else{
+            guard.vm_state.try_set(VmState::ShuttingDown);
+        }
+    }
+    VmShutdownFinishedFuture{data}.await
+}
+
 pub struct ServerStuff{
     pub data: Arc<Mutex<ServerData>>,
+    /// runs maintain_bus
+    pub handle: JoinHandle<()>,
+    pub bus: SystemBus
+}
+
+/// The current system bus connection. maintain_bus swaps in a new one whenever the bus is lost
+#[derive(Clone)]
+pub struct SystemBus(Arc<Mutex<Arc<SyncConnection>>>);
+impl SystemBus{
+    pub fn conn(&self) -> Arc<SyncConnection>{
+        self.0.lock_or_recover().clone()
+    }
+}
+
+/// A connection to the system bus with the server interface on it, and the tasks serving it
+pub struct BusConnection{
+    pub conn: Arc<SyncConnection>,
     pub handle: JoinHandle<IOResourceError>,
-    pub signal_handle: MsgMatch,
-    pub conn: Arc<SyncConnection>
+    pub state_signal_handle: JoinHandle<ServerError>,
+    pub signal_handle: MsgMatch
 }
 
-pub async fn server() -> Result<ServerStuff, ServerError>{
-    let (r, conn) = dbus_tokio::connection::new_system_sync().map_err(|err| ServerError::FailedToConnectToSystemBus(err))?;
+/// longest wait between attempts to reconnect to the system bus
+pub const RECONNECT_MAX_DELAY: Duration = Duration::from_secs(30);
+
+pub async fn server(config: Config) -> Result<ServerStuff, ServerError>{
+    let data = Arc::new(Mutex::new(ServerData{config, ..Default::default()}));
+    let connection = connect(data.clone()).await?;
+    let bus = SystemBus(Arc::new(Mutex::new(connection.conn.clone())));
+    let handle = tokio::spawn(maintain_bus(data.clone(), bus.clone(), connection));
+    Ok(ServerStuff { data, handle, bus })
+}
+
+/// Connects to the system bus and serves the interface for data on it
+pub async fn connect(data: Arc<Mutex<ServerData>>) -> Result<BusConnection, ServerError>{
+    let (r, conn) = dbus_tokio::connection::new_system_sync().map_err(ServerError::FailedToConnectToSystemBus)?;
     let handle = tokio::spawn(r);
-    let (data, signal_handle) = define_server(conn.clone()).await?;
-    Ok(ServerStuff { data, handle, signal_handle, conn })
+    let signal_handle = match define_server(conn.clone(), data.clone()).await {
+        Ok(signal_handle) => signal_handle,
+        Err(err) => {handle.abort(); return Err(err);}
+    };
+    let state_signal_handle = tokio::spawn(emit_state_changes(data, conn.clone()));
+    Ok(BusConnection { conn, handle, state_signal_handle, signal_handle })
+}
+
+/// Waits for the system bus connection to drop, e.g. when dbus-daemon restarts, then reconnects with backoff. never returns
+/// the server data lives on between connections, so a running vm is unaffected
+pub async fn maintain_bus(data: Arc<Mutex<ServerData>>, bus: SystemBus, mut connection: BusConnection){
+    loop {
+        match (&mut connection.handle).await {
+            Ok(err) => {log::error!("Lost the system bus connection: {}", err);},
+            Err(err) => {log::error!("The system bus connection task failed: {}", err);}
+        }
+        connection.state_signal_handle.abort();
+        let mut delay = Duration::from_secs(1);
+        connection = loop {
+            tokio::time::sleep(delay).await;
+            match connect(data.clone()).await {
+                Ok(connection) => break connection,
+                Err(err) => {
+                    delay = (delay * 2).min(RECONNECT_MAX_DELAY);
+                    log::warn!("Could not reconnect to the system bus, retrying in {:?}: {}", delay, err);
+                }
+            }
+        };
+        log::info!("Reconnected to the system bus");
+        *bus.0.lock_or_recover() = connection.conn.clone();
+    }
+}
+
+/// Emits the StateChanged signal every time the vm state changes. should never return
+pub async fn emit_state_changes(data: Arc<Mutex<ServerData>>, conn: Arc<SyncConnection>) -> ServerError{
+    let mut last = VmState::default();
+    loop{
+        last = match (VmStateChangedFuture{last, data: data.clone()}).await {
+            Ok(state) => state,
+            Err(err) => {return err;}
+        };
+        log::debug!("Vm state changed to {}", last);
+        let signal = Message::signal(&"/org/cws/WindowsLauncher".into(), &"org.cws.WindowsLauncher.Manager".into(), &"StateChanged".into())
+            .append1(last.to_string());
+        if conn.send(signal).is_err() {log::warn!("Failed to send the StateChanged signal");}
+    }
+}
+
+/// Returns the uid of the process owning the bus name sender, if the bus will tell us
+pub async fn connection_uid(conn: Arc<SyncConnection>, sender: Option<dbus::strings::BusName<'_>>, dbus_timeout: Duration) -> Option<u32>{
+    let sender = sender?.to_string();
+    let proxy = Proxy::new("org.freedesktop.DBus", "/org/freedesktop/DBus", dbus_timeout, conn);
+    match proxy.method_call::<(u32,), _, _, _>("org.freedesktop.DBus", "GetConnectionUnixUser", (sender,)).await {
+        Ok((uid,)) => Some(uid),
+        Err(err) => {log::warn!("Could not get the uid of a connecting session: {}", err); None}
+    }
 }
 
 /// setup the dbus server
-pub async fn define_server(conn: Arc<SyncConnection>) -> Result<(Arc<Mutex<ServerData>>, MsgMatch), ServerError>{
+pub async fn define_server(conn: Arc<SyncConnection>, server_data: Arc<Mutex<ServerData>>) -> Result<MsgMatch, ServerError>{
     // get name
     conn.request_name("org.cws.WindowsLauncher", false, false, true).await
-        .map_err(|err| ServerError::FailedToGetName(err))?;
+        .map_err(ServerError::FailedToGetName)?;
     // setup crossroads for managing interface
     let mut cr = Crossroads::new();
     cr.set_async_support(Some((conn.clone(), Box::new(|x| {tokio::spawn(x);}))));
     // define main interface
-    let manager = cr.register("org.cws.WindowsLauncher.Manager", |b: &mut IfaceBuilder<Arc<Mutex<ServerData>>>| {
+    let uid_conn = conn.clone();
+    let manager = cr.register("org.cws.WindowsLauncher.Manager", move |b: &mut IfaceBuilder<Arc<Mutex<ServerData>>>| {
         // Tells the system that a user has connected, returns when the vm is ready to launch
-        // Returns "" if the vm is not being launched
-        b.method_with_cr_async("UserConnected", (), ("VmType",), 
-        |mut ctx, cr, _: ()| {
-            println!("User Connected to DBus!");
+        // Returns "" if the vm is not being launched, along with the libvirt domain name
+        // if the vm is not running within the configured timeout, fails with NOT_READY_ERROR so the session can call again
+        b.method_with_cr_async("UserConnected", (), ("VmType", "VmName"), 
+        move |mut ctx, cr, _: ()| {
+            log::debug!("User Connected to DBus!");
             let object = cr.data_mut::<Arc<Mutex<ServerData>>>(&"/org/cws/WindowsLauncher".into()).cloned();
+            let uid_conn = uid_conn.clone();
             async move {
                 let Some(data) = object else {return ctx.reply(Err(MethodErr::failed(&ServerError::FailedToFindServerData)));};
-                let vm_type = if let Ok(mut guard) = data.lock() {
-                    if let VmState::Inactive = guard.vm_state.get() {return ctx.reply(Ok(("".to_string(),)));}
-                    println!("User Connected!");
+                let dbus_timeout = data.lock_or_recover().config.dbus_timeout();
+                let uid = connection_uid(uid_conn, ctx.message().sender(), dbus_timeout).await;
+                let (vm_type, vm_name, timeout) = {
+                    let mut guard = data.lock_or_recover();
+                    if let VmState::Inactive = guard.vm_state.get() {return ctx.reply(Ok(("".to_string(), "".to_string())));}
+                    log::info!("User Connected!");
+                    if guard.connected_uid.is_none() {guard.connected_uid = uid;}
                     guard.user_connected.set(true);
-                    guard.vm_type.clone()
-                } else {return ctx.reply(Err(MethodErr::failed(&ServerError::CouldNotLockServerData)));};
-                if let Err(err) = (VmLaunchedFuture{data}).await {return ctx.reply(Err(MethodErr::failed(&err)));}
-                ctx.reply(Ok((vm_type.to_string(),)))
+                    guard.connected_users += 1;
+                    (guard.vm_type.clone(), guard.vm_name.clone(), Duration::from_secs(guard.config.user_connect_timeout_secs))
+                };
+                match tokio::time::timeout(timeout, VmLaunchedFuture{data: data.clone()}).await {
+                    Ok(Ok(())) => {},
+                    Ok(Err(err)) => {return ctx.reply(Err(MethodErr::failed(&err)));},
+                    Err(_) => {
+                        // the session will call again, and be counted again
+                        let mut guard = data.lock_or_recover();
+                        guard.connected_users = guard.connected_users.saturating_sub(1);
+                        drop(guard);
+                        return ctx.reply(Err(MethodErr::from((NOT_READY_ERROR, "The vm is still launching, call again"))));
+                    }
+                }
+                ctx.reply(Ok((vm_type.as_code().to_string(), vm_name)))
             }
         });
         // tells the system to shutdown the vm
         // returns when the vm is fully shutdown
         b.method_with_cr_async("Shutdown", (), (), 
         |mut ctx, cr, _: ()| {
-            println!("Shutdown Requested!");
+            log::info!("Shutdown Requested!");
             let object = cr.data_mut::<Arc<Mutex<ServerData>>>(&"/org/cws/WindowsLauncher".into()).cloned();
             async move {
                 let Some(data) = object else {return ctx.reply(Err(MethodErr::failed(&ServerError::FailedToFindServerData)));};
-                if let Ok(mut guard) = data.lock() {
-                    if let VmState::Inactive = guard.vm_state.get() {return ctx.reply(Ok(()));}
-                    if let VmState::ShuttingDown = guard.vm_state.get() {} else{
-                        guard.vm_state.set(VmState::ShuttingDown);
-                    }
-                } else {return ctx.reply(Err(MethodErr::failed(&ServerError::CouldNotLockServerData)));}
-                if let Err(err) = (VmShutdownFinishedFuture{data}).await {return ctx.reply(Err(MethodErr::failed(&err)));}
-                ctx.reply(Ok(()))
+                ctx.reply(request_shutdown(data).await.map_err(|err| MethodErr::failed(&err)))
+            }
+        });
+        // destroys the vm at once, for a hung guest
+        // returns when the vm is fully shutdown
+        b.method_with_cr_async("ForceShutdown", (), (), 
+        |mut ctx, cr, _: ()| {
+            log::info!("Force Shutdown Requested!");
+            let object = cr.data_mut::<Arc<Mutex<ServerData>>>(&"/org/cws/WindowsLauncher".into()).cloned();
+            async move {
+                let Some(data) = object else {return ctx.reply(Err(MethodErr::failed(&ServerError::FailedToFindServerData)));};
+                ctx.reply(request_force_shutdown(data).await.map_err(|err| MethodErr::failed(&err)))
+            }
+        });
+        // cancels a launch before the vm is running
+        // returns once the host is cleaned up
+        b.method_with_cr_async("CancelLaunch", (), (), 
+        |mut ctx, cr, _: ()| {
+            log::info!("Launch Cancel Requested!");
+            let object = cr.data_mut::<Arc<Mutex<ServerData>>>(&"/org/cws/WindowsLauncher".into()).cloned();
+            async move {
+                let Some(data) = object else {return ctx.reply(Err(MethodErr::failed(&ServerError::FailedToFindServerData)));};
+                ctx.reply(request_cancel(data).await.map_err(|err| MethodErr::failed(&err)))
             }
         });
         // returns the vm state and type
         b.method::<_, (String, String), _, _>("Query", (), ("VmState", "VmType"), 
         |_, data, _: ()| {
-            println!("Query Requested!");
-            if let Ok(guard) = data.lock() {
-                Ok((guard.vm_state.get().to_string(), guard.vm_type.to_string()))
-            }else {Ok(("None".to_string(), "Not Running".to_string()))}
+            log::debug!("Query Requested!");
+            Ok(query(data))
+        });
+        // returns the vm state along with gpu, display manager, and user session state
+        b.method::<_, (HashMap<String, String>,), _, _>("QueryDetailed", (), ("Status",), 
+        |_, data, _: ()| {
+            log::debug!("Detailed Query Requested!");
+            Ok((query_detailed(data),))
+        });
+        // returns each launch phase's last duration in seconds, successes, and failures
+        b.method::<_, (HashMap<String, (f64, u64, u64)>,), _, _>("Metrics", (), ("Phases",), 
+        |_, data, _: ()| {
+            log::debug!("Metrics Requested!");
+            Ok((data.lock_or_recover().metrics.report(),))
+        });
+        // re-reads the config file. fails if it changes something the running vm depends on
+        b.method("Reload", (), (), 
+        |_, data, _: ()| {
+            log::info!("Reload Requested!");
+            reload_config(data).map_err(|err| MethodErr::failed(&err))
+        });
+        // pauses the running vm, returns immediately
+        b.method("Pause", (), (), 
+        |_, data, _: ()| {
+            log::info!("Pause Requested!");
+            request_pause(data, true).map_err(|err| MethodErr::failed(&err))
         });
+        // resumes the running vm, unless the lid is closed. returns immediately
+        b.method("Resume", (), (), 
+        |_, data, _: ()| {
+            log::info!("Resume Requested!");
+            request_pause(data, false).map_err(|err| MethodErr::failed(&err))
+        });
+        // emitted whenever the vm state changes, carrying the same state string as Query
+        b.signal::<(String,), _>("StateChanged", ("VmState",));
         // tells the server to launch looking glass, returns immediately
-        b.method("LaunchLG", ("MousePath",), (), 
-        |_, data, (path,): (String,)| {
-            println!("LG Launch Requested!");
-            if let Ok(mut guard) = data.lock() {
-                match guard.vm_state.get() {
-                    VmState::Inactive => {
-                        guard.vm_type = VmType::LookingGlass;
-                        guard.vm_state.set(VmState::Activating);
-                        guard.user_connected.set(false);
-                        guard.mouse_path = path;
-                        Ok(())
-                    }, 
-                    _ => {
-                        Err(MethodErr::failed("Vm Already Launched"))
-                    }
-                }
-            }else{Err(MethodErr::failed("Could not lock ServerData"))}
+        // an empty VmName uses the configured domain
+        b.method("LaunchLG", ("MousePath", "VmName"), (), 
+        |_, data, (path, name): (String, String)| {
+            log::info!("LG Launch Requested!");
+            let path = launch_mouse_path(data, &VmType::LookingGlass, path).map_err(launch_err)?;
+            request_launch(data, VmType::LookingGlass, path, Some(name).filter(|name| !name.is_empty()), None).map_err(launch_err)
+        });
+        // tells the server to launch the vm on its own monitor, with no viewer. returns immediately
+        b.method("LaunchDirect", ("VmName",), (), 
+        |_, data, (name,): (String,)| {
+            log::info!("Direct Launch Requested!");
+            request_launch(data, VmType::Direct, String::new(), Some(name).filter(|name| !name.is_empty()), None).map_err(launch_err)
+        });
+        // tells the server to launch spice without touching the host. returns immediately
+        b.method("LaunchSpiceLite", ("VmName",), (), 
+        |_, data, (name,): (String,)| {
+            log::info!("Spice Lite Launch Requested!");
+            request_launch(data, VmType::SpiceLite, String::new(), Some(name).filter(|name| !name.is_empty()), None).map_err(launch_err)
+        });
+        // tells the server to launch a vm using a named config profile. returns immediately
+        // VmType is lg, spice, spice-lite, or direct. an empty VmName uses the profile's domain
+        b.method("LaunchProfile", ("VmType", "MousePath", "VmName", "Profile"), (), 
+        |_, data, (vm_type, path, name, profile): (String, String, String, String)| {
+            log::info!("Profile {} Launch Requested!", profile);
+            let vm_type = VmType::from_code(&vm_type).ok_or(MethodErr::invalid_arg(&vm_type))?;
+            let path = launch_mouse_path(data, &vm_type, path).map_err(launch_err)?;
+            request_launch(data, vm_type, path, Some(name).filter(|name| !name.is_empty()), Some(profile)).map_err(launch_err)
         });
         // tells the server to launch spice. returns immediately
-        b.method("LaunchSpice", ("MousePath",), (), 
-        |_, data, (path,): (String,)| {
-            println!("Spice Launch Requested!");
-            if let Ok(mut guard) = data.lock() {
-                match guard.vm_state.get() {
-                    VmState::Inactive => {
-                        guard.vm_type = VmType::Spice;
-                        guard.vm_state.set(VmState::Activating);
-                        guard.user_connected.set(false);
-                        guard.mouse_path = path;
-                        Ok(())
-                    }, 
-                    _ => {
-                        Err(MethodErr::failed("Vm Already Launched"))
-                    }
-                }
-            }else{Err(MethodErr::failed("Could not lock ServerData"))}
+        b.method("LaunchSpice", ("MousePath", "VmName"), (), 
+        |_, data, (path, name): (String, String)| {
+            log::info!("Spice Launch Requested!");
+            let path = launch_mouse_path(data, &VmType::Spice, path).map_err(launch_err)?;
+            request_launch(data, VmType::Spice, path, Some(name).filter(|name| !name.is_empty()), None).map_err(launch_err)
         });
     });
-    let server_data = Arc::new(Mutex::new(ServerData::default()));
     cr.insert("/org/cws/WindowsLauncher", &[manager, cr.introspectable(), cr.properties()], server_data.clone());
     // start handling interface functions
     conn.start_receive(MatchRule::new_method_call(), Box::new(move |msg, conn| {
@@ -314,18 +679,101 @@ pub async fn define_server(conn: Arc<SyncConnection>) -> Result<(Arc<Mutex<Serve
     let mr = MatchRule::new_signal("org.freedesktop.DBus.Properties", "PropertiesChanged");
     let data = server_data.clone();
     let signal_handle = conn.add_match(mr).await
-        .map_err(|err| ServerError::FailedToAddSignalHandler(err))?
+        .map_err(ServerError::FailedToAddSignalHandler)?
         .cb(move |_, (iname, change, _): (String, PropMap, Vec<String>)| {
             if iname == "org.freedesktop.UPower"{
                 if let Some(value) = change.get("LidIsClosed") {
                     if let Some(value) = arg::cast::<bool>(&value.0){
-                        if let Ok(mut guard) = data.lock(){
-                            guard.lid_is_closed.set(*value);
-                        }
+                        data.lock_or_recover().lid_is_closed.set(*value);
                     }
                 }
             }
             true
         });
-    Ok((server_data, signal_handle))
-}
\ No newline at end of file
+    Ok(signal_handle)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Barrier;
+
+    #[test]
+    fn concurrent_launches_only_start_one() {
+        for _ in 0..100 {
+            let data = Arc::new(Mutex::new(ServerData::default()));
+            let barrier = Arc::new(Barrier::new(2));
+            let requests = [VmType::LookingGlass, VmType::Spice].map(|vm_type| {
+                let (data, barrier) = (data.clone(), barrier.clone());
+                std::thread::spawn(move || {
+                    barrier.wait();
+                    request_launch(&data, vm_type, "/dev/input/event7".to_string(), None, None)
+                })
+            });
+            let results = requests.map(|request| request.join().unwrap());
+            assert_eq!(results.iter().filter(|result| result.is_ok()).count(), 1);
+            assert!(results.iter().any(|result| matches!(result, Err(ServerError::VmAlreadyLaunched))));
+            assert_eq!(*data.lock().unwrap().vm_state.get(), VmState::Activating);
+        }
+    }
+
+    #[test]
+    fn launch_is_rejected_until_inactive() {
+        let data = Arc::new(Mutex::new(ServerData::default()));
+        request_launch(&data, VmType::Direct, String::new(), None, None).unwrap();
+        for state in [VmState::Activating, VmState::Launched, VmState::ShuttingDown] {
+            data.lock().unwrap().vm_state.set(state);
+            assert!(matches!(request_launch(&data, VmType::Spice, String::new(), None, None), Err(ServerError::VmAlreadyLaunched)));
+        }
+        data.lock().unwrap().vm_state.set(VmState::Inactive);
+        assert!(request_launch(&data, VmType::Spice, String::new(), None, None).is_ok());
+    }
+
+    #[test]
+    fn illegal_state_changes_are_ignored() {
+        let mut state = Hookable::<VmState>::default();
+        assert!(!state.try_set(VmState::Launched));
+        assert_eq!(*state.get(), VmState::Inactive);
+        for next in [VmState::Activating, VmState::ShuttingDown] {assert!(state.try_set(next));}
+        // a vm that finishes starting after a shutdown was requested doesn't get marked running
+        assert!(!state.try_set(VmState::Launched));
+        assert_eq!(*state.get(), VmState::ShuttingDown);
+        assert!(state.try_set(VmState::Inactive));
+    }
+
+    #[test]
+    fn a_panic_holding_the_lock_doesnt_wedge_the_server() {
+        let data = Arc::new(Mutex::new(ServerData::default()));
+        let poisoner = data.clone();
+        let _ = std::thread::spawn(move || {
+            let _guard = poisoner.lock().unwrap();
+            panic!("task panicked while holding the server data");
+        }).join();
+        assert!(data.is_poisoned());
+        assert!(request_launch(&data, VmType::Spice, String::new(), None, None).is_ok());
+        assert!(!data.is_poisoned());
+        assert_eq!(query(&data).0, VmState::Activating.to_string());
+    }
+
+    #[test]
+    fn reload_keeps_what_a_running_vm_depends_on() {
+        let data = Arc::new(Mutex::new(ServerData::default()));
+        let config = Config{idle_shutdown_minutes: 30, gpu_pci_ids: vec![], ..Default::default()};
+        apply_config(&data, config.clone()).unwrap();
+        request_launch(&data, VmType::Spice, String::new(), None, None).unwrap();
+        let mut live = config.clone();
+        live.idle_shutdown_minutes = 10;
+        live.lg_viewer_cmd = "looking-glass-client -F".to_string();
+        apply_config(&data, live.clone()).unwrap();
+        assert_eq!(data.lock().unwrap().config, live);
+        let mut devices = live.clone();
+        devices.gpu_pci_ids = vec!["pci_0000_01_00_0".to_string()];
+        assert!(matches!(apply_config(&data, devices.clone()), Err(ServerError::ReloadChangesRunningVm)));
+        assert_eq!(data.lock().unwrap().config, live);
+        // anything goes once the vm is gone
+        data.lock().unwrap().vm_state.set(VmState::Inactive);
+        apply_config(&data, devices.clone()).unwrap();
+        assert_eq!(data.lock().unwrap().config, devices);
+    }
+}
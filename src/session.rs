@@ -6,8 +6,14 @@
     wait for software to close
 */
 
-use std::{error::Error, fmt::Display, fs::File, process::Stdio, time::Duration};
-use dbus::nonblock::Proxy;
+use std::{error::Error, fmt::Display, process::Stdio, sync::Arc, time::Duration};
+use dbus::{arg::PropMap, message::MatchRule, nonblock::{stdintf::org_freedesktop_dbus::Properties, MsgMatch, Proxy, SyncConnection}};
+use crate::{config::{Config, ConfigError}, launcher::{VmState, VmType}, logs::create_log_file, server::NOT_READY_ERROR};
+
+/// how many times the user manager is asked for XAUTHORITY before giving up on the viewer
+pub const XAUTHORITY_ATTEMPTS: usize = 5;
+/// delay between XAUTHORITY lookups
+pub const XAUTHORITY_RETRY_DELAY: Duration = Duration::from_millis(500);
 
 /// Represents all ways the session program can fail
 #[derive(Debug)]
@@ -20,6 +26,9 @@ pub enum SessionError{
     FailedToLaunchVirtViewer(std::io::Error),
     VirtViewerFailed,
     FailedtoCreateLogFile(std::io::Error),
+    FailedToLoadConfig(ConfigError),
+    InvalidViewerCmd(String),
+    MissingXauthority,
     ServerError(dbus::Error)
 }
 impl Display for SessionError{
@@ -29,10 +38,13 @@ impl Display for SessionError{
             Self::FailedToLaunchLookingGlass(err) => format!("Could not launch looking-glass-client: {}", *err),
             Self::UnknownLaunchType(launch_type) => format!("The UserConnected method of org.cws.WindowsLauncher return an unknown launch type: {}", *launch_type),
             Self::FailedToWaitOnViewer(err) => format!("Asynchronously waiting on the launched viewer process failed: {}", *err),
-            Self::LookingGlassFailed => format!("Looking glass returned with error"),
+            Self::LookingGlassFailed => "Looking glass returned with error".to_string(),
             Self::FailedToLaunchVirtViewer(err) => format!("Could not launch virt-viewer: {}", *err),
-            Self::VirtViewerFailed => format!("virt-viewer returned with error"),
+            Self::VirtViewerFailed => "virt-viewer returned with error".to_string(),
             Self::FailedtoCreateLogFile(err) => format!("Could not create the log files: {}", *err),
+            Self::FailedToLoadConfig(err) => format!("Could not load the config: {}", *err),
+            Self::InvalidViewerCmd(cmd) => format!("Could not parse the viewer command: {}", *cmd),
+            Self::MissingXauthority => "The x11 session never set XAUTHORITY in the user manager, not launching a viewer that can't connect".to_string(),
             Self::ServerError(err) => format!("Server return error: {}", *err)
         });
         Ok(())
@@ -43,53 +55,187 @@ impl Error for SessionError{}
 pub async fn session()->Result<(), SessionError> {
     if users::get_current_groupname().is_some_and(|name| name.eq_ignore_ascii_case("sddm")) {return Ok(());}
     let (r, conn) = dbus_tokio::connection::new_system_sync()
-        .map_err(|err| SessionError::FailedToConnectToSystemBus(err))?;
+        .map_err(SessionError::FailedToConnectToSystemBus)?;
     let handle = tokio::spawn(r);
-    let proxy = Proxy::new("org.cws.WindowsLauncher", "/org/cws/WindowsLauncher", Duration::from_secs(30), conn.clone());
-    let launch_type = match proxy.method_call::<(String,), _, _, _>("org.cws.WindowsLauncher.Manager", "UserConnected", ()).await {
-        Err(err) => {
-            return Err(SessionError::ServerError(err));
-        },
-        Ok((launch_type,)) => {
-            if launch_type == ""{
-                println!("Got empty launch type, vm is not running");
-                return Ok(());
+    let config = Config::load().map_err(SessionError::FailedToLoadConfig)?;
+    // checked before connecting, so another user's session never counts as the vm's user
+    let name = users::get_current_username().map(|name| name.to_string_lossy().to_string()).unwrap_or_default();
+    if !config.viewer_user_allowed(users::get_current_uid(), &name) {
+        log::info!("{} is not in viewer_users, not connecting", name);
+        handle.abort();
+        return Ok(());
+    }
+    // subscribe before connecting, since UserConnected only returns once the vm is running
+    let notifier = if config.notifications {notify_on_state_change(conn.clone(), config.dbus_timeout()).await} else {None};
+    // leave the server time to give up first, so it can tell us to retry
+    let timeout = Duration::from_secs(config.user_connect_timeout_secs + 10);
+    let proxy = Proxy::new("org.cws.WindowsLauncher", "/org/cws/WindowsLauncher", timeout, conn.clone());
+    let (launch_type, vm_name) = loop {
+        match proxy.method_call::<(String, String), _, _, _>("org.cws.WindowsLauncher.Manager", "UserConnected", ()).await {
+            Err(err) if err.name() == Some(NOT_READY_ERROR) => {
+                log::info!("Vm is still launching, connecting again");
+            },
+            Err(err) => {
+                return Err(SessionError::ServerError(err));
+            },
+            Ok((launch_type, vm_name)) => {
+                if launch_type.is_empty(){
+                    log::info!("Got empty launch type, vm is not running");
+                    return Ok(());
+                }
+                break (launch_type, vm_name);
             }
-            launch_type
         }
     };
-    println!("Got vm type of: {}", launch_type);
-    let log_file = File::create(format!("/var/log/windows/viewer/log-{}.txt", chrono::Local::now().to_string()))
-        .map_err(|err| SessionError::FailedtoCreateLogFile(err))?;
-    let log = Stdio::from(log_file.try_clone().map_err(|err| SessionError::FailedtoCreateLogFile(err))?);
+    log::info!("Got vm type of: {}", launch_type);
+    let vm_type = VmType::from_code(&launch_type).ok_or(SessionError::UnknownLaunchType(launch_type))?;
+    if vm_type == VmType::Direct {
+        log::info!("Direct vm has no viewer");
+        handle.abort();
+        return Ok(());
+    }
+    if !is_viewer_session(conn.clone(), &config.viewer_policy, config.dbus_timeout()).await {
+        log::info!("This session does not match the viewer policy {}, not opening a viewer", config.viewer_policy);
+        handle.abort();
+        return Ok(());
+    }
+    let display_env = display_env(config.dbus_timeout()).await?;
+    let (_, log_file) = create_log_file(&config.log_dir, "viewer", config.log_keep)
+        .map_err(SessionError::FailedtoCreateLogFile)?;
+    let log = Stdio::from(log_file.try_clone().map_err(SessionError::FailedtoCreateLogFile)?);
     let log_err = Stdio::from(log_file);
-    if launch_type == "Looking Glass" {
-        launch_lg(log, log_err).await?;
-    }else if launch_type == "Spice" {
-        launch_spice(log, log_err).await?;
-    }else {
-        return Err(SessionError::UnknownLaunchType(launch_type));
+    match vm_type {
+        VmType::LookingGlass => {launch_lg(log, log_err, &viewer_cmd(&config.lg_viewer_cmd)?, &display_env).await?;},
+        VmType::Spice | VmType::SpiceLite => {launch_spice(log, log_err, &viewer_cmd(&config.spice_viewer_cmd)?, &config.libvirt_uri, &vm_name, &display_env).await?;},
+        VmType::Direct => {}
+    }
+    if let Some((notify_match, session_handle)) = notifier {
+        let _ = conn.remove_match(notify_match.token()).await;
+        session_handle.abort();
     }
     handle.abort();
     Ok(())
 }
 
-pub async fn launch_lg(log: Stdio, log_err: Stdio) -> Result<(), SessionError> {
-    let status = tokio::process::Command::new("looking-glass-client")
-        .args(["-T", "-s", "input:captureOnFocus"])
+/// Raises a desktop notification whenever the server reports the vm is ready or shutting down
+/// returns the signal match and the session bus handle, or None if either could not be set up
+pub async fn notify_on_state_change(conn: Arc<SyncConnection>, dbus_timeout: Duration) -> Option<(MsgMatch, tokio::task::JoinHandle<dbus_tokio::connection::IOResourceError>)>{
+    let (r, session_conn) = match dbus_tokio::connection::new_session_sync() {
+        Ok(session) => session,
+        Err(err) => {log::warn!("Could not connect to the session bus, notifications are disabled: {}", err); return None;}
+    };
+    let session_handle = tokio::spawn(r);
+    let notify_match = match conn.add_match(MatchRule::new_signal("org.cws.WindowsLauncher.Manager", "StateChanged")).await {
+        Ok(notify_match) => notify_match,
+        Err(err) => {
+            log::warn!("Could not subscribe to vm state changes, notifications are disabled: {}", err);
+            session_handle.abort();
+            return None;
+        }
+    };
+    let notify_match = notify_match.cb(move |_, (state,): (String,)| {
+        let body = if state == VmState::Launched.to_string() {"The windows vm is ready"}
+            else if state == VmState::ShuttingDown.to_string() {"The windows vm is shutting down"}
+            else {return true;};
+        let session_conn = session_conn.clone();
+        tokio::spawn(async move {
+            let proxy = Proxy::new("org.freedesktop.Notifications", "/org/freedesktop/Notifications", dbus_timeout, session_conn);
+            let result = proxy.method_call::<(u32,), _, _, _>("org.freedesktop.Notifications", "Notify", 
+                ("Windows Launcher", 0_u32, "", "Windows VM", body, Vec::<String>::new(), PropMap::new(), -1_i32)).await;
+            if let Err(err) = result {log::warn!("Failed to send a desktop notification: {}", err);}
+        });
+        true
+    });
+    Some((notify_match, session_handle))
+}
+
+/// Returns whether this login session should open the viewer under policy
+/// if the session can't be found through login1, the viewer is opened anyway
+pub async fn is_viewer_session(conn: Arc<SyncConnection>, policy: &str, dbus_timeout: Duration) -> bool {
+    if policy == "all" {return true;}
+    let login_proxy = Proxy::new("org.freedesktop.login1", "/org/freedesktop/login1", dbus_timeout, conn.clone());
+    let session_path = match std::env::var("XDG_SESSION_ID") {
+        Ok(id) => login_proxy.method_call::<(dbus::Path<'static>,), _, _, _>("org.freedesktop.login1.Manager", "GetSession", (id,)).await,
+        Err(_) => login_proxy.method_call::<(dbus::Path<'static>,), _, _, _>("org.freedesktop.login1.Manager", "GetSessionByPID", (std::process::id(),)).await
+    };
+    let (session_path,) = match session_path {
+        Ok(path) => path,
+        Err(err) => {
+            log::warn!("Could not find this login session, opening the viewer anyway: {}", err);
+            return true;
+        }
+    };
+    let session_proxy = Proxy::new("org.freedesktop.login1", session_path, dbus_timeout, conn);
+    if policy == "active" {
+        session_proxy.get::<bool>("org.freedesktop.login1.Session", "Active").await.unwrap_or(true)
+    }else {
+        session_proxy.get::<(String, dbus::Path)>("org.freedesktop.login1.Session", "Seat").await
+            .is_ok_and(|(seat, _)| seat == policy)
+    }
+}
+
+/// Returns DISPLAY, WAYLAND_DISPLAY, and XAUTHORITY from the user manager's environment, for the viewer
+/// a fresh x11 session may not have set XAUTHORITY yet, so it is fetched again a few times before giving up
+pub async fn display_env(dbus_timeout: Duration) -> Result<Vec<(String, String)>, SessionError> {
+    let (r, conn) = match dbus_tokio::connection::new_session_sync() {
+        Ok(session) => session,
+        Err(err) => {log::warn!("Could not connect to the session bus, using the inherited display environment: {}", err); return Ok(vec![]);}
+    };
+    let handle = tokio::spawn(r);
+    let proxy = Proxy::new("org.freedesktop.systemd1", "/org/freedesktop/systemd1", dbus_timeout, conn);
+    let mut env = vec![];
+    for attempt in 1..=XAUTHORITY_ATTEMPTS {
+        env = match proxy.get::<Vec<String>>("org.freedesktop.systemd1.Manager", "Environment").await {
+            Ok(vars) => vars.iter().filter_map(|var| var.split_once('='))
+                .filter(|(key, value)| ["DISPLAY", "WAYLAND_DISPLAY", "XAUTHORITY"].contains(key) && !value.is_empty())
+                .map(|(key, value)| (key.to_string(), value.to_string())).collect::<Vec<(String, String)>>(),
+            Err(err) => {log::warn!("Could not read the user manager environment: {}", err); vec![]}
+        };
+        let has = |name: &str| env.iter().any(|(key, _)| key == name);
+        // only x11 needs the cookie
+        if !has("DISPLAY") || has("XAUTHORITY") {break;}
+        if attempt == XAUTHORITY_ATTEMPTS {
+            handle.abort();
+            return Err(SessionError::MissingXauthority);
+        }
+        log::info!("XAUTHORITY is not set yet, retrying ({}/{})", attempt, XAUTHORITY_ATTEMPTS);
+        tokio::time::sleep(XAUTHORITY_RETRY_DELAY).await;
+    }
+    handle.abort();
+    Ok(env)
+}
+
+/// Splits a viewer command line into its words, shell style. an empty command is invalid
+pub fn viewer_cmd(cmd: &str) -> Result<Vec<String>, SessionError> {
+    match shell_words::split(cmd) {
+        Ok(words) if !words.is_empty() => Ok(words),
+        _ => Err(SessionError::InvalidViewerCmd(cmd.to_string()))
+    }
+}
+
+pub async fn launch_lg(log: Stdio, log_err: Stdio, cmd: &[String], env: &[(String, String)]) -> Result<(), SessionError> {
+    let status = tokio::process::Command::new(&cmd[0])
+        .args(&cmd[1..]).envs(env.iter().cloned())
         .stdout(log).stderr(log_err).spawn()
-        .map_err(|err| SessionError::FailedToLaunchLookingGlass(err))?
-        .wait().await.map_err(|err| SessionError::FailedToWaitOnViewer(err))?;
+        .map_err(SessionError::FailedToLaunchLookingGlass)?
+        .wait().await.map_err(SessionError::FailedToWaitOnViewer)?;
     if !status.success() {return Err(SessionError::LookingGlassFailed);}
     Ok(())
 }
 
-pub async fn launch_spice(log: Stdio, log_err: Stdio) -> Result<(), SessionError> {
-    let status = tokio::process::Command::new("virt-viewer")
-        .args(["--connect", "qemu:///system", "windows"])
+/// Returns the viewer args, connecting to uri unless the command already picks a connection
+pub fn with_connect(cmd: &[String], uri: &str) -> Vec<String> {
+    let connects = cmd[1..].iter().any(|arg| arg.starts_with("-c") || arg == "--connect" || arg.starts_with("--connect="));
+    if connects {return cmd[1..].to_vec();}
+    ["--connect".to_string(), uri.to_string()].into_iter().chain(cmd[1..].iter().cloned()).collect()
+}
+
+pub async fn launch_spice(log: Stdio, log_err: Stdio, cmd: &[String], uri: &str, vm_name: &str, env: &[(String, String)]) -> Result<(), SessionError> {
+    let status = tokio::process::Command::new(&cmd[0])
+        .args(with_connect(cmd, uri)).arg(vm_name).envs(env.iter().cloned())
         .stdout(log).stderr(log_err).spawn()
-        .map_err(|err| SessionError::FailedToLaunchVirtViewer(err))?
-        .wait().await.map_err(|err| SessionError::FailedToWaitOnViewer(err))?;
+        .map_err(SessionError::FailedToLaunchVirtViewer)?
+        .wait().await.map_err(SessionError::FailedToWaitOnViewer)?;
     if !status.success() {return Err(SessionError::VirtViewerFailed);}
     Ok(())
 }
\ No newline at end of file
@@ -0,0 +1,155 @@
+/*
+    Optional unix socket front-end to the server, for scripts that don't want to speak dbus
+    Accepts newline delimited json commands, and replies with a json line per command
+    It drives the same ServerData as the dbus interface
+    A separate read only status socket answers any line with the current status, for monitors and bars
+*/
+
+use std::{error::Error, fmt::Display, os::unix::fs::PermissionsExt, sync::{Arc, Mutex}};
+use serde::Deserialize;
+use serde_json::{json, Value};
+use tokio::{io::{AsyncBufReadExt, AsyncWriteExt, BufReader}, net::{UnixListener, UnixStream}};
+use crate::{launcher::VmType, server::{launch_mouse_path, LockOrRecover, query, request_launch, request_shutdown, ServerData}};
+
+/// Represents all ways the socket server can fail
+#[derive(Debug)]
+pub enum SocketServerError{
+    FailedToRemoveOldSocket(String, std::io::Error),
+    FailedToBindSocket(String, std::io::Error),
+    FailedToAcceptConnection(std::io::Error),
+    FailedToSetSocketPermissions(String, std::io::Error)
+}
+impl Display for SocketServerError{
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let _ = f.write_str(&match self {
+            Self::FailedToRemoveOldSocket(path, err) => format!("Could not remove the old socket at {}: {}", *path, *err),
+            Self::FailedToBindSocket(path, err) => format!("Could not bind the control socket at {}: {}", *path, *err),
+            Self::FailedToAcceptConnection(err) => format!("Failed to accept a connection on the control socket: {}", *err),
+            Self::FailedToSetSocketPermissions(path, err) => format!("Could not make the status socket at {} readable: {}", *path, *err)
+        });
+        Ok(())
+    }
+}
+impl Error for SocketServerError{}
+
+/// all commands accepted on the socket
+#[derive(Deserialize, Debug)]
+#[serde(tag = "cmd", rename_all = "lowercase")]
+pub enum SocketCommand{
+    Launch{
+        #[serde(rename = "type")]
+        vm_type: String,
+        /// unused for spice-lite and direct
+        #[serde(default)]
+        mouse: String,
+        /// libvirt domain to launch, the configured one if missing
+        name: Option<String>,
+        /// config profile to launch with, if any
+        profile: Option<String>
+    },
+    Query,
+    Shutdown
+}
+
+/// where the status socket listens when status_socket is enabled
+pub const STATUS_SOCKET_PATH: &str = "/run/windows-vm-launcher/status.sock";
+
+/// binds a unix socket at path, replacing any left by a previous server
+fn bind(path: &str) -> Result<UnixListener, SocketServerError>{
+    if std::path::Path::new(path).exists() {
+        std::fs::remove_file(path).map_err(|err| SocketServerError::FailedToRemoveOldSocket(path.to_string(), err))?;
+    }
+    if let Some(parent) = std::path::Path::new(path).parent() {
+        std::fs::create_dir_all(parent).map_err(|err| SocketServerError::FailedToBindSocket(path.to_string(), err))?;
+    }
+    UnixListener::bind(path).map_err(|err| SocketServerError::FailedToBindSocket(path.to_string(), err))
+}
+
+/// Listens on the unix socket at path, handling each connection in its own task. should never return
+pub async fn socket_server(data: Arc<Mutex<ServerData>>, path: String) -> Result<(), SocketServerError>{
+    let listener = bind(&path)?;
+    log::info!("Listening for commands on {}", path);
+    loop{
+        let (stream, _) = listener.accept().await.map_err(SocketServerError::FailedToAcceptConnection)?;
+        tokio::spawn(handle_connection(data.clone(), stream));
+    }
+}
+
+/// reads commands from the stream until it closes, replying to each one
+pub async fn handle_connection(data: Arc<Mutex<ServerData>>, stream: UnixStream){
+    let (read, mut write) = stream.into_split();
+    let mut lines = BufReader::new(read).lines();
+    while let Ok(Some(line)) = lines.next_line().await {
+        if line.trim().is_empty() {continue;}
+        let reply = match serde_json::from_str::<SocketCommand>(&line) {
+            Ok(command) => handle_command(data.clone(), command).await,
+            Err(err) => json!({"ok": false, "error": format!("Malformed command: {}", err)})
+        };
+        if write.write_all(format!("{}\n", reply).as_bytes()).await.is_err() {return;}
+    }
+}
+
+/// executes a single command against the server data, returning the json reply
+pub async fn handle_command(data: Arc<Mutex<ServerData>>, command: SocketCommand) -> Value{
+    match command {
+        SocketCommand::Launch{vm_type, mouse, name, profile} => {
+            log::info!("Socket Launch Requested!");
+            let Some(vm_type) = VmType::from_code(&vm_type) else {
+                return json!({"ok": false, "error": format!("Unknown vm type: {}", vm_type)});
+            };
+            let mouse = match launch_mouse_path(&data, &vm_type, mouse) {
+                Ok(mouse) => mouse,
+                Err(err) => {return json!({"ok": false, "error": err.to_string()});}
+            };
+            match request_launch(&data, vm_type, mouse, name, profile) {
+                Ok(()) => json!({"ok": true}),
+                Err(err) => json!({"ok": false, "error": err.to_string()})
+            }
+        },
+        SocketCommand::Query => {
+            log::debug!("Socket Query Requested!");
+            let (state, vm_type) = query(&data);
+            json!({"ok": true, "state": state, "type": vm_type})
+        },
+        SocketCommand::Shutdown => {
+            log::info!("Socket Shutdown Requested!");
+            match request_shutdown(data).await {
+                Ok(()) => json!({"ok": true}),
+                Err(err) => json!({"ok": false, "error": err.to_string()})
+            }
+        }
+    }
+}
+
+/// Answers every line sent to the socket at path with the current status, then closes. should never return
+/// the socket is world writable since it can't change anything, so user level monitors can read it
+pub async fn status_server(data: Arc<Mutex<ServerData>>, path: String) -> Result<(), SocketServerError>{
+    let listener = bind(&path)?;
+    std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o666))
+        .map_err(|err| SocketServerError::FailedToSetSocketPermissions(path.clone(), err))?;
+    log::info!("Serving status on {}", path);
+    loop{
+        let (stream, _) = listener.accept().await.map_err(SocketServerError::FailedToAcceptConnection)?;
+        let data = data.clone();
+        tokio::spawn(async move {
+            let (read, mut write) = stream.into_split();
+            // any request gets the status, the line only says the client is ready for it
+            let _ = BufReader::new(read).lines().next_line().await;
+            let _ = write.write_all(format!("{}\n", status(&data)).as_bytes()).await;
+        });
+    }
+}
+
+/// the live status of the server and host as json
+pub fn status(data: &Arc<Mutex<ServerData>>) -> Value{
+    let (state, vm_type) = query(data);
+    let guard = data.lock_or_recover();
+    json!({
+        "state": state,
+        "type": vm_type,
+        "connected_users": guard.connected_users,
+        "gpu_detached": guard.system_state.gpu_detached(),
+        "vfio_loaded": guard.system_state.vfio_loaded(),
+        "dp_running": !guard.system_state.dp_stopped()
+    })
+}